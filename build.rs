@@ -0,0 +1,302 @@
+//! Writes memory.x for the linker from memory.x.in and converts the PNGs in assets/ into `Asset`s, see src/asset.rs.
+//!
+//! `name.png` becomes `NAME`, palette or QOI encoded, `name.mask.png` becomes a 1 bit mask of the opaque pixels.
+//! `SOURCES` lists the PNG of every asset, the tests in src/assets.rs decode them and compare every pixel.
+//!
+//! BDF fonts in assets/fonts/ become `text::Font`s the same way, `name.bdf` is `NAME`.
+//!
+//! `PROTO_SLOT=a` or `b` links the firmware for a slot of proto-boot instead of the start of the flash. The template
+//! is not called memory.x, the linker would find it in the package directory before the one in OUT_DIR.
+//!
+//! USB ids and strings come from `PROTO_USB_VID`, `PROTO_USB_PID`, `PROTO_USB_MANUFACTURER` and `PROTO_USB_PRODUCT`, see src/usb.rs.
+
+use std::env;
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+#[allow(dead_code)]
+#[path = "src/asset.rs"]
+mod asset;
+use asset::Encoding;
+
+#[allow(dead_code)]
+#[path = "proto-image/src/layout.rs"]
+mod layout;
+
+struct Image {
+    width: u32,
+    height: u32,
+    /// RGBA
+    pixels: Vec<[u8; 4]>
+}
+
+fn main() {
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+
+    let (memory, slot) = memory_x(&fs::read_to_string("memory.x.in").unwrap());
+    fs::write(out.join("memory.x"), memory).unwrap();
+    fs::write(out.join("slot.rs"), format!("// generated by build.rs from PROTO_SLOT\npub const SLOT: Option<usize> = {:?};\n", slot)).unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=memory.x.in");
+    if env::var_os("CARGO_FEATURE_DEFMT").is_some() {
+        println!("cargo:rustc-link-arg=-Tdefmt.x");
+    }
+    println!("cargo:rerun-if-changed=assets");
+
+    let mut code = String::from("// generated by build.rs from assets/\n");
+    let mut sources = String::new();
+    for path in files("assets", "png") {
+        println!("cargo:rerun-if-changed={}", path.display());
+
+        let stem = path.file_stem().unwrap().to_str().unwrap();
+        let (name, mask) = match stem.strip_suffix(".mask") {
+            Some(name) => (name, true),
+            None => (stem, false)
+        };
+        let name = name.to_uppercase().replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+
+        let image = load(&path);
+        let (encoding, palette, data) = if mask { encode_mask(&image) } else { encode(&image) };
+
+        writeln!(
+            code,
+            "pub static {}: Asset = Asset {{ width: {}, height: {}, encoding: Encoding::{:?}, palette: &{:?}, data: &{:?} }};",
+            name, image.width, image.height, encoding, palette, data
+        ).unwrap();
+        write!(sources, "({:?}, &{}), ", path.display().to_string(), name).unwrap();
+    }
+    writeln!(code, "#[cfg(test)]\npub static SOURCES: &[(&str, &Asset)] = &[{}];", sources).unwrap();
+
+    fs::write(out.join("assets.rs"), code).unwrap();
+
+    println!("cargo:rerun-if-changed=assets/fonts");
+    let mut code = String::from("// generated by build.rs from assets/fonts/\n");
+    for path in files("assets/fonts", "bdf") {
+        println!("cargo:rerun-if-changed={}", path.display());
+        let name = path.file_stem().unwrap().to_str().unwrap().to_uppercase().replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+        code += &convert_bdf(&name, &fs::read_to_string(&path).unwrap());
+    }
+    fs::write(out.join("fonts.rs"), code).unwrap();
+
+    fs::write(out.join("usb.rs"), usb_config()).unwrap();
+}
+
+/// memory.x.in with the FLASH of the slot in `PROTO_SLOT`, unchanged without
+fn memory_x(memory: &str) -> (String, Option<usize>) {
+    println!("cargo:rerun-if-env-changed=PROTO_SLOT");
+    let name = match env::var("PROTO_SLOT") {
+        Ok(name) => name,
+        Err(_) => return (memory.to_string(), None)
+    };
+    let number = layout::slot(&name).unwrap_or_else(|| panic!("PROTO_SLOT has to be a or b, not {:?}", name));
+    let slot = &layout::SLOTS[number];
+
+    let flash = format!(
+        "  /* slot {} of proto-boot, after the image header */\n  FLASH : ORIGIN = {:#010X}, LENGTH = {}",
+        slot.name, slot.image(), slot.max_length()
+    );
+    let memory = memory.lines()
+        .map(| line | if line.trim_start().starts_with("FLASH") { flash.clone() } else { line.to_string() })
+        .collect::<Vec<String>>()
+        .join("\n");
+    (memory + "\n", Some(number))
+}
+
+/// The shared V-USB ids for CDC devices unless the environment says otherwise
+fn usb_config() -> String {
+    let setting = | name: &str, default: &str | {
+        println!("cargo:rerun-if-env-changed={}", name);
+        env::var(name).unwrap_or_else(| _ | default.to_string())
+    };
+    let id = | name: &str, default: &str | {
+        let text = setting(name, default);
+        let parsed = match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => text.parse()
+        };
+        parsed.unwrap_or_else(| _ | panic!("{} has to be a 16 bit number like 0x16c0, not {:?}", name, text))
+    };
+
+    format!(
+        "// generated by build.rs from the environment\n\
+        pub const VID: u16 = {:#06x};\n\
+        pub const PID: u16 = {:#06x};\n\
+        pub const MANUFACTURER: &str = {:?};\n\
+        pub const PRODUCT: &str = {:?};\n",
+        id("PROTO_USB_VID", "0x16c0"),
+        id("PROTO_USB_PID", "0x27dd"),
+        setting("PROTO_USB_MANUFACTURER", "Lukas Sturm"),
+        setting("PROTO_USB_PRODUCT", "Macro Proto")
+    )
+}
+
+fn files(dir: &str, extension: &str) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .map(| dir | dir.filter_map(| entry | entry.ok()).map(| entry | entry.path()).collect())
+        .unwrap_or_default();
+    paths.retain(| path | path.extension().map_or(false, | found | found == extension));
+    paths.sort();
+    paths
+}
+
+struct BdfGlyph {
+    code: u32,
+    advance: i32,
+    /// width, height, x offset, y offset of the bounding box, y up from the baseline
+    bbx: [i32; 4],
+    rows: Vec<u8>
+}
+
+/// Only what a bitmap font needs: ascent, descent, default char and per glyph DWIDTH, BBX and BITMAP
+fn convert_bdf(name: &str, bdf: &str) -> String {
+    let mut ascent = 0;
+    let mut descent = 0;
+    let mut default_char = 0xFFFD;
+    let mut glyphs: Vec<BdfGlyph> = Vec::new();
+    let mut glyph: Option<BdfGlyph> = None;
+    let mut in_bitmap = false;
+
+    for line in bdf.lines() {
+        let mut words = line.split_whitespace();
+        let keyword = words.next().unwrap_or("");
+        let numbers: Vec<i32> = words.filter_map(| word | word.parse().ok()).collect();
+
+        match keyword {
+            "FONT_ASCENT" => ascent = numbers[0],
+            "FONT_DESCENT" => descent = numbers[0],
+            "DEFAULT_CHAR" => default_char = numbers[0] as u32,
+            "STARTCHAR" => glyph = Some(BdfGlyph { code: 0, advance: 0, bbx: [0; 4], rows: Vec::new() }),
+            "ENCODING" => glyph.as_mut().unwrap().code = numbers[0] as u32,
+            "DWIDTH" => glyph.as_mut().unwrap().advance = numbers[0],
+            "BBX" => glyph.as_mut().unwrap().bbx.copy_from_slice(&numbers[..4]),
+            "BITMAP" => in_bitmap = true,
+            "ENDCHAR" => {
+                in_bitmap = false;
+                let glyph = glyph.take().unwrap();
+                // -1 are glyphs without a code point
+                if glyph.code as i32 >= 0 {
+                    glyphs.push(glyph);
+                }
+            },
+            hex if in_bitmap => {
+                let row = glyph.as_mut().unwrap();
+                for i in (0..hex.len()).step_by(2) {
+                    row.rows.push(u8::from_str_radix(&hex[i..i + 2], 16).unwrap());
+                }
+            },
+            _ => ()
+        }
+    }
+
+    glyphs.sort_by_key(| glyph | glyph.code);
+    let default = glyphs.iter().position(| glyph | glyph.code == default_char)
+        .unwrap_or_else(|| panic!("{}: DEFAULT_CHAR {} is missing", name, default_char));
+
+    let mut bitmap: Vec<u8> = Vec::new();
+    let mut table = String::new();
+    for glyph in &glyphs {
+        let [width, height, x, y] = glyph.bbx;
+        assert_eq!(glyph.rows.len(), ((width as usize + 7) / 8) * height as usize, "{}: glyph {} bitmap size", name, glyph.code);
+        write!(
+            table,
+            "Glyph {{ code: {}, advance: {}, width: {}, height: {}, x: {}, y: {}, offset: {} }}, ",
+            glyph.code, glyph.advance, width, height, x, y, bitmap.len()
+        ).unwrap();
+        bitmap.extend(&glyph.rows);
+    }
+
+    format!(
+        "pub static {}: Font = Font {{ ascent: {}, descent: {}, default: {}, glyphs: &[{}], bitmap: &{:?} }};\n",
+        name, ascent, descent, default, table, bitmap
+    )
+}
+
+fn load(path: &Path) -> Image {
+    let mut decoder = png::Decoder::new(File::open(path).unwrap());
+    // palette and low bit depths to 8 bit gray or RGB
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info().unwrap();
+    let mut buf = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut buf).unwrap();
+
+    let (color_type, _) = reader.output_color_type();
+    let channels = color_type.samples();
+    let pixels = buf[..(info.width * info.height) as usize * channels]
+        .chunks_exact(channels)
+        .map(| pixel | match color_type {
+            png::ColorType::Grayscale => [pixel[0], pixel[0], pixel[0], 255],
+            png::ColorType::GrayscaleAlpha => [pixel[0], pixel[0], pixel[0], pixel[1]],
+            png::ColorType::RGB => [pixel[0], pixel[1], pixel[2], 255],
+            png::ColorType::RGBA => [pixel[0], pixel[1], pixel[2], pixel[3]],
+            png::ColorType::Indexed => panic!("{}: palette was not expanded", path.display())
+        })
+        .collect();
+
+    Image { width: info.width, height: info.height, pixels }
+}
+
+fn opaque(pixel: &[u8; 4]) -> bool {
+    pixel[3] >= 128
+}
+
+fn encode_mask(image: &Image) -> (Encoding, Vec<u16>, Vec<u8>) {
+    let mut data = vec![0u8; (image.pixels.len() + 7) / 8];
+    for (i, pixel) in image.pixels.iter().enumerate() {
+        if opaque(pixel) {
+            data[i / 8] |= 0x80 >> (i % 8);
+        }
+    }
+    (Encoding::Mask, Vec::new(), data)
+}
+
+/// Palette if the image has 256 colors or less (after going to RGB565), packed or RLE, whichever is smaller.
+/// QOI otherwise. Alpha is dropped.
+fn encode(image: &Image) -> (Encoding, Vec<u16>, Vec<u8>) {
+    let colors: Vec<u16> = image.pixels.iter().map(| pixel | asset::rgb565(pixel[0], pixel[1], pixel[2])).collect();
+
+    let mut palette: Vec<u16> = Vec::new();
+    for color in &colors {
+        if !palette.contains(color) {
+            palette.push(*color);
+        }
+    }
+
+    if palette.len() > 256 {
+        let rgb: Vec<u8> = image.pixels.iter().flat_map(| pixel | pixel[..3].to_vec()).collect();
+        let data = qoi::encode_to_vec(&rgb, image.width, image.height).unwrap();
+        return (Encoding::Qoi, Vec::new(), data);
+    }
+
+    let indices: Vec<u8> = colors.iter().map(| color | palette.iter().position(| c | c == color).unwrap() as u8).collect();
+
+    let bits = match palette.len() {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8
+    };
+    let mut packed = vec![0u8; (indices.len() * bits + 7) / 8];
+    for (i, index) in indices.iter().enumerate() {
+        let bit = i * bits;
+        packed[bit / 8] |= index << (8 - bits - bit % 8);
+    }
+
+    let mut rle = Vec::new();
+    let mut i = 0;
+    while i < indices.len() {
+        let mut run = 1;
+        while run < 256 && i + run < indices.len() && indices[i + run] == indices[i] {
+            run += 1;
+        }
+        rle.push((run - 1) as u8);
+        rle.push(indices[i]);
+        i += run;
+    }
+
+    if rle.len() < packed.len() {
+        (Encoding::RlePalette, palette, rle)
+    } else {
+        (Encoding::Palette(bits as u8), palette, packed)
+    }
+}
@@ -10,6 +10,10 @@ use cortex_m;
 use cortex_m_rt::entry;
 use stm32f4xx_hal::{delay::Delay, prelude::*, pwm, stm32};
 
+#[path = "../src/haptic.rs"]
+mod haptic;
+use haptic::{Effect, Player};
+
 
 
 // straight from stm32f4xx-hal examples
@@ -43,31 +47,25 @@ fn main() -> ! {
     ch1.set_duty(max_duty);
     ch1.enable();
 
-    loop {
-        delay.delay_ms(100u16);
-
-        ch1.set_duty(max_duty / 16);
-        delay.delay_ms(500u16);
-
-        ch1.set_duty(max_duty / 8);
-        delay.delay_ms(200u16);
-        
-        ch1.set_duty(max_duty / 4);
-        delay.delay_ms(200u16);
+    // swell up and down, driven by the same player the firmware uses
+    let mut now = 0u32;
+    let mut effect = Effect::RampUp;
+    let mut player = Player::new(effect.segments(), now).unwrap();
 
-        ch1.set_duty(max_duty / 2);
-        delay.delay_ms(200u16);
-        
-        ch1.set_duty(max_duty);
-        delay.delay_ms(500u16);
-
-        ch1.set_duty(max_duty / 2);    
-        delay.delay_ms(100u16);
-
-        ch1.set_duty(max_duty / 4);
-        delay.delay_ms(100u16);
-
-        ch1.set_duty(max_duty / 8);
+    loop {
+        match player.update(now) {
+            Some(duty) => ch1.set_duty((max_duty as u32 * duty as u32 / 100) as u16),
+            None => {
+                effect = match effect {
+                    Effect::RampUp => Effect::RampDown,
+                    _ => Effect::RampUp
+                };
+                player = Player::new(effect.segments(), now).unwrap();
+            }
+        }
+
+        delay.delay_ms(1u8);
+        now += 1;
     }
 
 }
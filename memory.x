@@ -1,7 +0,0 @@
-MEMORY
-{
-  FLASH : ORIGIN = 0x08000000, LENGTH = 512K
-  RAM : ORIGIN = 0x20000000, LENGTH = 128K
-}
-
-_stack_start = ORIGIN(RAM) + LENGTH(RAM);
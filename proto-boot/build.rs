@@ -0,0 +1,27 @@
+//! Copies memory.x for the linker. With `--features signed` the public key from `PROTO_SIGNING_KEY`
+//! (64 hex digits, `proto-image keygen` prints it) is compiled in.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn main() {
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+
+    fs::copy("memory.x", out.join("memory.x")).unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=memory.x");
+
+    println!("cargo:rerun-if-env-changed=PROTO_SIGNING_KEY");
+    let key = if env::var_os("CARGO_FEATURE_SIGNED").is_some() {
+        let hex = env::var("PROTO_SIGNING_KEY").expect("--features signed needs PROTO_SIGNING_KEY");
+        let bytes: Vec<u8> = (0..hex.len()).step_by(2)
+            .map(| i | u8::from_str_radix(hex.get(i..i + 2).unwrap_or("x"), 16).expect("PROTO_SIGNING_KEY is not hex"))
+            .collect();
+        assert!(bytes.len() == 32, "PROTO_SIGNING_KEY has to be 32 bytes");
+        format!("Some({:?})", bytes)
+    } else {
+        String::from("None")
+    };
+    fs::write(out.join("key.rs"), format!("// generated by build.rs\npub const KEY: Option<[u8; 32]> = {};\n", key)).unwrap();
+}
@@ -0,0 +1,8 @@
+MEMORY
+{
+  /* sectors 0 to 2, the boot records and slots follow, see proto-image/src/layout.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 48K
+  RAM : ORIGIN = 0x20000000, LENGTH = 128K
+}
+
+_stack_start = ORIGIN(RAM) + LENGTH(RAM);
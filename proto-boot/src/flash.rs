@@ -0,0 +1,132 @@
+// Erasing and programming the slots and the boot records, like src/storage.rs of the firmware
+use core::ptr;
+
+use proto_image::layout::{SLOTS, STATE};
+use proto_image::state::{self, BootState, Record};
+use proto_image::update;
+use stm32f4xx_hal::stm32::FLASH;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+pub struct Flash {
+    flash: FLASH
+}
+
+impl Flash {
+    pub fn new(flash: FLASH) -> Flash {
+        Flash { flash }
+    }
+
+    pub fn state(&self) -> BootState {
+        state::load(sectors()).1
+    }
+
+    /// Appends `record`, compacts into the other state sector when the current one is full
+    pub fn note(&mut self, record: Record) -> Result<(), &'static str> {
+        let (current, state) = state::load(sectors());
+
+        self.unlock();
+        let result = match current {
+            Some(sector) if state.used + 1 < (STATE[sector].size / 4) as usize => {
+                self.program_bytes(STATE[sector].address + (state.used as u32 + 1) * 4, &record.to_word().to_le_bytes())
+            },
+            _ => {
+                let (next, generation) = match current {
+                    Some(sector) => (1 - sector, state::generation(sectors()[sector][0]).unwrap().wrapping_add(1)),
+                    None => (0, 0)
+                };
+                self.erase_sector(STATE[next].number).and_then(| _ | {
+                    state::compacted(&state, generation, record)
+                        .try_for_each(| (index, word) | self.program_bytes(STATE[next].address + index as u32 * 4, &word.to_le_bytes()))
+                })
+            }
+        };
+        self.lock();
+
+        result.map_err(| _ | "writing the boot record failed")
+    }
+
+    fn unlock(&mut self) {
+        self.flash.keyr.write(| w | unsafe { w.key().bits(KEY1) });
+        self.flash.keyr.write(| w | unsafe { w.key().bits(KEY2) });
+    }
+
+    fn lock(&mut self) {
+        self.flash.cr.modify(| _, w | w.lock().set_bit());
+    }
+
+    fn wait(&self) -> Result<(), ()> {
+        while self.flash.sr.read().bsy().bit_is_set() {}
+
+        let sr = self.flash.sr.read();
+        let failed = sr.pgserr().bit_is_set() || sr.pgperr().bit_is_set()
+            || sr.pgaerr().bit_is_set() || sr.wrperr().bit_is_set() || sr.operr().bit_is_set();
+
+        // error flags are cleared by writing 1
+        self.flash.sr.write(| w | w
+            .pgserr().set_bit()
+            .pgperr().set_bit()
+            .pgaerr().set_bit()
+            .wrperr().set_bit()
+            .operr().set_bit()
+            .eop().set_bit()
+        );
+
+        if failed { Err(()) } else { Ok(()) }
+    }
+
+    /// Up to 2s for a 128K sector
+    fn erase_sector(&mut self, sector: u8) -> Result<(), ()> {
+        self.wait()?;
+        self.flash.cr.modify(| _, w | unsafe { w.ser().set_bit().snb().bits(sector).psize().bits(0b10) });
+        self.flash.cr.modify(| _, w | w.strt().set_bit());
+        let result = self.wait();
+        self.flash.cr.modify(| _, w | w.ser().clear_bit());
+        result
+    }
+
+    fn program_bytes(&mut self, address: u32, bytes: &[u8]) -> Result<(), ()> {
+        // byte wise (PSIZE x8), the update arrives in small pieces anyway
+        self.flash.cr.modify(| _, w | unsafe { w.pg().set_bit().psize().bits(0b00) });
+
+        let mut result = Ok(());
+        for (i, byte) in bytes.iter().enumerate() {
+            unsafe { ptr::write_volatile((address as usize + i) as *mut u8, *byte) };
+
+            result = self.wait();
+            if result.is_err() {
+                break;
+            }
+        }
+
+        self.flash.cr.modify(| _, w | w.pg().clear_bit());
+        result
+    }
+}
+
+/// The words of both state sectors
+fn sectors() -> [&'static [u32]; 2] {
+    let words = | sector: usize | unsafe { core::slice::from_raw_parts(STATE[sector].address as *const u32, (STATE[sector].size / 4) as usize) };
+    [words(0), words(1)]
+}
+
+impl update::Flash for Flash {
+    fn erase(&mut self, slot: usize) -> Result<(), &'static str> {
+        self.unlock();
+        let result = self.erase_sector(SLOTS[slot].sector);
+        self.lock();
+        result.map_err(| _ | "erasing failed")
+    }
+
+    fn program(&mut self, address: u32, bytes: &[u8]) -> Result<(), &'static str> {
+        self.unlock();
+        let result = self.program_bytes(address, bytes);
+        self.lock();
+        result.map_err(| _ | "programming failed")
+    }
+
+    fn read(&self, address: u32, length: u32) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(address as *const u8, length as usize) }
+    }
+}
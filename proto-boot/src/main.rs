@@ -0,0 +1,250 @@
+//! proto-boot, the A/B bootloader of the Macro Proto.
+//!
+//! Starts the image of slot a or b the boot records ask for, an image on trial only `MAX_ATTEMPTS` times
+//! until the firmware confirms it, then the last confirmed one again. An image on trial runs with the
+//! independent watchdog, a hang resets and counts like a crash. Without a good image, or when the
+//! firmware asked for it with `update`, it waits for an update on the USB serial port:
+//!
+//!     info                  slots, versions and where the update goes
+//!     begin <length>        erases the slot for an image of `length` bytes with header
+//!     data <hex>            the next up to 64 bytes
+//!     end                   checks CRC, slot, version and signature, the image starts on trial next
+//!     boot                  restarts
+//!
+//! Every command is answered with `OK` or `ERR <reason>` like the console of the firmware, see tools/update.py.
+#![no_std]
+#![no_main]
+
+use core::fmt::Write;
+use core::panic::PanicInfo;
+
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::entry;
+use heapless::{String, Vec};
+use proto_image::header::{self, Header};
+use proto_image::layout::{BACKUP_REGISTER, SLOTS, UPDATE_REQUEST};
+use proto_image::state::Record;
+use proto_image::update::{decode_hex, Receiver};
+use stm32f4xx_hal::otg_fs::{UsbBus, USB, UsbBusType};
+use stm32f4xx_hal::{prelude::*, stm32};
+use usb_device::bus::UsbBusAllocator;
+use usb_device::prelude::*;
+use usbd_serial::{SerialPort, USB_CLASS_CDC};
+
+mod flash;
+use flash::Flash;
+
+mod key {
+    include!(concat!(env!("OUT_DIR"), "/key.rs"));
+}
+
+const LINE_LENGTH: usize = 160;
+
+/// LSI (32 kHz) / 256, 8 ms per count: resets after 8 s without the firmware feeding it
+const WATCHDOG_PRESCALER: u32 = 0b110;
+const WATCHDOG_RELOAD: u32 = 1000;
+
+static mut USB_BUF: [u32; 32] = [0; 32];
+static mut USB_BUS: Option<UsbBusAllocator<UsbBusType>> = None;
+
+#[entry]
+fn main() -> ! {
+    let peripherals = stm32::Peripherals::take().unwrap();
+    let mut flash = Flash::new(peripherals.FLASH);
+
+    let requested = unsafe { core::ptr::read_volatile(BACKUP_REGISTER as *const u32) } == UPDATE_REQUEST;
+    if requested {
+        // only once
+        peripherals.RCC.apb1enr.modify(| _, w | w.pwren().set_bit());
+        peripherals.PWR.cr.modify(| _, w | w.dbp().set_bit());
+        unsafe { core::ptr::write_volatile(BACKUP_REGISTER as *mut u32, 0) };
+    } else {
+        let state = flash.state();
+        let valid = [image(0).is_ok(), image(1).is_ok()];
+
+        if let Some((slot, record)) = state.decide(valid) {
+            // without the record the attempt would not count, better not start it at all
+            if record.map_or(Ok(()), | record | flash.note(record)).is_ok() {
+                if let Some(Record::Attempt(_)) = record {
+                    start_watchdog(&peripherals.IWDG);
+                }
+                unsafe { start(SLOTS[slot].image()) };
+            }
+        }
+    }
+
+    updater(peripherals, flash)
+}
+
+/// The header of a slot with a complete image, signed if the bootloader has a key
+fn image(slot: usize) -> Result<Header, &'static str> {
+    let slot = &SLOTS[slot];
+    let bytes = unsafe { core::slice::from_raw_parts(slot.address as *const u8, slot.size as usize) };
+    header::bootable(bytes, slot, key::KEY.as_ref())
+}
+
+/// Can not be stopped again until the next reset, the firmware feeds it from its main loop, see src/slots.rs
+fn start_watchdog(iwdg: &stm32::IWDG) {
+    unsafe {
+        iwdg.kr.write(| w | w.bits(0xCCCC));
+        // unlocks the prescaler and reload registers
+        iwdg.kr.write(| w | w.bits(0x5555));
+        iwdg.pr.write(| w | w.bits(WATCHDOG_PRESCALER));
+        iwdg.rlr.write(| w | w.bits(WATCHDOG_RELOAD));
+    }
+    while iwdg.sr.read().bits() != 0 {}
+    unsafe { iwdg.kr.write(| w | w.bits(0xAAAA)) };
+}
+
+/// Jumps to the firmware with the chip still in reset state, nothing but the flash was touched
+unsafe fn start(vector_table: u32) -> ! {
+    (*SCB::ptr()).vtor.write(vector_table);
+    let stack = core::ptr::read_volatile(vector_table as *const u32);
+    let reset = core::ptr::read_volatile((vector_table + 4) as *const u32);
+    cortex_m::register::msp::write(stack);
+    let reset: extern "C" fn() -> ! = core::mem::transmute(reset as usize);
+    reset()
+}
+
+fn updater(peripherals: stm32::Peripherals, mut flash: Flash) -> ! {
+    let rcc = peripherals.RCC.constrain();
+    // same clocks as the firmware, USB needs the 48 MHz
+    let clocks = rcc
+        .cfgr
+        .use_hse(25.mhz())
+        .sysclk(96.mhz())
+        .hclk(96.mhz())
+        .pclk1(48.mhz())
+        .pclk2(96.mhz())
+        .freeze();
+
+    let gpioa = peripherals.GPIOA.split();
+    let usb = USB {
+        hclk: clocks.hclk(),
+        usb_global: peripherals.OTG_FS_GLOBAL,
+        usb_device: peripherals.OTG_FS_DEVICE,
+        usb_pwrclk: peripherals.OTG_FS_PWRCLK,
+        pin_dp: gpioa.pa12.into_alternate_af10(),
+        pin_dm: gpioa.pa11.into_alternate_af10(),
+    };
+
+    let bus = unsafe {
+        USB_BUS = Some(UsbBus::new(usb, &mut USB_BUF));
+        USB_BUS.as_ref().unwrap()
+    };
+    let mut serial = SerialPort::new(bus);
+    let mut usb_dev = UsbDeviceBuilder::new(bus, UsbVidPid(0x16c0, 0x27dd))
+        .manufacturer("Lukas Sturm")
+        .product("Macro Proto Updater")
+        .serial_number("update")
+        .device_class(USB_CLASS_CDC)
+        .build();
+
+    let mut receiver = Receiver::new();
+    let mut line: Vec<u8, LINE_LENGTH> = Vec::new();
+    let mut too_long = false;
+
+    loop {
+        if !usb_dev.poll(&mut [&mut serial]) {
+            continue;
+        }
+
+        let mut buf = [0u8; 64];
+        let count = match serial.read(&mut buf) {
+            Ok(count) => count,
+            Err(_) => continue
+        };
+
+        for byte in &buf[..count] {
+            if *byte != b'\r' && *byte != b'\n' {
+                too_long |= line.push(*byte).is_err();
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut reply: String<LINE_LENGTH> = String::new();
+            let result = match core::str::from_utf8(&line) {
+                _ if too_long => Err("line too long"),
+                Ok(text) => run(text.trim(), &mut receiver, &mut flash, &mut reply),
+                Err(_) => Err("not UTF-8")
+            };
+            let _ = match result {
+                Ok(_) => write!(reply, "OK\r\n"),
+                Err(reason) => write!(reply, "ERR {}\r\n", reason)
+            };
+            send(&mut usb_dev, &mut serial, reply.as_bytes());
+
+            line.clear();
+            too_long = false;
+            if result == Ok("boot") {
+                // the OK is in the FIFO, time for the host to fetch it
+                cortex_m::asm::delay(96_000 * 20);
+                SCB::sys_reset();
+            }
+        }
+    }
+}
+
+/// Runs one command, `reply` gets the lines before `OK`
+fn run(line: &str, receiver: &mut Receiver, flash: &mut Flash, reply: &mut String<LINE_LENGTH>) -> Result<&'static str, &'static str> {
+    let mut words = line.splitn(2, ' ');
+    let command = words.next().unwrap_or("");
+    let arguments = words.next().unwrap_or("").trim();
+    let state = flash.state();
+
+    match command {
+        "info" => {
+            for (slot, name) in SLOTS.iter().map(| slot | slot.name).enumerate() {
+                let _ = match image(slot) {
+                    Ok(header) => write!(reply, "slot {} {}{}\r\n", name, header.version,
+                        if state.confirmed == Some(slot) { " confirmed" } else if state.failed() == Some(slot) { " failed" } else { "" }),
+                    Err(reason) => write!(reply, "slot {} {}\r\n", name, reason)
+                };
+            }
+            let _ = write!(reply, "update {}{}\r\n", SLOTS[state.update_slot()].name, if key::KEY.is_some() { " signed" } else { "" });
+            Ok("info")
+        },
+        "begin" => {
+            let length = arguments.parse().map_err(| _ | "expected the length")?;
+            receiver.begin(flash, state.update_slot(), length)?;
+            Ok("begin")
+        },
+        "data" => {
+            let mut bytes = [0u8; 64];
+            let count = decode_hex(arguments, &mut bytes)?;
+            receiver.data(flash, &bytes[..count])?;
+            Ok("data")
+        },
+        "end" => {
+            // no downgrades below the image that runs fine
+            let minimum = state.confirmed.and_then(| slot | image(slot).ok()).map(| header | header.version);
+            let (slot, header) = receiver.end(flash, key::KEY.as_ref(), minimum)?;
+            flash.note(Record::Installed(slot))?;
+            let _ = write!(reply, "installed {} in slot {}\r\n", header.version, SLOTS[slot].name);
+            Ok("end")
+        },
+        "boot" => Ok("boot"),
+        _ => Err("unknown command")
+    }
+}
+
+/// Blocks until the host took everything, gives up when the port is closed
+fn send(usb_dev: &mut UsbDevice<UsbBusType>, serial: &mut SerialPort<UsbBusType>, mut data: &[u8]) {
+    while !data.is_empty() && serial.dtr() {
+        usb_dev.poll(&mut [serial]);
+        match serial.write(data) {
+            Ok(count) => data = &data[count..],
+            Err(UsbError::WouldBlock) => (),
+            Err(_) => return
+        }
+    }
+}
+
+#[inline(never)]
+#[panic_handler]
+fn panic(_: &PanicInfo) -> ! {
+    // the next reset tries again
+    loop {}
+}
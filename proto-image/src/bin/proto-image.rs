@@ -0,0 +1,116 @@
+//! Host tool for proto-boot images, needs `--features host`.
+//!
+//!     proto-image keygen <secret key file>
+//!     proto-image pack <firmware.bin> <image> <slot a|b> <version 1.2.3> [secret key file]
+//!     proto-image verify <image> <slot a|b> [public key hex]
+//!
+//! `keygen` prints the public key for `PROTO_SIGNING_KEY` of the bootloader build.
+//! The firmware has to be built for the slot, `PROTO_SLOT=a cargo build --release`, see build.rs.
+use std::convert::TryInto;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::process;
+
+use ed25519_compact::{KeyPair, Seed};
+use proto_image::header::{self, Header, Version, SIGNATURE_SIZE};
+use proto_image::layout::{self, SLOTS};
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(| byte | format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Result<Vec<u8>, String> {
+    let text = text.trim();
+    if text.len() & 1 != 0 {
+        return Err("odd number of hex digits".into());
+    }
+    (0..text.len()).step_by(2)
+        .map(| i | u8::from_str_radix(&text[i..i + 2], 16).map_err(| _ | format!("not hex: {}", text)))
+        .collect()
+}
+
+fn key_pair(path: &str) -> Result<KeyPair, String> {
+    let seed = unhex(&fs::read_to_string(path).map_err(| error | format!("{}: {}", path, error))?)?;
+    let seed: [u8; 32] = seed.try_into().map_err(| _ | "a secret key is 32 bytes".to_string())?;
+    Ok(KeyPair::from_seed(Seed::new(seed)))
+}
+
+fn slot(name: &str) -> Result<usize, String> {
+    layout::slot(name).ok_or_else(|| format!("no slot {}, a or b", name))
+}
+
+fn keygen(path: &str) -> Result<(), String> {
+    let mut seed = [0u8; 32];
+    fs::File::open("/dev/urandom").and_then(| mut file | file.read_exact(&mut seed)).map_err(| error | error.to_string())?;
+
+    fs::write(path, hex(&seed) + "\n").map_err(| error | error.to_string())?;
+    let pair = KeyPair::from_seed(Seed::new(seed));
+    println!("{}", hex(pair.pk.as_ref()));
+    Ok(())
+}
+
+fn pack(firmware: &str, image: &str, slot_name: &str, version: &str, key: Option<&str>) -> Result<(), String> {
+    let slot = &SLOTS[slot(slot_name)?];
+    let version = Version::parse(version).ok_or("version like 1.2.3")?;
+    let firmware = fs::read(firmware).map_err(| error | format!("{}: {}", firmware, error))?;
+
+    if firmware.len() as u32 > slot.max_length() {
+        return Err(format!("{} bytes do not fit the slot, {} at most", firmware.len(), slot.max_length()));
+    }
+    // the reset vector tells where the firmware was linked
+    let reset = u32::from_le_bytes(firmware.get(4..8).ok_or("firmware too short")?.try_into().unwrap());
+    if reset < slot.image() || reset >= slot.address + slot.size {
+        return Err(format!("firmware is not linked for slot {}, build it with PROTO_SLOT={}", slot.name, slot.name));
+    }
+
+    let header = Header::new(&firmware, slot, version, key.is_some());
+    let mut bytes = header.to_bytes().to_vec();
+    bytes.extend_from_slice(&firmware);
+
+    if let Some(key) = key {
+        let pair = key_pair(key)?;
+        let signature = pair.sk.sign(header::signed_part(&bytes), None);
+        bytes[..SIGNATURE_SIZE].copy_from_slice(signature.as_ref());
+    }
+
+    fs::write(image, &bytes).map_err(| error | format!("{}: {}", image, error))?;
+    println!("{} {} bytes, slot {}, version {}, {}", image, bytes.len(), slot.name, version,
+        if key.is_some() { "signed" } else { "unsigned" });
+    Ok(())
+}
+
+fn verify(image: &str, slot_name: &str, key: Option<&str>) -> Result<(), String> {
+    let slot = &SLOTS[slot(slot_name)?];
+    let bytes = fs::read(image).map_err(| error | format!("{}: {}", image, error))?;
+
+    let header = header::verify(&bytes, slot)?;
+    if let Some(key) = key {
+        let key: [u8; 32] = unhex(key)?.try_into().map_err(| _ | "a public key is 32 bytes".to_string())?;
+        header::verify_signature(&bytes, &header, &key)?;
+    }
+    println!("{} ok, slot {}, version {}, {} bytes, {}", image, slot.name, header.version, header.length,
+        if header.signed { "signed" } else { "unsigned" });
+    Ok(())
+}
+
+fn main() {
+    let arguments: Vec<String> = env::args().skip(1).collect();
+    let arguments: Vec<&str> = arguments.iter().map(| argument | argument.as_str()).collect();
+
+    let result = match arguments.as_slice() {
+        ["keygen", path] => keygen(path),
+        ["pack", firmware, image, slot, version] => pack(firmware, image, slot, version, None),
+        ["pack", firmware, image, slot, version, key] => pack(firmware, image, slot, version, Some(key)),
+        ["verify", image, slot] => verify(image, slot, None),
+        ["verify", image, slot, key] => verify(image, slot, Some(key)),
+        _ => Err("usage: proto-image keygen <secret key file>\n\
+            \x20      proto-image pack <firmware.bin> <image> <slot a|b> <version 1.2.3> [secret key file]\n\
+            \x20      proto-image verify <image> <slot a|b> [public key hex]".into())
+    };
+
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
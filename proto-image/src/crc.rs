@@ -0,0 +1,63 @@
+// CRC-32 like zlib and Python's zlib.crc32, with a 16 entry table to keep the bootloader small
+
+const TABLE: [u32; 16] = [
+    0x0000_0000, 0x1DB7_1064, 0x3B6E_20C8, 0x26D9_30AC, 0x76DC_4190, 0x6B6B_51F4, 0x4DB2_6158, 0x5005_713C,
+    0xEDB8_8320, 0xF00F_9344, 0xD6D6_A3E8, 0xCB61_B38C, 0x9B64_C2B0, 0x86D3_D2D4, 0xA00A_E278, 0xBDBD_F21C
+];
+
+pub struct Crc32 {
+    value: u32
+}
+
+impl Crc32 {
+    pub const fn new() -> Crc32 {
+        Crc32 { value: 0xFFFF_FFFF }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.value ^= *byte as u32;
+            self.value = (self.value >> 4) ^ TABLE[(self.value & 0xF) as usize];
+            self.value = (self.value >> 4) ^ TABLE[(self.value & 0xF) as usize];
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.value
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32::new()
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_as_zlib() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"a"), 0xE8B7_BE43);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414F_A339);
+        assert_eq!(crc32(&[0xFF; 4]), 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn in_pieces() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+}
@@ -0,0 +1,237 @@
+// The 512 byte header in front of an image.
+//
+//   0  signature, Ed25519 over everything from byte 64 to the end of the image, zeros if unsigned
+//  64  magic "MPI1"
+//  68  flags, bit 0: signed
+//  72  firmware version, major << 16 | minor << 8 | patch
+//  76  image length
+//  80  CRC-32 of the image
+//  84  load address, where the vector table has to be
+//  88  CRC-32 of bytes 64 to 88
+//  92  0xFF up to 512
+//
+// Numbers are little endian. The signed part is contiguous in flash, it can be verified in place.
+use core::convert::TryInto;
+use core::fmt;
+
+use crate::crc::crc32;
+use crate::layout::{Slot, HEADER_SIZE};
+
+pub const MAGIC: [u8; 4] = *b"MPI1";
+pub const SIGNATURE_SIZE: usize = 64;
+const FLAG_SIGNED: u32 = 1 << 0;
+/// End of the fields covered by the header CRC
+const FIELDS_END: usize = 88;
+
+/// major.minor.patch packed into a u32
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub struct Version(pub u32);
+
+impl Version {
+    pub fn new(major: u8, minor: u8, patch: u8) -> Version {
+        Version((major as u32) << 16 | (minor as u32) << 8 | patch as u32)
+    }
+
+    /// `1.2.3`
+    pub fn parse(text: &str) -> Option<Version> {
+        let mut parts = text.split('.').map(| part | part.parse::<u8>());
+        let version = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) => Version::new(major, minor, patch),
+            _ => return None
+        };
+        Some(version)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", (self.0 >> 16) & 0xFF, (self.0 >> 8) & 0xFF, self.0 & 0xFF)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Header {
+    pub version: Version,
+    pub length: u32,
+    pub crc: u32,
+    pub load_address: u32,
+    pub signed: bool
+}
+
+fn word(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Header {
+    /// Header for `image`, built to run from `slot`
+    pub fn new(image: &[u8], slot: &Slot, version: Version, signed: bool) -> Header {
+        Header { version, length: image.len() as u32, crc: crc32(image), load_address: slot.image(), signed }
+    }
+
+    /// Checks magic and header CRC, not the image
+    pub fn parse(bytes: &[u8]) -> Result<Header, &'static str> {
+        if bytes.len() < HEADER_SIZE as usize {
+            return Err("header too short");
+        }
+        if bytes[64..68] != MAGIC {
+            return Err("no image");
+        }
+        if crc32(&bytes[64..FIELDS_END]) != word(bytes, FIELDS_END) {
+            return Err("header damaged");
+        }
+
+        Ok(Header {
+            signed: word(bytes, 68) & FLAG_SIGNED != 0,
+            version: Version(word(bytes, 72)),
+            length: word(bytes, 76),
+            crc: word(bytes, 80),
+            load_address: word(bytes, 84)
+        })
+    }
+
+    /// Without signature, see `signed_part`
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE as usize] {
+        let mut bytes = [0xFF; HEADER_SIZE as usize];
+        bytes[..SIGNATURE_SIZE].copy_from_slice(&[0; SIGNATURE_SIZE]);
+        bytes[64..68].copy_from_slice(&MAGIC);
+        bytes[68..72].copy_from_slice(&(if self.signed { FLAG_SIGNED } else { 0 }).to_le_bytes());
+        bytes[72..76].copy_from_slice(&self.version.0.to_le_bytes());
+        bytes[76..80].copy_from_slice(&self.length.to_le_bytes());
+        bytes[80..84].copy_from_slice(&self.crc.to_le_bytes());
+        bytes[84..88].copy_from_slice(&self.load_address.to_le_bytes());
+        let crc = crc32(&bytes[64..FIELDS_END]);
+        bytes[FIELDS_END..FIELDS_END + 4].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+}
+
+/// What the signature covers, `image` starts with the header
+pub fn signed_part(image: &[u8]) -> &[u8] {
+    &image[SIGNATURE_SIZE..]
+}
+
+/// Header, slot and CRC of an image as it is in the slot. `bytes` may be the whole slot
+pub fn verify(bytes: &[u8], slot: &Slot) -> Result<Header, &'static str> {
+    let header = Header::parse(bytes)?;
+    if header.load_address != slot.image() {
+        return Err("image is built for the other slot");
+    }
+    if header.length > slot.max_length() || bytes.len() < (HEADER_SIZE + header.length) as usize {
+        return Err("image too long");
+    }
+
+    if crc32(&bytes[HEADER_SIZE as usize..(HEADER_SIZE + header.length) as usize]) != header.crc {
+        return Err("image damaged");
+    }
+    Ok(header)
+}
+
+/// An image the bootloader may start: `verify`, and with a `key` signed by it
+pub fn bootable(bytes: &[u8], slot: &Slot, key: Option<&[u8; 32]>) -> Result<Header, &'static str> {
+    let header = verify(bytes, slot)?;
+    if let Some(key) = key {
+        #[cfg(feature = "ed25519")]
+        verify_signature(bytes, &header, key)?;
+        #[cfg(not(feature = "ed25519"))]
+        {
+            let _ = key;
+            return Err("built without ed25519");
+        }
+    }
+    Ok(header)
+}
+
+/// The Ed25519 signature of a verified image against the `key` compiled into the bootloader
+#[cfg(feature = "ed25519")]
+pub fn verify_signature(bytes: &[u8], header: &Header, key: &[u8; 32]) -> Result<(), &'static str> {
+    use ed25519_compact::{PublicKey, Signature};
+
+    if !header.signed {
+        return Err("image not signed");
+    }
+    let end = (HEADER_SIZE + header.length) as usize;
+    let signature = Signature::from_slice(&bytes[..SIGNATURE_SIZE]).map_err(| _ | "bad signature")?;
+    PublicKey::new(*key).verify(signed_part(&bytes[..end]), &signature).map_err(| _ | "wrong signature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::SLOTS;
+
+    /// Header and `length` bytes of image for `slot`
+    fn image(slot: usize, length: usize) -> Vec<u8> {
+        let firmware: Vec<u8> = (0..length).map(| i | i as u8).collect();
+        let header = Header::new(&firmware, &SLOTS[slot], Version::new(1, 2, 3), false);
+        let mut bytes = header.to_bytes().to_vec();
+        bytes.extend(firmware);
+        bytes
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(Version::parse("1.2.3"), Some(Version(0x01_02_03)));
+        assert_eq!(Version::new(1, 2, 3).to_string(), "1.2.3");
+        assert!(Version::parse("1.10.0") > Version::parse("1.9.9"));
+        assert_eq!(Version::parse("1.2"), None);
+        assert_eq!(Version::parse("1.2.3.4"), None);
+        assert_eq!(Version::parse("1.256.0"), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let header = Header { version: Version::new(2, 0, 1), length: 1234, crc: 0xDEAD_BEEF, load_address: SLOTS[1].image(), signed: true };
+        let bytes = header.to_bytes();
+        assert_eq!(Header::parse(&bytes), Ok(header));
+        assert_eq!(bytes[..SIGNATURE_SIZE], [0; SIGNATURE_SIZE]);
+        assert!(bytes[FIELDS_END + 4..].iter().all(| byte | *byte == 0xFF));
+
+        let unsigned = Header { signed: false, ..header };
+        assert_eq!(Header::parse(&unsigned.to_bytes()), Ok(unsigned));
+    }
+
+    #[test]
+    fn broken_headers() {
+        let bytes = Header::new(b"image", &SLOTS[0], Version::new(1, 0, 0), false).to_bytes();
+        assert_eq!(Header::parse(&bytes[..HEADER_SIZE as usize - 1]), Err("header too short"));
+        // erased flash
+        assert_eq!(Header::parse(&[0xFF; HEADER_SIZE as usize]), Err("no image"));
+
+        let mut magic = bytes;
+        magic[67] = b'2';
+        assert_eq!(Header::parse(&magic), Err("no image"));
+
+        // every field is covered by the CRC, the signature and the padding are not
+        for offset in 68..FIELDS_END + 4 {
+            let mut damaged = bytes;
+            damaged[offset] ^= 0x10;
+            assert_eq!(Header::parse(&damaged), Err("header damaged"), "byte {}", offset);
+        }
+        let mut padding = bytes;
+        padding[0] = 1;
+        padding[200] = 0;
+        assert!(Header::parse(&padding).is_ok());
+    }
+
+    #[test]
+    fn verifies_the_image() {
+        let bytes = image(0, 1000);
+        assert_eq!(verify(&bytes, &SLOTS[0]).map(| header | header.length), Ok(1000));
+        // a whole slot of flash, erased behind the image
+        let mut slot = bytes.clone();
+        slot.resize(SLOTS[0].size as usize, 0xFF);
+        assert!(verify(&slot, &SLOTS[0]).is_ok());
+
+        assert_eq!(verify(&bytes, &SLOTS[1]), Err("image is built for the other slot"));
+        assert_eq!(verify(&bytes[..bytes.len() - 1], &SLOTS[0]), Err("image too long"));
+
+        let mut damaged = bytes.clone();
+        damaged[HEADER_SIZE as usize + 500] ^= 1;
+        assert_eq!(verify(&damaged, &SLOTS[0]), Err("image damaged"));
+
+        let too_long = Header { length: SLOTS[0].max_length() + 4, ..Header::parse(&bytes).unwrap() };
+        let mut bytes = too_long.to_bytes().to_vec();
+        bytes.resize(SLOTS[0].size as usize + 4, 0);
+        assert_eq!(verify(&bytes, &SLOTS[0]), Err("image too long"));
+    }
+}
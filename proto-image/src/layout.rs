@@ -0,0 +1,59 @@
+// Flash of the STM32F411CE with proto-boot. Also used by the firmware's build.rs for the memory.x of a slot
+
+/// Sectors 0 to 2, the bootloader
+pub const BOOTLOADER: u32 = 0x0800_0000;
+pub const BOOTLOADER_SIZE: u32 = 48 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Sector {
+    pub address: u32,
+    pub number: u8,
+    pub size: u32
+}
+
+/// Sectors 3 and 4, boot records, see state.rs. They take turns, so compacting never erases the only copy
+pub const STATE: [Sector; 2] = [
+    Sector { address: 0x0800_C000, number: 3, size: 16 * 1024 },
+    Sector { address: 0x0801_0000, number: 4, size: 64 * 1024 }
+];
+
+/// Sector 7 keeps the settings of the firmware, neither the bootloader nor an update touches it
+pub const SETTINGS: u32 = 0x0806_0000;
+
+/// Header in front of every image, the vector table after it has to be 512 byte aligned
+pub const HEADER_SIZE: u32 = 512;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Slot {
+    pub name: char,
+    pub address: u32,
+    pub sector: u8,
+    pub size: u32
+}
+
+impl Slot {
+    /// Where the vector table of the firmware is, what it is linked for
+    pub const fn image(&self) -> u32 {
+        self.address + HEADER_SIZE
+    }
+
+    pub const fn max_length(&self) -> u32 {
+        self.size - HEADER_SIZE
+    }
+}
+
+/// Sectors 5 and 6
+pub const SLOTS: [Slot; 2] = [
+    Slot { name: 'a', address: 0x0802_0000, sector: 5, size: 128 * 1024 },
+    Slot { name: 'b', address: 0x0804_0000, sector: 6, size: 128 * 1024 }
+];
+
+/// Slot number for `a` or `b`
+pub fn slot(name: &str) -> Option<usize> {
+    SLOTS.iter().position(| slot | name.len() == 1 && name.starts_with(slot.name))
+}
+
+/// RTC backup register 0, survives the reset from the firmware into the updater
+pub const BACKUP_REGISTER: u32 = 0x4000_2850;
+/// In `BACKUP_REGISTER`, the bootloader stays in update mode once
+pub const UPDATE_REQUEST: u32 = 0x5550_4454;
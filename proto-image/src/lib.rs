@@ -0,0 +1,11 @@
+//! Firmware images for proto-boot, shared by the bootloader, the firmware and the host tool.
+//!
+//! A slot holds a header (header.rs) and the image, the state sector a list of boot records (state.rs).
+//! Nothing here touches hardware, so all of it runs on the host too.
+#![cfg_attr(not(test), no_std)]
+
+pub mod crc;
+pub mod header;
+pub mod layout;
+pub mod state;
+pub mod update;
@@ -0,0 +1,331 @@
+// A/B boot records, appended word by word to the state sector so nothing has to be erased on the way.
+//
+// The bootloader notes `Installed` after an update and `Attempt` before every start of an image on trial.
+// The firmware notes `Confirmed` once it runs fine. An image that is started `MAX_ATTEMPTS` times without
+// confirming is given up and the last confirmed one starts again.
+//
+// The records are in one of the two sectors of `layout::STATE`, the one whose first word has the later generation.
+// A full sector is compacted into the other one: the records first, its generation word last. Until that word is
+// complete the old sector stays current, a reset on the way loses nothing.
+
+/// Starts of an unconfirmed image before it is rolled back
+pub const MAX_ATTEMPTS: u8 = 3;
+
+const MARKER: u32 = 0xB5 << 24;
+const SECTOR_MARKER: u32 = 0xB6 << 24;
+const ERASED: u32 = 0xFFFF_FFFF;
+
+/// First word of a state sector in use
+pub fn sector_word(generation: u16) -> u32 {
+    SECTOR_MARKER | generation as u32
+}
+
+/// Generation of a state sector by its first word, `None` if it is not in use
+pub fn generation(word: u32) -> Option<u16> {
+    if word & 0xFFFF_0000 == SECTOR_MARKER { Some(word as u16) } else { None }
+}
+
+/// The state sector with the records by the first words of both, `None` if neither is in use yet
+pub fn current(first: [u32; 2]) -> Option<usize> {
+    match (generation(first[0]), generation(first[1])) {
+        // generations wrap
+        (Some(a), Some(b)) => Some(if (b.wrapping_sub(a) as i16) > 0 { 1 } else { 0 }),
+        (Some(_), None) => Some(0),
+        (None, Some(_)) => Some(1),
+        (None, None) => None
+    }
+}
+
+/// The current sector and its state from the words of both sectors, an empty state without one
+pub fn load(sectors: [&[u32]; 2]) -> (Option<usize>, BootState) {
+    match current([sectors[0][0], sectors[1][0]]) {
+        Some(sector) => (Some(sector), BootState::read(sectors[sector][1..].iter().copied())),
+        None => (None, BootState::default())
+    }
+}
+
+/// Word index and word for compacting `state` and then `record` into the other sector with `generation`,
+/// in the order to program them after erasing it
+pub fn compacted(state: &BootState, generation: u16, record: Record) -> impl Iterator<Item = (usize, u32)> {
+    state.records()
+        .chain(Some(record))
+        .enumerate()
+        .map(| (index, record) | (index + 1, record.to_word()))
+        .chain(Some((0, sector_word(generation))))
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Record {
+    Installed(usize),
+    Attempt(usize),
+    Confirmed(usize)
+}
+
+impl Record {
+    pub fn to_word(self) -> u32 {
+        let (kind, slot) = match self {
+            Record::Installed(slot) => (1, slot),
+            Record::Attempt(slot) => (2, slot),
+            Record::Confirmed(slot) => (3, slot)
+        };
+        MARKER | kind << 8 | slot as u32
+    }
+
+    /// `None` for anything else, e.g. a word cut short by a reset while programming
+    pub fn from_word(word: u32) -> Option<Record> {
+        if word & 0xFFFF_0000 != MARKER || word & 0xFF > 1 {
+            return None;
+        }
+        let slot = (word & 0xFF) as usize;
+        match (word >> 8) & 0xFF {
+            1 => Some(Record::Installed(slot)),
+            2 => Some(Record::Attempt(slot)),
+            3 => Some(Record::Confirmed(slot)),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct BootState {
+    /// Slot of the last image that confirmed
+    pub confirmed: Option<usize>,
+    /// Slot with a new image that did not confirm yet, and how often it was started
+    pub trial: Option<(usize, u8)>,
+    /// Words in use, the next record goes after them
+    pub used: usize
+}
+
+impl BootState {
+    /// Replays the records of a state sector, the words after its generation, up to the first erased one
+    pub fn read<I: Iterator<Item = u32>>(words: I) -> BootState {
+        let mut state = BootState::default();
+
+        for word in words.take_while(| word | *word != ERASED) {
+            state.used += 1;
+            match Record::from_word(word) {
+                Some(Record::Installed(slot)) => state.trial = Some((slot, 0)),
+                Some(Record::Attempt(slot)) => {
+                    if let Some((trial, attempts)) = state.trial.as_mut() {
+                        if *trial == slot {
+                            *attempts = attempts.saturating_add(1);
+                        }
+                    }
+                },
+                Some(Record::Confirmed(slot)) => {
+                    state.confirmed = Some(slot);
+                    if state.trial.map(| (trial, _) | trial) == Some(slot) {
+                        state.trial = None;
+                    }
+                },
+                None => ()
+            }
+        }
+        state
+    }
+
+    /// A trial that ran out of attempts
+    pub fn failed(&self) -> Option<usize> {
+        match self.trial {
+            Some((slot, attempts)) if attempts >= MAX_ATTEMPTS => Some(slot),
+            _ => None
+        }
+    }
+
+    /// The slot to start and the record to note before, for the slots that hold a verified image.
+    /// `None` if there is nothing to start, then the bootloader waits for an update
+    pub fn decide(&self, valid: [bool; 2]) -> Option<(usize, Option<Record>)> {
+        if let Some((slot, attempts)) = self.trial {
+            if valid[slot] && attempts < MAX_ATTEMPTS {
+                return Some((slot, Some(Record::Attempt(slot))));
+            }
+        }
+        if let Some(slot) = self.confirmed {
+            if valid[slot] {
+                return Some((slot, None));
+            }
+        }
+        // e.g. flashed with a debugger, it confirms itself
+        (0..valid.len())
+            .find(| slot | valid[*slot] && self.failed() != Some(*slot))
+            .map(| slot | (slot, None))
+    }
+
+    /// Where the next update goes, never over the last image that confirmed
+    pub fn update_slot(&self) -> usize {
+        match self.confirmed {
+            Some(slot) => 1 - slot,
+            None => 0
+        }
+    }
+
+    /// The same state in as few records as possible, for when the sector is full, see `compacted`
+    pub fn records(&self) -> impl Iterator<Item = Record> {
+        let confirmed = self.confirmed.map(Record::Confirmed);
+        let (installed, attempts) = match self.trial {
+            Some((slot, attempts)) => (Some(Record::Installed(slot)), attempts),
+            None => (None, 0)
+        };
+        let slot = self.trial.map_or(0, | (slot, _) | slot);
+
+        confirmed.into_iter()
+            .chain(installed)
+            .chain((0..attempts.min(MAX_ATTEMPTS)).map(move | _ | Record::Attempt(slot)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(records: &[Record]) -> BootState {
+        BootState::read(records.iter().map(| record | record.to_word()))
+    }
+
+    /// What the bootloader starts with these records, noting the attempt like it does
+    fn boot(records: &mut Vec<Record>, valid: [bool; 2]) -> Option<usize> {
+        let (slot, record) = read(records).decide(valid)?;
+        records.extend(record);
+        Some(slot)
+    }
+
+    #[test]
+    fn words() {
+        for record in [Record::Installed(0), Record::Attempt(1), Record::Confirmed(1)].iter() {
+            assert_eq!(Record::from_word(record.to_word()), Some(*record));
+        }
+        assert_eq!(Record::from_word(0), None);
+        assert_eq!(Record::from_word(ERASED), None);
+        // a slot that does not exist, a kind that does not exist
+        assert_eq!(Record::from_word(Record::Attempt(0).to_word() | 2), None);
+        assert_eq!(Record::from_word(MARKER | 4 << 8), None);
+        // cut short while programming, bits only go from 1 to 0
+        assert_eq!(Record::from_word(Record::Confirmed(0).to_word() | 0xFF00_0000), None);
+    }
+
+    #[test]
+    fn replay() {
+        let state = read(&[Record::Installed(0), Record::Confirmed(0), Record::Installed(1), Record::Attempt(1), Record::Attempt(0)]);
+        assert_eq!(state, BootState { confirmed: Some(0), trial: Some((1, 1)), used: 5 });
+        assert_eq!(state.update_slot(), 1);
+
+        let state = read(&[Record::Installed(1), Record::Attempt(1), Record::Confirmed(1)]);
+        assert_eq!(state, BootState { confirmed: Some(1), trial: None, used: 3 });
+        assert_eq!(state.update_slot(), 0);
+
+        // stops at the first erased word, broken words still take their place
+        let words = [Record::Confirmed(0).to_word(), 0x1234_5678, ERASED, Record::Confirmed(1).to_word()];
+        let state = BootState::read(words.iter().copied());
+        assert_eq!(state, BootState { confirmed: Some(0), trial: None, used: 2 });
+    }
+
+    #[test]
+    fn confirmed_update() {
+        let mut records = vec![Record::Confirmed(0), Record::Installed(1)];
+        assert_eq!(boot(&mut records, [true, true]), Some(1));
+        records.push(Record::Confirmed(1));
+        assert_eq!(boot(&mut records, [true, true]), Some(1));
+        assert_eq!(records.len(), 4);
+        assert_eq!(read(&records).update_slot(), 0);
+    }
+
+    #[test]
+    fn rollback_after_max_attempts() {
+        let mut records = vec![Record::Confirmed(0), Record::Installed(1)];
+        for _ in 0..MAX_ATTEMPTS {
+            assert_eq!(read(&records).failed(), None);
+            assert_eq!(boot(&mut records, [true, true]), Some(1));
+        }
+        // the last attempt still confirms if it gets that far, this one did not
+        assert_eq!(read(&records).failed(), Some(1));
+        // back to the old one, without counting anything
+        assert_eq!(boot(&mut records, [true, true]), Some(0));
+        assert_eq!(boot(&mut records, [true, true]), Some(0));
+        assert_eq!(records.len(), 2 + MAX_ATTEMPTS as usize);
+        // the next update goes over the failed image
+        assert_eq!(read(&records).update_slot(), 1);
+    }
+
+    #[test]
+    fn without_a_good_image() {
+        // flashed with a debugger, no records at all
+        assert_eq!(boot(&mut Vec::new(), [false, true]), Some(1));
+        assert_eq!(boot(&mut Vec::new(), [false, false]), None);
+
+        // the confirmed image is gone and the trial failed
+        let mut records = vec![Record::Installed(1), Record::Attempt(1), Record::Attempt(1), Record::Attempt(1)];
+        assert_eq!(boot(&mut records, [false, true]), None);
+        assert_eq!(boot(&mut records, [true, true]), Some(0));
+
+        // an installed image that was damaged since
+        let mut records = vec![Record::Confirmed(0), Record::Installed(1)];
+        assert_eq!(boot(&mut records, [true, false]), Some(0));
+    }
+
+    #[test]
+    fn compaction_keeps_the_state() {
+        let histories: [&[Record]; 5] = [
+            &[],
+            &[Record::Confirmed(1)],
+            &[Record::Confirmed(0), Record::Installed(1), Record::Attempt(1), Record::Attempt(1)],
+            &[Record::Installed(0), Record::Attempt(0), Record::Attempt(0), Record::Attempt(0)],
+            &[Record::Confirmed(0), Record::Installed(1), Record::Confirmed(1), Record::Installed(0), Record::Attempt(1)]
+        ];
+        for history in histories.iter() {
+            let state = read(history);
+            let compacted: Vec<Record> = state.records().collect();
+            assert!(compacted.len() <= 2 + MAX_ATTEMPTS as usize);
+            assert_eq!(read(&compacted), BootState { used: compacted.len(), ..state }, "{:?}", history);
+        }
+
+        // more attempts than count can't be noted, they compact to a failed trial all the same
+        let state = read(&[Record::Installed(0), Record::Attempt(0), Record::Attempt(0), Record::Attempt(0), Record::Attempt(0)]);
+        let compacted = read(&state.records().collect::<Vec<_>>());
+        assert_eq!(compacted.failed(), Some(0));
+        assert_eq!(compacted.decide([true, false]), None);
+    }
+
+    #[test]
+    fn current_sector() {
+        assert_eq!(current([ERASED, ERASED]), None);
+        assert_eq!(current([sector_word(0), ERASED]), Some(0));
+        assert_eq!(current([ERASED, sector_word(7)]), Some(1));
+        assert_eq!(current([sector_word(5), sector_word(6)]), Some(1));
+        assert_eq!(current([sector_word(6), sector_word(5)]), Some(0));
+        assert_eq!(current([sector_word(0xFFFF), sector_word(0)]), Some(1));
+        // cut short while programming, the marker byte is the last one
+        assert_eq!(current([sector_word(3), sector_word(4) | 0xFF00_0000]), Some(0));
+        // a record where a generation should be, e.g. an interrupted compaction
+        assert_eq!(current([ERASED, Record::Confirmed(0).to_word()]), None);
+    }
+
+    #[test]
+    fn compaction_can_be_cut_short() {
+        let history = [Record::Confirmed(0), Record::Installed(1), Record::Attempt(1), Record::Confirmed(1), Record::Installed(0)];
+        let mut old = vec![sector_word(0xFFFF)];
+        old.extend(history.iter().map(| record | record.to_word()));
+        let state = read(&history);
+
+        let expected = {
+            let mut records: Vec<Record> = state.records().collect();
+            records.push(Record::Attempt(0));
+            read(&records)
+        };
+        let words: Vec<(usize, u32)> = compacted(&state, 0, Record::Attempt(0)).collect();
+        assert_eq!(words.last(), Some(&(0, sector_word(0))));
+
+        // a reset after any number of words
+        for count in 0..=words.len() {
+            let mut new = vec![ERASED; 8];
+            for (index, word) in words[..count].iter() {
+                new[*index] = *word;
+            }
+            let loaded = load([&old, &new]);
+            if count < words.len() {
+                assert_eq!(loaded, (Some(0), state), "{}", count);
+            } else {
+                assert_eq!(loaded, (Some(1), expected));
+            }
+        }
+    }
+}
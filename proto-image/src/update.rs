@@ -0,0 +1,299 @@
+// Receiving an image into a slot, `begin`, `data` and `end` of the update protocol of proto-boot
+use crate::header::{bootable, Header, Version};
+use crate::layout::{HEADER_SIZE, SLOTS};
+
+/// Flash of the device, or memory on the host
+pub trait Flash {
+    fn erase(&mut self, slot: usize) -> Result<(), &'static str>;
+    fn program(&mut self, address: u32, bytes: &[u8]) -> Result<(), &'static str>;
+    fn read(&self, address: u32, length: u32) -> &[u8];
+}
+
+pub struct Receiver {
+    slot: Option<usize>,
+    length: u32,
+    written: u32
+}
+
+impl Receiver {
+    pub const fn new() -> Receiver {
+        Receiver { slot: None, length: 0, written: 0 }
+    }
+
+    /// Erases `slot` for an image of `length` bytes with its header
+    pub fn begin<F: Flash>(&mut self, flash: &mut F, slot: usize, length: u32) -> Result<(), &'static str> {
+        self.slot = None;
+        if length <= HEADER_SIZE || length > SLOTS[slot].size {
+            return Err("image does not fit the slot");
+        }
+
+        flash.erase(slot)?;
+        self.slot = Some(slot);
+        self.length = length;
+        self.written = 0;
+        Ok(())
+    }
+
+    /// The next bytes of the image, starting with the header
+    pub fn data<F: Flash>(&mut self, flash: &mut F, bytes: &[u8]) -> Result<(), &'static str> {
+        let slot = self.slot.ok_or("no update started")?;
+        if self.written + bytes.len() as u32 > self.length {
+            return Err("more data than announced");
+        }
+
+        if let Err(reason) = flash.program(SLOTS[slot].address + self.written, bytes) {
+            self.slot = None;
+            return Err(reason);
+        }
+        self.written += bytes.len() as u32;
+        Ok(())
+    }
+
+    /// Checks the whole image in the slot. With a `key` it has to be signed by it,
+    /// older versions than `minimum` are refused. The slot and header to note as installed.
+    /// A refused image is erased, the bootloader could otherwise still start it when nothing else is there
+    pub fn end<F: Flash>(&mut self, flash: &mut F, key: Option<&[u8; 32]>, minimum: Option<Version>) -> Result<(usize, Header), &'static str> {
+        let slot = self.slot.take().ok_or("no update started")?;
+        let result = self.check(flash, slot, key, minimum);
+        if result.is_err() {
+            // the reason matters more to the host than a failed erase
+            let _ = flash.erase(slot);
+        }
+        result.map(| header | (slot, header))
+    }
+
+    fn check<F: Flash>(&self, flash: &F, slot: usize, key: Option<&[u8; 32]>, minimum: Option<Version>) -> Result<Header, &'static str> {
+        if self.written != self.length {
+            return Err("image incomplete");
+        }
+
+        let bytes = flash.read(SLOTS[slot].address, self.length);
+        let header = bootable(bytes, &SLOTS[slot], key)?;
+        if HEADER_SIZE + header.length != self.length {
+            return Err("length does not match the header");
+        }
+        if matches!(minimum, Some(minimum) if header.version < minimum) {
+            return Err("older than the installed firmware");
+        }
+        Ok(header)
+    }
+
+    /// Bytes written and announced, while an update is running
+    pub fn progress(&self) -> Option<(u32, u32)> {
+        self.slot.map(| _ | (self.written, self.length))
+    }
+}
+
+impl Default for Receiver {
+    fn default() -> Receiver {
+        Receiver::new()
+    }
+}
+
+/// Hex digits to bytes, the number of bytes in `out`
+pub fn decode_hex(text: &str, out: &mut [u8]) -> Result<usize, &'static str> {
+    let text = text.as_bytes();
+    if text.len() & 1 != 0 || text.len() / 2 > out.len() {
+        return Err("odd number of digits or too long");
+    }
+
+    let digit = | c: u8 | (c as char).to_digit(16).map(| digit | digit as u8).ok_or("not hex");
+    for (i, pair) in text.chunks(2).enumerate() {
+        out[i] = digit(pair[0])? << 4 | digit(pair[1])?;
+    }
+    Ok(text.len() / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::verify;
+    use crate::layout::Slot;
+    use crate::state::BootState;
+
+    /// Both slots in memory
+    struct Memory {
+        bytes: Vec<u8>
+    }
+
+    impl Memory {
+        fn new() -> Memory {
+            Memory { bytes: vec![0; (SLOTS[1].address + SLOTS[1].size - SLOTS[0].address) as usize] }
+        }
+
+        fn offset(address: u32) -> usize {
+            (address - SLOTS[0].address) as usize
+        }
+    }
+
+    impl Flash for Memory {
+        fn erase(&mut self, slot: usize) -> Result<(), &'static str> {
+            let start = Memory::offset(SLOTS[slot].address);
+            self.bytes[start..start + SLOTS[slot].size as usize].iter_mut().for_each(| byte | *byte = 0xFF);
+            Ok(())
+        }
+
+        fn program(&mut self, address: u32, bytes: &[u8]) -> Result<(), &'static str> {
+            let start = Memory::offset(address);
+            assert!(self.bytes[start..start + bytes.len()].iter().all(| byte | *byte == 0xFF), "not erased");
+            self.bytes[start..start + bytes.len()].copy_from_slice(bytes);
+            Ok(())
+        }
+
+        fn read(&self, address: u32, length: u32) -> &[u8] {
+            let start = Memory::offset(address);
+            &self.bytes[start..start + length as usize]
+        }
+    }
+
+    fn image(slot: &Slot, version: Version) -> Vec<u8> {
+        let firmware: Vec<u8> = (0..1000u32).map(| i | (i * 7) as u8).collect();
+        let mut bytes = Header::new(&firmware, slot, version, false).to_bytes().to_vec();
+        bytes.extend(firmware);
+        bytes
+    }
+
+    /// The whole protocol, `data` in the chunks of tools/update.py
+    fn send(memory: &mut Memory, slot: usize, image: &[u8], key: Option<&[u8; 32]>, minimum: Option<Version>) -> Result<(usize, Header), &'static str> {
+        let mut receiver = Receiver::new();
+        receiver.begin(memory, slot, image.len() as u32)?;
+        for chunk in image.chunks(64) {
+            receiver.data(memory, chunk)?;
+        }
+        assert_eq!(receiver.progress(), Some((image.len() as u32, image.len() as u32)));
+        receiver.end(memory, key, minimum)
+    }
+
+    #[test]
+    fn receives_an_image() {
+        let mut memory = Memory::new();
+        let bytes = image(&SLOTS[1], Version::new(1, 1, 0));
+        let (slot, header) = send(&mut memory, 1, &bytes, None, Some(Version::new(1, 0, 0))).unwrap();
+        assert_eq!((slot, header.version, header.length), (1, Version::new(1, 1, 0), 1000));
+        assert_eq!(memory.read(SLOTS[1].address, bytes.len() as u32), &bytes[..]);
+        // slot a is not touched
+        assert!(memory.read(SLOTS[0].address, 16).iter().all(| byte | *byte == 0));
+    }
+
+    #[test]
+    fn wrong_slot_or_version() {
+        let mut memory = Memory::new();
+        assert_eq!(send(&mut memory, 0, &image(&SLOTS[1], Version::new(1, 0, 0)), None, None), Err("image is built for the other slot"));
+        assert_eq!(send(&mut memory, 0, &image(&SLOTS[0], Version::new(1, 0, 0)), None, Some(Version::new(1, 0, 1))), Err("older than the installed firmware"));
+        // the same version again is fine
+        assert!(send(&mut memory, 0, &image(&SLOTS[0], Version::new(1, 0, 1)), None, Some(Version::new(1, 0, 1))).is_ok());
+    }
+
+    #[test]
+    fn damaged_images() {
+        let mut memory = Memory::new();
+        let mut bytes = image(&SLOTS[0], Version::new(1, 0, 0));
+        bytes[HEADER_SIZE as usize + 10] ^= 0x80;
+        assert_eq!(send(&mut memory, 0, &bytes, None, None), Err("image damaged"));
+
+        bytes[70] ^= 0x80;
+        assert_eq!(send(&mut memory, 0, &bytes, None, None), Err("header damaged"));
+
+        // more than the header says
+        let mut bytes = image(&SLOTS[0], Version::new(1, 0, 0));
+        bytes.extend([0; 4].iter());
+        assert_eq!(send(&mut memory, 0, &bytes, None, None), Err("length does not match the header"));
+    }
+
+    #[test]
+    fn protocol_errors() {
+        let mut memory = Memory::new();
+        let mut receiver = Receiver::new();
+        assert_eq!(receiver.data(&mut memory, b"x"), Err("no update started"));
+        assert_eq!(receiver.end(&mut memory, None, None).err(), Some("no update started"));
+
+        assert_eq!(receiver.begin(&mut memory, 0, HEADER_SIZE), Err("image does not fit the slot"));
+        assert_eq!(receiver.begin(&mut memory, 0, SLOTS[0].size + 1), Err("image does not fit the slot"));
+        assert_eq!(receiver.progress(), None);
+
+        receiver.begin(&mut memory, 0, HEADER_SIZE + 4).unwrap();
+        assert_eq!(receiver.data(&mut memory, &[0xAB; HEADER_SIZE as usize + 5]), Err("more data than announced"));
+        receiver.data(&mut memory, &[0xAB; HEADER_SIZE as usize]).unwrap();
+        assert_eq!(receiver.progress(), Some((HEADER_SIZE, HEADER_SIZE + 4)));
+        assert_eq!(receiver.end(&mut memory, None, None).err(), Some("image incomplete"));
+        // end is the end, also when it failed
+        assert_eq!(receiver.data(&mut memory, b"1234"), Err("no update started"));
+    }
+
+    #[test]
+    fn hex() {
+        let mut out = [0; 4];
+        assert_eq!(decode_hex("00aBFf", &mut out), Ok(3));
+        assert_eq!(out[..3], [0x00, 0xAB, 0xFF]);
+        assert_eq!(decode_hex("", &mut out), Ok(0));
+        assert_eq!(decode_hex("abc", &mut out), Err("odd number of digits or too long"));
+        assert_eq!(decode_hex("0011223344", &mut out), Err("odd number of digits or too long"));
+        assert_eq!(decode_hex("0g", &mut out), Err("not hex"));
+    }
+
+    #[test]
+    fn refused_images_are_never_started() {
+        let mut memory = Memory::new();
+        let slot = &SLOTS[0];
+        // both have a good header and CRC, the slot would hold a startable image without the erase
+        let mut longer = image(slot, Version::new(1, 0, 0));
+        longer.extend([0; 4].iter());
+        let older = image(slot, Version::new(1, 0, 0));
+
+        for (bytes, minimum) in [(longer, None), (older, Some(Version::new(2, 0, 0)))].iter() {
+            assert!(send(&mut memory, 0, bytes, None, *minimum).is_err());
+            let valid = verify(memory.read(slot.address, slot.size), slot).is_ok();
+            assert!(!valid);
+            assert_eq!(BootState::default().decide([valid, false]), None);
+        }
+    }
+
+    #[cfg(not(feature = "ed25519"))]
+    #[test]
+    fn no_signatures_without_ed25519() {
+        let mut memory = Memory::new();
+        let bytes = image(&SLOTS[0], Version::new(1, 0, 0));
+        assert_eq!(send(&mut memory, 0, &bytes, Some(&[0; 32]), None), Err("built without ed25519"));
+    }
+
+    #[cfg(feature = "ed25519")]
+    #[test]
+    fn signatures() {
+        use crate::header::{signed_part, SIGNATURE_SIZE};
+        use ed25519_compact::{KeyPair, Seed};
+
+        let pair = KeyPair::from_seed(Seed::new([7; 32]));
+        let other = KeyPair::from_seed(Seed::new([8; 32]));
+        let sign = | pair: &KeyPair, signed: bool | {
+            let mut bytes = image(&SLOTS[0], Version::new(1, 0, 0));
+            let header = Header { signed, ..Header::parse(&bytes).unwrap() };
+            bytes[..HEADER_SIZE as usize].copy_from_slice(&header.to_bytes());
+            let signature = pair.sk.sign(signed_part(&bytes), None);
+            bytes[..SIGNATURE_SIZE].copy_from_slice(signature.as_ref());
+            bytes
+        };
+        let mut memory = Memory::new();
+
+        assert!(send(&mut memory, 0, &sign(&pair, true), Some(&pair.pk), None).is_ok());
+        assert_eq!(send(&mut memory, 0, &sign(&other, true), Some(&pair.pk), None), Err("wrong signature"));
+        assert_eq!(send(&mut memory, 0, &sign(&pair, false), Some(&pair.pk), None), Err("image not signed"));
+        assert_eq!(verify(memory.read(SLOTS[0].address, SLOTS[0].size), &SLOTS[0]).err(), Some("no image"));
+
+        // put there some other way, the bootloader with a key still does not start it
+        let unsigned = sign(&pair, false);
+        memory.erase(0).unwrap();
+        memory.program(SLOTS[0].address, &unsigned).unwrap();
+        let bytes = memory.read(SLOTS[0].address, SLOTS[0].size);
+        assert_eq!(bootable(bytes, &SLOTS[0], Some(&pair.pk)).err(), Some("image not signed"));
+        assert!(bootable(bytes, &SLOTS[0], None).is_ok());
+        // no key in the bootloader, anything goes
+        assert!(send(&mut memory, 0, &sign(&other, true), None, None).is_ok());
+
+        // signed, then changed: the CRC is fixed up, the signature can't be
+        let mut bytes = sign(&pair, true);
+        bytes[HEADER_SIZE as usize] ^= 1;
+        let header = Header::new(&bytes[HEADER_SIZE as usize..], &SLOTS[0], Version::new(1, 0, 0), true);
+        bytes[SIGNATURE_SIZE..HEADER_SIZE as usize].copy_from_slice(&header.to_bytes()[SIGNATURE_SIZE..]);
+        assert_eq!(send(&mut memory, 0, &bytes, Some(&pair.pk), None), Err("wrong signature"));
+    }
+}
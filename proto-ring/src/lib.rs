@@ -0,0 +1,274 @@
+//! The ring buffer behind the USB serial port and raw HID: filled by the main loop, drained by the USB interrupt.
+//!
+//! Everything written is lines ending with `\n` (console replies, log lines, stream records, defmt frames in hex),
+//! so when the buffer is full whole lines are dropped and the host never sees half of one.
+//! Nothing here touches hardware, so it runs on the host too.
+#![cfg_attr(not(test), no_std)]
+
+/// What gives way when the buffer is full
+#[derive(Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /// the oldest lines are dropped, the latest output is what the host sees when it starts reading
+    DropOldest,
+    /// the line that does not fit is dropped
+    DropNewest
+}
+
+pub struct TxRing<const N: usize> {
+    buffer: [u8; N],
+    start: usize,
+    length: usize,
+    overflow: Overflow,
+    dropped: u32,
+    /// the first line was sent in part, so it is not dropped
+    sending: bool,
+    /// the start of the line being written was dropped, so is the rest of it
+    discarding: bool
+}
+
+impl<const N: usize> TxRing<N> {
+    pub const fn new(overflow: Overflow) -> TxRing<N> {
+        TxRing { buffer: [0; N], start: 0, length: 0, overflow, dropped: 0, sending: false, discarding: false }
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Bytes that fit without dropping any
+    pub fn free(&self) -> usize {
+        N - self.length
+    }
+
+    /// Bytes lost to overflows so far, wraps
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Queues `data`, false if some or all of it was dropped. A line longer than the buffer can still be cut
+    pub fn push(&mut self, data: &[u8]) -> bool {
+        let mut data = data;
+        let mut complete = true;
+        if self.discarding {
+            match data.iter().position(| byte | *byte == b'\n') {
+                Some(end) => {
+                    self.drop(end + 1);
+                    self.discarding = false;
+                    complete = false;
+                    data = &data[end + 1..];
+                }
+                None => {
+                    self.drop(data.len());
+                    return false;
+                }
+            }
+        }
+
+        if data.len() > N - self.length && self.overflow == Overflow::DropOldest {
+            self.drop_lines(data.len() - (N - self.length));
+        }
+        if data.len() > N - self.length {
+            // the line is dropped as a whole, the part already queued and the rest that is still to come
+            let kept = self.last_line_start();
+            self.drop(self.length - kept + data.len());
+            self.length = kept;
+            self.discarding = data.last() != Some(&b'\n');
+            return false;
+        }
+
+        for byte in data {
+            self.buffer[(self.start + self.length) % N] = *byte;
+            self.length += 1;
+        }
+        complete
+    }
+
+    /// Oldest bytes, the part up to the end of the buffer. Empty if nothing is waiting
+    pub fn peek(&self) -> &[u8] {
+        let end = (self.start + self.length).min(N);
+        &self.buffer[self.start..end]
+    }
+
+    /// Forgets the `count` oldest bytes, after they were sent
+    pub fn consume(&mut self, count: usize) {
+        let count = count.min(self.length);
+        if count > 0 {
+            self.sending = self.at(count - 1) != b'\n';
+        }
+        self.start = (self.start + count) % N;
+        self.length -= count;
+    }
+
+    fn at(&self, index: usize) -> u8 {
+        self.buffer[(self.start + index) % N]
+    }
+
+    /// Index after the first line end at or after `from`
+    fn line_end(&self, from: usize) -> Option<usize> {
+        (from..self.length).find(| index | self.at(*index) == b'\n').map(| index | index + 1)
+    }
+
+    /// Where the unfinished line at the end starts, it can only go if none of it was sent
+    fn last_line_start(&self) -> usize {
+        match (0..self.length).rev().find(| index | self.at(*index) == b'\n') {
+            Some(index) => index + 1,
+            None if self.sending => self.length,
+            None => 0
+        }
+    }
+
+    /// Drops complete lines, oldest first, to free `needed` bytes. Drops none if that is not enough.
+    /// A line that is being sent stays
+    fn drop_lines(&mut self, needed: usize) {
+        let first = if self.sending {
+            match self.line_end(0) {
+                Some(end) => end,
+                None => return
+            }
+        } else {
+            0
+        };
+
+        let mut cut = first;
+        while cut - first < needed {
+            match self.line_end(cut) {
+                Some(end) => cut = end,
+                None => return
+            }
+        }
+
+        // the kept line moves up to where the dropped ones ended
+        let removed = cut - first;
+        for index in (0..first).rev() {
+            self.buffer[(self.start + index + removed) % N] = self.at(index);
+        }
+        self.start = (self.start + removed) % N;
+        self.length -= removed;
+        self.drop(removed);
+    }
+
+    fn drop(&mut self, count: usize) {
+        self.dropped = self.dropped.wrapping_add(count as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Everything waiting, oldest first, as the USB interrupt would send it
+    fn take<const N: usize>(ring: &mut TxRing<N>) -> Vec<u8> {
+        let mut taken = Vec::new();
+        while !ring.is_empty() {
+            let chunk = ring.peek().to_vec();
+            ring.consume(chunk.len());
+            taken.extend(chunk);
+        }
+        taken
+    }
+
+    #[test]
+    fn peek_stops_at_the_wrap_point() {
+        let mut ring = TxRing::<8>::new(Overflow::DropOldest);
+        assert!(ring.push(b"abcdef"));
+        ring.consume(4);
+        assert!(ring.push(b"ghijk"));
+        assert_eq!(ring.len(), 7);
+        assert_eq!(ring.free(), 1);
+        assert_eq!(ring.peek(), b"efgh");
+        ring.consume(3);
+        assert_eq!(ring.peek(), b"h");
+        ring.consume(1);
+        assert_eq!(ring.peek(), b"ijk");
+        assert_eq!(ring.dropped(), 0);
+    }
+
+    #[test]
+    fn consume_in_small_steps() {
+        let mut ring = TxRing::<4>::new(Overflow::DropOldest);
+        let mut sent = Vec::new();
+        for chunk in [&b"ab"[..], b"cde", b"f", b"ghi"].iter() {
+            ring.push(chunk);
+            while !ring.is_empty() {
+                sent.push(ring.peek()[0]);
+                ring.consume(1);
+            }
+        }
+        assert_eq!(sent, b"abcdefghi");
+        assert_eq!(ring.peek(), b"");
+    }
+
+    #[test]
+    fn drop_oldest_drops_whole_lines() {
+        let mut ring = TxRing::<12>::new(Overflow::DropOldest);
+        ring.push(b"ab\n");
+        ring.push(b"cde\n");
+        ring.push(b"f\n");
+        assert!(ring.push(b"ghij\n"));
+        assert_eq!(ring.dropped(), 3);
+        assert_eq!(take(&mut ring), b"cde\nf\nghij\n");
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_line_being_sent() {
+        let mut ring = TxRing::<12>::new(Overflow::DropOldest);
+        ring.push(b"abcd\n");
+        ring.push(b"ef\n");
+        ring.consume(2);
+        assert!(ring.push(b"ghijklm\n"));
+        assert_eq!(ring.dropped(), 3);
+        assert_eq!(take(&mut ring), b"cd\nghijklm\n");
+    }
+
+    #[test]
+    fn a_line_written_in_parts_is_dropped_as_a_whole() {
+        let mut ring = TxRing::<8>::new(Overflow::DropOldest);
+        ring.push(b"ab\n");
+        ring.consume(3);
+        ring.push(b"cd\n");
+        ring.push(b"ef");
+        // does not fit even without "cd\n", so "ef" goes, the rest of the line too
+        assert!(!ring.push(b"ghijklm"));
+        assert!(!ring.push(b"no\n"));
+        assert!(ring.push(b"pq\n"));
+        assert_eq!(ring.dropped(), 2 + 7 + 3);
+        assert_eq!(take(&mut ring), b"cd\npq\n");
+    }
+
+    #[test]
+    fn drop_newest_drops_whole_lines() {
+        let mut ring = TxRing::<8>::new(Overflow::DropNewest);
+        ring.push(b"abcde\n");
+        ring.push(b"fg");
+        assert!(!ring.push(b"hi\n"));
+        assert_eq!(ring.dropped(), 5);
+        assert!(ring.push(b"j\n"));
+        assert!(!ring.push(b"0123456789\n"));
+        assert_eq!(ring.dropped(), 5 + 11);
+        assert_eq!(take(&mut ring), b"abcde\nj\n");
+    }
+
+    #[test]
+    fn a_line_longer_than_the_buffer_is_dropped() {
+        let mut ring = TxRing::<8>::new(Overflow::DropOldest);
+        ring.push(b"ab\n");
+        assert!(!ring.push(b"0123456789\n"));
+        assert_eq!(ring.dropped(), 11);
+        assert_eq!(take(&mut ring), b"ab\n");
+    }
+
+    #[test]
+    fn consume_past_the_end_empties() {
+        let mut ring = TxRing::<8>::new(Overflow::DropOldest);
+        ring.push(b"abc");
+        ring.consume(10);
+        assert_eq!(ring.len(), 0);
+        assert_eq!(ring.peek(), b"");
+        ring.push(b"d");
+        assert_eq!(take(&mut ring), b"d");
+    }
+}
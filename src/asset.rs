@@ -0,0 +1,168 @@
+// Image format for assets in flash, written by build.rs which also uses this decoder to check its output
+
+/// How `Asset::data` is encoded
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    /// 1 bit per pixel, MSB first. Set pixels are drawn in a tint color, the rest is transparent
+    Mask,
+    /// Palette indices with 1, 2, 4 or 8 bits, MSB first
+    Palette(u8),
+    /// Pairs of run length - 1 and palette index
+    RlePalette,
+    /// Whole QOI file, for images with too many colors for a palette, decoded with `Qoi` like `image::draw_qoi`
+    Qoi
+}
+
+/// Bytes before the first QOI chunk: magic, width, height, channels and colorspace
+pub const QOI_HEADER: usize = 14;
+
+/// QOI chunk decoder shared by assets and `image::draw_qoi`, see https://qoiformat.org/qoi-specification.pdf
+#[derive(Clone, Copy)]
+pub struct Qoi {
+    index: [[u8; 4]; 64],
+    pixel: [u8; 4],
+    run: u8
+}
+
+impl Qoi {
+    pub const fn new() -> Qoi {
+        Qoi { index: [[0; 4]; 64], pixel: [0, 0, 0, 255], run: 0 }
+    }
+
+    /// The next RGBA pixel, `byte` supplies the encoded data
+    pub fn next<E, F: FnMut() -> Result<u8, E>>(&mut self, mut byte: F) -> Result<[u8; 4], E> {
+        if self.run > 0 {
+            self.run -= 1;
+            return Ok(self.pixel);
+        }
+
+        let pixel = &mut self.pixel;
+        let op = byte()?;
+        match op {
+            0xFE => {
+                pixel[0] = byte()?;
+                pixel[1] = byte()?;
+                pixel[2] = byte()?;
+            },
+            0xFF => {
+                for channel in pixel.iter_mut() {
+                    *channel = byte()?;
+                }
+            },
+            _ => match op >> 6 {
+                0b00 => *pixel = self.index[op as usize & 0x3F],
+                0b01 => {
+                    pixel[0] = pixel[0].wrapping_add((op >> 4) & 0x03).wrapping_sub(2);
+                    pixel[1] = pixel[1].wrapping_add((op >> 2) & 0x03).wrapping_sub(2);
+                    pixel[2] = pixel[2].wrapping_add(op & 0x03).wrapping_sub(2);
+                },
+                0b10 => {
+                    let dg = (op & 0x3F).wrapping_sub(32);
+                    let next = byte()?;
+                    pixel[0] = pixel[0].wrapping_add(dg).wrapping_add(next >> 4).wrapping_sub(8);
+                    pixel[1] = pixel[1].wrapping_add(dg);
+                    pixel[2] = pixel[2].wrapping_add(dg).wrapping_add(next & 0x0F).wrapping_sub(8);
+                },
+                _ => self.run = op & 0x3F
+            }
+        }
+
+        let hash = (pixel[0] as usize * 3 + pixel[1] as usize * 5 + pixel[2] as usize * 7 + pixel[3] as usize * 11) % 64;
+        self.index[hash] = *pixel;
+        Ok(*pixel)
+    }
+}
+
+/// RGB888 to RGB565, same as `image::rgb`
+pub fn rgb565(r: u8, g: u8, b: u8) -> u16 {
+    (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3
+}
+
+/// Image compiled into the firmware, colors are RGB565
+#[derive(Clone, Copy)]
+pub struct Asset<'a> {
+    pub width: u16,
+    pub height: u16,
+    pub encoding: Encoding,
+    pub palette: &'a [u16],
+    pub data: &'a [u8]
+}
+
+impl<'a> Asset<'a> {
+    /// Pixels row by row, `None` for transparent ones
+    pub fn pixels(&self, tint: u16) -> Pixels<'a> {
+        Pixels {
+            asset: *self,
+            tint,
+            index: 0,
+            offset: if self.encoding == Encoding::Qoi { QOI_HEADER } else { 0 },
+            run: 0,
+            color: 0,
+            qoi: Qoi::new()
+        }
+    }
+}
+
+pub struct Pixels<'a> {
+    asset: Asset<'a>,
+    tint: u16,
+    /// next pixel
+    index: usize,
+    /// next RLE pair or QOI chunk byte
+    offset: usize,
+    /// pixels left in the current run
+    run: u16,
+    color: u16,
+    qoi: Qoi
+}
+
+impl<'a> Iterator for Pixels<'a> {
+    type Item = Option<u16>;
+
+    /// Ends early on broken data instead of panicking
+    fn next(&mut self) -> Option<Option<u16>> {
+        let asset = &self.asset;
+        if self.index >= asset.width as usize * asset.height as usize {
+            return None;
+        }
+
+        let pixel = match asset.encoding {
+            Encoding::Mask => {
+                let byte = *asset.data.get(self.index / 8)?;
+                if byte >> (7 - self.index % 8) & 1 == 1 {
+                    Some(self.tint)
+                } else {
+                    None
+                }
+            },
+            Encoding::Palette(bits) => {
+                let bit = self.index * bits as usize;
+                let byte = *asset.data.get(bit / 8)? as usize;
+                let shift = 8 - bits as usize - bit % 8;
+                let color = (byte >> shift) & ((1 << bits) - 1);
+                Some(*asset.palette.get(color)?)
+            },
+            Encoding::RlePalette => {
+                if self.run == 0 {
+                    let pair = asset.data.get(self.offset..self.offset + 2)?;
+                    self.run = pair[0] as u16 + 1;
+                    self.color = *asset.palette.get(pair[1] as usize)?;
+                    self.offset += 2;
+                }
+                self.run -= 1;
+                Some(self.color)
+            },
+            Encoding::Qoi => {
+                let (data, offset) = (asset.data, &mut self.offset);
+                let [r, g, b, _] = self.qoi.next(|| {
+                    *offset += 1;
+                    data.get(*offset - 1).copied().ok_or(())
+                }).ok()?;
+                Some(rgb565(r, g, b))
+            }
+        };
+
+        self.index += 1;
+        Some(pixel)
+    }
+}
@@ -0,0 +1,60 @@
+// images from assets/, converted by build.rs
+#![allow(dead_code)]
+
+use crate::asset::{Asset, Encoding};
+
+include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::path::Path;
+
+    use super::*;
+    use crate::asset::rgb565;
+
+    const TINT: u16 = 0xFFFF;
+
+    /// RGBA pixels of the PNG, read like build.rs does
+    fn load(path: &str) -> Vec<[u8; 4]> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(path);
+        let mut decoder = png::Decoder::new(File::open(&path).unwrap());
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let (info, mut reader) = decoder.read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut buf).unwrap();
+
+        let (color_type, _) = reader.output_color_type();
+        let channels = color_type.samples();
+        buf[..(info.width * info.height) as usize * channels]
+            .chunks_exact(channels)
+            .map(| pixel | match color_type {
+                png::ColorType::Grayscale => [pixel[0], pixel[0], pixel[0], 255],
+                png::ColorType::GrayscaleAlpha => [pixel[0], pixel[0], pixel[0], pixel[1]],
+                png::ColorType::RGB => [pixel[0], pixel[1], pixel[2], 255],
+                png::ColorType::RGBA => [pixel[0], pixel[1], pixel[2], pixel[3]],
+                png::ColorType::Indexed => panic!("{}: palette was not expanded", path.display())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn assets_match_their_pngs() {
+        assert!(!SOURCES.is_empty());
+
+        for (path, asset) in SOURCES {
+            let pixels = load(path);
+            let decoded: Vec<Option<u16>> = asset.pixels(TINT).collect();
+            assert_eq!(decoded.len(), pixels.len(), "{}: decoded size differs", path);
+
+            for (i, (pixel, decoded)) in pixels.iter().zip(decoded).enumerate() {
+                let expected = if asset.encoding == Encoding::Mask {
+                    Some(TINT).filter(| _ | pixel[3] >= 128)
+                } else {
+                    Some(rgb565(pixel[0], pixel[1], pixel[2]))
+                };
+                assert_eq!(decoded, expected, "{}: pixel {}, {} differs", path, i % asset.width as usize, i / asset.width as usize);
+            }
+        }
+    }
+}
@@ -0,0 +1,41 @@
+// restarting into the ROM bootloader of the STM32 (USB DFU) without pressing BOOT0.
+// The settings sector is not part of the firmware, see memory.x.in, an update that only writes the image keeps it
+use cortex_m::peripheral::SCB;
+use stm32f4xx_hal::stm32;
+
+/// System memory, the vector table of the ROM bootloader
+const SYSTEM_MEMORY: u32 = 0x1FFF_0000;
+const MAGIC: u32 = 0xB007_10AD;
+
+/// Kept over the reset like the panic message, see fault.rs
+#[link_section = ".uninit.BOOTLOADER"]
+static mut REQUEST: u32 = 0;
+
+/// Resets, `check` then jumps to the bootloader with the chip in reset state
+pub fn enter() -> ! {
+    unsafe { core::ptr::write_volatile(&mut REQUEST, MAGIC) };
+    SCB::sys_reset()
+}
+
+/// Has to run first in `main`, before any clock or peripheral is set up
+pub fn check() {
+    unsafe {
+        if core::ptr::read_volatile(&REQUEST) != MAGIC {
+            return;
+        }
+        // only once, the next reset starts the firmware again
+        core::ptr::write_volatile(&mut REQUEST, 0);
+
+        // system memory at 0, the bootloader expects to run from there
+        let rcc = &*stm32::RCC::ptr();
+        rcc.apb2enr.modify(| _, w | w.syscfgen().set_bit());
+        let syscfg = &*stm32::SYSCFG::ptr();
+        syscfg.memrm.modify(| _, w | w.mem_mode().bits(0b01));
+
+        let stack = core::ptr::read_volatile(SYSTEM_MEMORY as *const u32);
+        let reset = core::ptr::read_volatile((SYSTEM_MEMORY + 4) as *const u32);
+        cortex_m::register::msp::write(stack);
+        let reset: extern "C" fn() -> ! = core::mem::transmute(reset as usize);
+        reset();
+    }
+}
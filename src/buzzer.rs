@@ -0,0 +1,259 @@
+use embedded_hal::PwmPin;
+use stm32f4xx_hal::stm32;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum Pitch {
+    C, Cs, D, Ds, E, F, Fs, G, Gs, A, As, B,
+    Rest
+}
+
+// octave 8 in Hz, lower octaves are shifted down from here
+const OCTAVE_8: [u32; 12] = [4186, 4435, 4699, 4978, 5274, 5588, 5920, 6272, 6645, 7040, 7459, 7902];
+
+#[derive(Clone, Copy)]
+pub struct Note {
+    pub pitch: Pitch,
+    pub octave: u8,
+    /// milliseconds
+    pub duration: u16
+}
+
+impl Note {
+    pub const fn new(pitch: Pitch, octave: u8, duration: u16) -> Note {
+        Note { pitch, octave, duration }
+    }
+
+    pub fn frequency(&self) -> Option<u32> {
+        match self.pitch {
+            Pitch::Rest => None,
+            pitch => Some(OCTAVE_8[pitch as usize] >> (8 - self.octave.min(8)))
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum Sound {
+    KeyClick,
+    LayerChange,
+    Error,
+    Melody(&'static [Note])
+}
+
+static KEY_CLICK: [Note; 1] = [Note::new(Pitch::C, 7, 5)];
+
+static LAYER_CHANGE: [Note; 2] = [
+    Note::new(Pitch::E, 6, 40),
+    Note::new(Pitch::A, 6, 60)
+];
+
+static ERROR: [Note; 3] = [
+    Note::new(Pitch::A, 4, 120),
+    Note::new(Pitch::Rest, 4, 40),
+    Note::new(Pitch::F, 4, 240)
+];
+
+impl Sound {
+    pub fn notes(&self) -> &'static [Note] {
+        match self {
+            Sound::KeyClick => &KEY_CLICK,
+            Sound::LayerChange => &LAYER_CHANGE,
+            Sound::Error => &ERROR,
+            Sound::Melody(notes) => notes
+        }
+    }
+}
+
+/// Prescaler and auto-reload of a timer running at `timer_clock` for `frequency`,
+/// as close as the 16 bit registers get. At least 2 ticks per period, so there is a low and a high part
+pub fn timing(timer_clock: u32, frequency: u32) -> (u16, u16) {
+    let ticks = (timer_clock / frequency.max(1)).max(2);
+    let psc = ((ticks - 1) >> 16).min(0xFFFF);
+    let arr = (ticks / (psc + 1)).max(2) - 1;
+    (psc as u16, arr.min(0xFFFF) as u16)
+}
+
+/// `duty` of a channel for a period of `old_arr` ticks, moved to a period of `new_arr` ticks
+pub fn rescale(duty: u16, old_arr: u16, new_arr: u16) -> u16 {
+    let duty = duty as u32 * (new_arr as u32 + 1) / (old_arr as u32 + 1);
+    duty.min(new_arr as u32 + 1) as u16
+}
+
+/// Piezo on the second TIM1 channel.
+/// The pitch is set through the TIM1 frequency, which the vibration motor on the first channel shares.
+pub struct Buzzer<C>
+where
+    C: PwmPin<Duty=u16>
+{
+    speaker: C,
+    timer_clock: u32,
+    idle_frequency: u32,
+    notes: &'static [Note],
+    index: usize,
+    note_start: u32,
+    playing: bool,
+    volume: u8,
+    muted: bool
+}
+
+impl<C> Buzzer<C>
+where
+    C: PwmPin<Duty=u16>
+{
+    /// `timer_clock` is the TIM1 input clock, `idle_frequency` the PWM frequency to go back to when silent
+    pub fn new(mut speaker: C, timer_clock: u32, idle_frequency: u32) -> Buzzer<C> {
+        speaker.set_duty(0);
+        speaker.enable();
+
+        Buzzer {
+            speaker,
+            timer_clock,
+            idle_frequency,
+            notes: &[],
+            index: 0,
+            note_start: 0,
+            playing: false,
+            volume: 100,
+            muted: false
+        }
+    }
+
+    pub fn set_volume(&mut self, volume: u8) {
+        self.volume = volume.min(100);
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+        if muted {
+            self.stop();
+        }
+    }
+
+    /// Starts `sound`, replacing whatever is currently playing
+    pub fn play(&mut self, sound: Sound, now: u32) {
+        if self.muted || self.volume == 0 || sound.notes().is_empty() {
+            return;
+        }
+
+        self.notes = sound.notes();
+        self.index = 0;
+        self.note_start = now;
+        self.playing = true;
+        self.start_note();
+    }
+
+    pub fn stop(&mut self) {
+        self.playing = false;
+        self.speaker.set_duty(0);
+        self.set_frequency(self.idle_frequency);
+    }
+
+    /// Advances the melody, runs every millisecond from the TIM4 tick interrupt
+    pub fn update(&mut self, now: u32) {
+        if !self.playing {
+            return;
+        }
+
+        let duration = self.notes[self.index].duration as u32;
+        if now.wrapping_sub(self.note_start) < duration {
+            return;
+        }
+
+        self.note_start = self.note_start.wrapping_add(duration);
+        self.index += 1;
+
+        if self.index < self.notes.len() {
+            self.start_note();
+        } else {
+            self.stop();
+        }
+    }
+
+    fn start_note(&mut self) {
+        match self.notes.get(self.index).and_then(| note | note.frequency()) {
+            Some(frequency) => {
+                self.set_frequency(frequency);
+                // 50% is the loudest a piezo gets
+                let max_duty = self.speaker.get_max_duty() as u32;
+                self.speaker.set_duty((max_duty / 2 * self.volume as u32 / 100) as u16);
+            },
+            None => self.speaker.set_duty(0)
+        }
+    }
+
+    /// Only with interrupts off or from the TIM4 interrupt, the motor duty is changed too
+    fn set_frequency(&mut self, frequency: u32) {
+        // the hal has no way to change the frequency after setup
+        let tim = unsafe { &*stm32::TIM1::ptr() };
+
+        let (psc, arr) = timing(self.timer_clock, frequency);
+        let old_arr = tim.arr.read().arr().bits();
+        // the motor keeps its duty in percent of the new period, otherwise it could be over it: full strength
+        let motor = rescale(tim.ccr1.read().ccr().bits(), old_arr, arr);
+
+        tim.psc.write(| w | w.psc().bits(psc));
+        tim.arr.write(| w | unsafe { w.arr().bits(arr) });
+        tim.ccr1.write(| w | unsafe { w.ccr().bits(motor) });
+        // load all of them at once, right away
+        tim.egr.write(| w | w.ug().set_bit());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// TIM1 on APB2 at 96 MHz
+    const CLOCK: u32 = 96_000_000;
+
+    fn frequency(timer_clock: u32, (psc, arr): (u16, u16)) -> u32 {
+        timer_clock / ((psc as u32 + 1) * (arr as u32 + 1))
+    }
+
+    #[test]
+    fn notes() {
+        assert_eq!(Note::new(Pitch::A, 8, 1).frequency(), Some(7040));
+        assert_eq!(Note::new(Pitch::A, 4, 1).frequency(), Some(440));
+        assert_eq!(Note::new(Pitch::C, 0, 1).frequency(), Some(16));
+        // octaves above 8 stay at 8
+        assert_eq!(Note::new(Pitch::C, 12, 1).frequency(), Some(4186));
+        assert_eq!(Note::new(Pitch::Rest, 4, 1).frequency(), None);
+    }
+
+    #[test]
+    fn timer_settings_for_notes() {
+        for octave in 0..=8 {
+            for pitch in [Pitch::C, Pitch::Fs, Pitch::B].iter() {
+                let wanted = Note::new(*pitch, octave, 1).frequency().unwrap();
+                let timing = timing(CLOCK, wanted);
+                let actual = frequency(CLOCK, timing);
+                // within 0.1%
+                assert!((actual as i64 - wanted as i64).abs() * 1000 <= wanted as i64, "{} {:?} {}", wanted, timing, actual);
+            }
+        }
+        assert_eq!(timing(CLOCK, 500), (2, 63999));
+        assert_eq!(timing(CLOCK, 440), (3, 54544));
+    }
+
+    #[test]
+    fn extreme_frequencies() {
+        // 0 Hz and below the slowest the registers can do
+        assert_eq!(timing(CLOCK, 0), timing(CLOCK, 1));
+        assert!(frequency(CLOCK, timing(CLOCK, 1)) <= 2);
+        // at or above the timer clock there still is a period of 2 ticks
+        assert_eq!(timing(CLOCK, CLOCK), (0, 1));
+        assert_eq!(timing(CLOCK, u32::MAX), (0, 1));
+        assert_eq!(timing(1, 1), (0, 1));
+    }
+
+    #[test]
+    fn motor_duty_follows_the_period() {
+        // half of the idle period stays half of the note's period
+        assert_eq!(rescale(32000, 63999, 54544), 27272);
+        assert_eq!(rescale(0, 63999, 1), 0);
+        // full strength stays full, never over the new period
+        assert_eq!(rescale(64000, 63999, 999), 1000);
+        assert_eq!(rescale(u16::MAX, 0, 999), 1000);
+    }
+}
@@ -0,0 +1,14 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Milliseconds since boot, advanced by the 1kHz TIM4 interrupt
+static MILLIS: AtomicU32 = AtomicU32::new(0);
+
+/// Milliseconds since boot, wraps after ~49 days
+pub fn now() -> u32 {
+    MILLIS.load(Ordering::Relaxed)
+}
+
+/// Advances the clock by one millisecond, only to be called from the tick interrupt
+pub fn tick() -> u32 {
+    MILLIS.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
+}
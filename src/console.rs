@@ -0,0 +1,682 @@
+// text console on the USB serial: line editing, history, tab completion and a table of commands.
+// Every line is answered with OK or ERR <reason>, output of the command comes before that
+use core::fmt::Write;
+
+use heapless::{String, Vec};
+
+use crate::haptic::{Effect, Envelope, Segment};
+use crate::matrix::KeyState;
+use crate::overlay::{Content, Item, Overlay};
+use crate::profile::{PROFILES, PROFILE_COUNT};
+use crate::slots;
+use crate::text;
+
+pub const LINE_LENGTH: usize = 192;
+const HISTORY: usize = 4;
+const MAX_TABLES: usize = 8;
+/// ms between redraws of the live key view
+const LIVE_INTERVAL: u32 = 100;
+
+pub type Output<'a> = &'a mut dyn Write;
+
+pub struct Command {
+    pub name: &'static str,
+    /// arguments and what it does, for `help`
+    pub help: &'static str,
+    pub run: fn(arguments: &str, context: &mut Context, out: Output) -> Result<(), &'static str>
+}
+
+/// Things the main loop does after the line, commands can not reach them directly
+pub enum Request {
+    Vibrate(Envelope),
+    Layer(usize),
+    Profile(usize),
+    Reset,
+    Bootloader,
+    Update
+}
+
+/// What commands can look at and change
+pub struct Context<'a> {
+    pub now: u32,
+    pub overlay: &'a mut Overlay,
+    pub keys: &'a [[KeyState; 4]; 4],
+    pub encoders: [u32; 2],
+    pub profile: usize,
+    pub layer: usize,
+    pub requests: Vec<Request, 4>,
+    /// keeps showing the keys until the next input
+    pub live_keys: bool
+}
+
+impl<'a> Context<'a> {
+    fn request(&mut self, request: Request) -> Result<(), &'static str> {
+        self.requests.push(request).map_err(| _ | "busy")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Escape {
+    None,
+    /// ESC received
+    Started,
+    /// ESC [ received
+    Sequence
+}
+
+pub struct Console {
+    line: Vec<u8, LINE_LENGTH>,
+    too_long: bool,
+    /// oldest first
+    history: Vec<String<LINE_LENGTH>, HISTORY>,
+    /// how far back the shown line is in `history`
+    browsing: Option<usize>,
+    escape: Escape,
+    last_was_cr: bool,
+    /// off for programs: no echo, no prompt, no editing output
+    echo: bool,
+    live_keys: bool,
+    last_live: Option<u32>,
+    tables: Vec<&'static [Command], MAX_TABLES>
+}
+
+const BUILT_IN: [&str; 2] = ["help", "echo"];
+
+impl Console {
+    pub fn new() -> Console {
+        Console {
+            line: Vec::new(),
+            too_long: false,
+            history: Vec::new(),
+            browsing: None,
+            escape: Escape::None,
+            last_was_cr: false,
+            echo: true,
+            live_keys: false,
+            last_live: None,
+            tables: Vec::new()
+        }
+    }
+
+    /// Starts without echo and prompt, for interfaces only programs talk to
+    pub fn set_echo(&mut self, on: bool) {
+        self.echo = on;
+    }
+
+    /// Adds the commands of a module, fails when there are too many tables
+    pub fn register(&mut self, commands: &'static [Command]) -> Result<(), ()> {
+        self.tables.push(commands).map_err(| _ | ())
+    }
+
+    fn commands(&self) -> impl Iterator<Item = &'static Command> + '_ {
+        self.tables.iter().flat_map(| table | table.iter())
+    }
+
+    /// Handles one received byte, runs the line when it is complete
+    pub fn input(&mut self, byte: u8, context: &mut Context, out: Output) {
+        // \r\n is one line end
+        if byte == b'\n' && self.last_was_cr {
+            self.last_was_cr = false;
+            return;
+        }
+        self.last_was_cr = byte == b'\r';
+
+        if self.live_keys {
+            self.live_keys = false;
+            if self.last_live.take().is_some() {
+                // below the view again
+                let _ = out.write_str("\x1b[4B");
+            }
+            self.prompt(out);
+            return;
+        }
+
+        match (self.escape, byte) {
+            (_, 0x1B) => self.escape = Escape::Started,
+            (Escape::Started, b'[') => self.escape = Escape::Sequence,
+            (Escape::Sequence, b'A') => {
+                self.escape = Escape::None;
+                self.browse(true, out);
+            },
+            (Escape::Sequence, b'B') => {
+                self.escape = Escape::None;
+                self.browse(false, out);
+            },
+            (Escape::Sequence, b'0'..=b'9') | (Escape::Sequence, b';') => (),
+            (Escape::Started, _) | (Escape::Sequence, _) => self.escape = Escape::None,
+
+            (_, b'\r') | (_, b'\n') => self.finish_line(context, out),
+            (_, 0x08) | (_, 0x7F) => self.backspace(out),
+            (_, b'\t') => self.complete(out),
+            // ctrl-c drops the line
+            (_, 0x03) => {
+                self.line.clear();
+                self.too_long = false;
+                if self.echo {
+                    let _ = write!(out, "^C\r\n");
+                }
+                self.prompt(out);
+            },
+            (_, byte) if byte >= 0x20 => self.insert(byte, out),
+            _ => ()
+        }
+    }
+
+    /// Redraws the live key view when it is on
+    pub fn update(&mut self, keys: &[[KeyState; 4]; 4], now: u32, out: Output) {
+        if !self.live_keys || self.last_live.map_or(false, | last | now.wrapping_sub(last) < LIVE_INTERVAL) {
+            return;
+        }
+        self.last_live = Some(now);
+
+        for row in keys {
+            for state in row {
+                let _ = out.write_str(match state {
+                    KeyState::Pressing | KeyState::Pressed => " #",
+                    KeyState::Releasing | KeyState::Released => " ."
+                });
+            }
+            let _ = out.write_str("\r\n");
+        }
+        // back up, the next redraw goes over it
+        let _ = out.write_str("\x1b[4A");
+    }
+
+    fn prompt(&self, out: Output) {
+        if self.echo {
+            let _ = out.write_str("> ");
+        }
+    }
+
+    fn insert(&mut self, byte: u8, out: Output) {
+        if self.line.push(byte).is_err() {
+            self.too_long = true;
+            return;
+        }
+        if !self.echo {
+            return;
+        }
+
+        // echo once a character is complete
+        let start = self.line.iter().rposition(| byte | byte & 0xC0 != 0x80).unwrap_or(0);
+        if let Ok(c) = core::str::from_utf8(&self.line[start..]) {
+            let _ = out.write_str(c);
+        }
+    }
+
+    fn backspace(&mut self, out: Output) {
+        // a whole UTF-8 character
+        while let Some(byte) = self.line.pop() {
+            if byte & 0xC0 != 0x80 {
+                if self.echo {
+                    let _ = out.write_str("\x08 \x08");
+                }
+                break;
+            }
+        }
+    }
+
+    fn finish_line(&mut self, context: &mut Context, out: Output) {
+        if self.echo {
+            let _ = out.write_str("\r\n");
+        }
+
+        let line: String<LINE_LENGTH> = text::lossy(&self.line);
+        let too_long = core::mem::replace(&mut self.too_long, false);
+        self.line.clear();
+        self.browsing = None;
+
+        if !line.trim().is_empty() && self.history.last().map_or(true, | last | last.as_str() != line.as_str()) {
+            if self.history.is_full() {
+                self.history = self.history.iter().skip(1).cloned().collect();
+            }
+            let _ = self.history.push(line.clone());
+        }
+
+        let result = if too_long {
+            Err("line too long")
+        } else {
+            context.live_keys = false;
+            let result = self.run(&line, context, out);
+            self.live_keys = context.live_keys;
+            result
+        };
+
+        let _ = match result {
+            Ok(()) => write!(out, "OK\r\n"),
+            Err(reason) => write!(out, "ERR {}\r\n", reason)
+        };
+        if !self.live_keys {
+            self.prompt(out);
+        }
+    }
+
+    /// Runs one line without any editing
+    pub fn run(&mut self, line: &str, context: &mut Context, out: Output) -> Result<(), &'static str> {
+        let (name, arguments) = split_first(line.trim());
+
+        match name {
+            "" => Ok(()),
+            "help" => {
+                let _ = write!(out, "help\r\n  this list\r\necho on|off\r\n  echo and prompt, off for programs\r\n");
+                for command in self.commands() {
+                    let _ = write!(out, "{}\r\n  {}\r\n", command.name, command.help);
+                }
+                Ok(())
+            },
+            "echo" => {
+                self.echo = on_off(arguments)?;
+                Ok(())
+            },
+            _ => {
+                let command = self.commands().find(| command | command.name == name).ok_or("unknown command")?;
+                (command.run)(arguments, context, out)
+            }
+        }
+    }
+
+    /// Older with `back`, newer otherwise, past the newest is an empty line
+    fn browse(&mut self, back: bool, out: Output) {
+        if !self.echo || self.history.is_empty() {
+            return;
+        }
+
+        let last = self.history.len() - 1;
+        self.browsing = match (self.browsing, back) {
+            (None, true) => Some(0),
+            (Some(steps), true) => Some((steps + 1).min(last)),
+            (Some(0), false) | (None, false) => None,
+            (Some(steps), false) => Some(steps - 1)
+        };
+
+        self.line.clear();
+        if let Some(steps) = self.browsing {
+            let _ = self.line.extend_from_slice(self.history[last - steps].as_bytes());
+        }
+        let _ = write!(out, "\r\x1b[K> {}", core::str::from_utf8(&self.line).unwrap_or(""));
+    }
+
+    /// Completes the command name, shows the choices when there is more than one
+    fn complete(&mut self, out: Output) {
+        if !self.echo || self.line.contains(&b' ') {
+            return;
+        }
+        let typed: String<LINE_LENGTH> = text::lossy(&self.line);
+
+        let names = || BUILT_IN.iter().copied()
+            .chain(self.commands().map(| command | command.name))
+            .filter(| name | name.starts_with(typed.as_str()));
+
+        let mut common: Option<&str> = None;
+        let mut count = 0;
+        for name in names() {
+            count += 1;
+            common = Some(match common {
+                None => name,
+                Some(common) => &common[..common_prefix(common, name)]
+            });
+        }
+
+        let common = match common {
+            Some(common) => common,
+            None => return
+        };
+
+        let mut added: String<LINE_LENGTH> = String::new();
+        let _ = added.push_str(&common[typed.len()..]);
+        if count == 1 {
+            let _ = added.push(' ');
+        }
+
+        if added.is_empty() {
+            let _ = out.write_str("\r\n");
+            for name in names() {
+                let _ = write!(out, "{}  ", name);
+            }
+            let _ = write!(out, "\r\n> {}", typed);
+        } else {
+            let _ = self.line.extend_from_slice(added.as_bytes());
+            let _ = out.write_str(&added);
+        }
+    }
+}
+
+/// Length of the part both start with, on a character boundary
+fn common_prefix(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .find(| ((_, a), b) | a != b)
+        .map_or(a.len().min(b.len()), | ((i, _), _) | i)
+}
+
+/// First word and the rest, both trimmed
+pub fn split_first(line: &str) -> (&str, &str) {
+    match line.find(' ') {
+        Some(space) => (&line[..space], line[space + 1..].trim()),
+        None => (line, "")
+    }
+}
+
+/// Words split at spaces, "quoted text" is one word without the quotes
+pub fn words(arguments: &str) -> Words {
+    Words { rest: arguments }
+}
+
+pub struct Words<'a> {
+    rest: &'a str
+}
+
+impl<'a> Iterator for Words<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() {
+            self.rest = rest;
+            return None;
+        }
+
+        let (word, after) = match rest.strip_prefix('"') {
+            // a missing closing quote takes the rest of the line
+            Some(quoted) => match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, "")
+            },
+            None => match rest.find(' ') {
+                Some(end) => (&rest[..end], &rest[end..]),
+                None => (rest, "")
+            }
+        };
+        self.rest = after;
+        Some(word)
+    }
+}
+
+pub fn on_off(word: &str) -> Result<bool, &'static str> {
+    match word {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err("expected on or off")
+    }
+}
+
+/// Segments of an effect by name, or `duty:ms,duty:ms,...` for up to `MAX_SEGMENTS` segments
+pub fn parse_effect(pattern: &str) -> Result<Envelope, &'static str> {
+    let effect = match pattern {
+        "tick" => Effect::Tick,
+        "click" => Effect::Click,
+        "double" => Effect::DoubleClick,
+        "buzz" => Effect::Buzz,
+        "up" => Effect::RampUp,
+        "down" => Effect::RampDown,
+        "heartbeat" => Effect::Heartbeat,
+        custom => {
+            let mut segments = Envelope::new();
+            for segment in custom.split(',') {
+                let colon = segment.find(':').ok_or("unknown pattern")?;
+                let duty: u8 = segment[..colon].parse().map_err(| _ | "bad duty")?;
+                let duration = segment[colon + 1..].parse().map_err(| _ | "bad duration")?;
+                segments.push(Segment::new(duty.min(100), duration)).map_err(| _ | "too many segments")?;
+            }
+            return Ok(segments);
+        }
+    };
+    Envelope::from_slice(effect.segments()).map_err(| _ | "too many segments")
+}
+
+/// Overlay id of `display text`
+const DISPLAY_TEXT_ID: u8 = 0xFF;
+
+pub const COMMANDS: &[Command] = &[
+    Command { name: "keys", help: "live view of the key matrix, any key stops it", run: keys },
+    Command { name: "enc", help: "encoder counts", run: enc },
+    Command { name: "vib", help: "<tick|click|double|buzz|up|down|heartbeat|duty:ms,...> plays a pattern", run: vib },
+    Command { name: "layer", help: "<n> switches the layer", run: layer },
+    Command { name: "profile", help: "list | use <n|name>", run: profile },
+    Command { name: "display", help: "text \"...\" [seconds] | clear, a message on the display", run: display },
+    Command { name: "reset", help: "restarts the firmware", run: reset },
+    Command { name: "bootloader", help: "restarts into the USB DFU bootloader", run: bootloader },
+    Command { name: "update", help: "restarts into the updater of proto-boot, see tools/update.py", run: update }
+];
+
+fn keys(_: &str, context: &mut Context, out: Output) -> Result<(), &'static str> {
+    let _ = out.write_str("any key stops\r\n");
+    context.live_keys = true;
+    Ok(())
+}
+
+fn enc(_: &str, context: &mut Context, out: Output) -> Result<(), &'static str> {
+    let _ = write!(out, "A {} B {}\r\n", context.encoders[0], context.encoders[1]);
+    Ok(())
+}
+
+fn vib(arguments: &str, context: &mut Context, _: Output) -> Result<(), &'static str> {
+    let segments = parse_effect(arguments)?;
+    context.request(Request::Vibrate(segments))
+}
+
+fn layer(arguments: &str, context: &mut Context, _: Output) -> Result<(), &'static str> {
+    let layer: usize = arguments.parse().map_err(| _ | "bad number")?;
+    if layer >= PROFILES[context.profile].layers.len() {
+        return Err("no such layer");
+    }
+    context.request(Request::Layer(layer))
+}
+
+fn profile(arguments: &str, context: &mut Context, out: Output) -> Result<(), &'static str> {
+    let (action, rest) = split_first(arguments);
+    match action {
+        "list" | "" => {
+            for (i, profile) in PROFILES.iter().enumerate() {
+                let active = if i == context.profile { "*" } else { " " };
+                let _ = write!(out, "{}{} {}\r\n", active, i, profile.name);
+            }
+            Ok(())
+        },
+        "use" => {
+            let index = rest.parse::<usize>().ok()
+                .filter(| index | *index < PROFILE_COUNT)
+                .or_else(|| PROFILES.iter().position(| profile | profile.name.eq_ignore_ascii_case(rest)))
+                .ok_or("no such profile")?;
+            context.request(Request::Profile(index))
+        },
+        _ => Err("expected list or use")
+    }
+}
+
+fn display(arguments: &str, context: &mut Context, _: Output) -> Result<(), &'static str> {
+    let mut words = words(arguments);
+    match words.next() {
+        Some("text") => {
+            let message = words.next().ok_or("missing text")?;
+            let seconds: u32 = match words.next() {
+                Some(seconds) => seconds.parse().map_err(| _ | "bad number")?,
+                None => 10
+            };
+            let item = Item {
+                id: DISPLAY_TEXT_ID,
+                priority: u8::MAX,
+                expires: if seconds > 0 { Some(context.now.wrapping_add(seconds.min(24 * 3600) * 1000)) } else { None },
+                content: Content::Message(text::truncated(message))
+            };
+            context.overlay.set(item).map_err(| _ | "overlay full")
+        },
+        Some("clear") => {
+            context.overlay.remove(DISPLAY_TEXT_ID);
+            Ok(())
+        },
+        _ => Err("expected text or clear")
+    }
+}
+
+fn reset(_: &str, context: &mut Context, _: Output) -> Result<(), &'static str> {
+    context.request(Request::Reset)
+}
+
+fn bootloader(_: &str, context: &mut Context, _: Output) -> Result<(), &'static str> {
+    // it would flash over proto-boot
+    if slots::SLOT.is_some() {
+        return Err("started by proto-boot, use update");
+    }
+    context.request(Request::Bootloader)
+}
+
+fn update(_: &str, context: &mut Context, _: Output) -> Result<(), &'static str> {
+    if slots::SLOT.is_none() {
+        return Err("not started by proto-boot, build with PROTO_SLOT");
+    }
+    context.request(Request::Update)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_graphics::{prelude::*, primitives::Rectangle};
+
+    use crate::ui;
+
+    static TEST: &[Command] = &[
+        Command { name: "ping", help: "", run: | _, _, out | { let _ = out.write_str("pong\r\n"); Ok(()) } },
+        Command { name: "pinch", help: "", run: | arguments, _, out | { let _ = write!(out, "[{}]\r\n", arguments); Ok(()) } }
+    ];
+
+    fn console(echo: bool) -> Console {
+        let mut console = Console::new();
+        console.register(TEST).unwrap();
+        console.set_echo(echo);
+        console
+    }
+
+    /// Everything the console writes back while it gets `input`
+    fn type_in(console: &mut Console, input: &[u8]) -> std::string::String {
+        let mut overlay = Overlay::new(Rectangle::new(Point::zero(), Point::new(127, 127)), ui::THEME);
+        let keys = [[KeyState::Released; 4]; 4];
+        let mut context = Context {
+            now: 0,
+            overlay: &mut overlay,
+            keys: &keys,
+            encoders: [0; 2],
+            profile: 0,
+            layer: 0,
+            requests: Vec::new(),
+            live_keys: false
+        };
+
+        let mut out = std::string::String::new();
+        for byte in input {
+            console.input(*byte, &mut context, &mut out);
+        }
+        out
+    }
+
+    fn segments(pattern: &str) -> std::vec::Vec<(u8, u16)> {
+        parse_effect(pattern).unwrap().iter().map(| segment | (segment.duty, segment.duration)).collect()
+    }
+
+    #[test]
+    fn effects_by_name_and_pattern() {
+        assert_eq!(segments("click"), [(100, 20)]);
+        assert_eq!(segments("heartbeat").len(), 4);
+        assert_eq!(segments("150:20,0:30,45:5"), [(100, 20), (0, 30), (45, 5)]);
+
+        assert_eq!(parse_effect("clicks").err(), Some("unknown pattern"));
+        assert_eq!(parse_effect("x:10").err(), Some("bad duty"));
+        assert_eq!(parse_effect("300:10").err(), Some("bad duty"));
+        assert_eq!(parse_effect("10:").err(), Some("bad duration"));
+        assert_eq!(parse_effect("10:5,").err(), Some("unknown pattern"));
+        assert_eq!(parse_effect("1:1,2:2,3:3,4:4,5:5,6:6,7:7,8:8,9:9").err(), Some("too many segments"));
+    }
+
+    #[test]
+    fn patterns_are_owned() {
+        // two `vib` in one go queue both, the second does not change the first
+        let first = parse_effect("10:5").unwrap();
+        let second = parse_effect("20:6,30:7").unwrap();
+        assert_eq!(first.iter().map(| segment | (segment.duty, segment.duration)).collect::<std::vec::Vec<_>>(), [(10, 5)]);
+        assert_eq!(second.len(), 2);
+    }
+
+    #[test]
+    fn lines_end_with_cr_lf_or_both() {
+        let mut console = console(false);
+        assert_eq!(type_in(&mut console, b"ping\r\nping\nping\r"), "pong\r\nOK\r\n".repeat(3));
+        assert_eq!(type_in(&mut console, b"\r\n"), "OK\r\n");
+    }
+
+    #[test]
+    fn errors() {
+        let mut console = console(false);
+        assert_eq!(type_in(&mut console, b"nope\r"), "ERR unknown command\r\n");
+        assert_eq!(type_in(&mut console, b"echo maybe\r"), "ERR expected on or off\r\n");
+
+        let mut long = [b'a'; LINE_LENGTH + 8];
+        long[LINE_LENGTH + 7] = b'\r';
+        assert_eq!(type_in(&mut console, &long), "ERR line too long\r\n");
+        assert_eq!(type_in(&mut console, b"ping\r"), "pong\r\nOK\r\n");
+    }
+
+    #[test]
+    fn arguments_are_trimmed() {
+        let mut console = console(false);
+        assert_eq!(type_in(&mut console, b"  pinch   a b  \r"), "[a b]\r\nOK\r\n");
+        assert_eq!(type_in(&mut console, b"pinch\r"), "[]\r\nOK\r\n");
+    }
+
+    #[test]
+    fn editing() {
+        let mut console = console(false);
+        // backspace takes the whole ä, ctrl-c the whole line
+        assert_eq!(type_in(&mut console, "pinch äx\x08\x7fy\r".as_bytes()), "[y]\r\nOK\r\n");
+        assert_eq!(type_in(&mut console, b"pinch\x03ping\r"), "pong\r\nOK\r\n");
+        // cursor keys the console does not know
+        assert_eq!(type_in(&mut console, b"\x1b[1;5Cping\x1bOx\r"), "ERR unknown command\r\n");
+    }
+
+    #[test]
+    fn echo() {
+        let mut console = console(true);
+        assert_eq!(type_in(&mut console, b"pinx\x08g\r"), "pinx\x08 \x08g\r\npong\r\nOK\r\n> ");
+        assert_eq!(type_in(&mut console, b"x\x03"), "x^C\r\n> ");
+        assert_eq!(type_in(&mut console, b"echo off\r"), "echo off\r\nOK\r\n");
+        assert_eq!(type_in(&mut console, b"ping\r"), "pong\r\nOK\r\n");
+    }
+
+    #[test]
+    fn history() {
+        let mut console = console(true);
+        type_in(&mut console, b"pinch 1\rpinch 2\rpinch 3\r");
+
+        assert!(type_in(&mut console, b"\x1b[A\x1b[A\r").ends_with("[2]\r\nOK\r\n> "));
+        // the line just run is the newest now
+        assert!(type_in(&mut console, b"\x1b[A\r").ends_with("[2]\r\nOK\r\n> "));
+        // 2 was run twice in a row, it is in the history once: 1 2 3 2
+        assert!(type_in(&mut console, b"\x1b[A\x1b[A\x1b[A\x1b[B\r").ends_with("[3]\r\nOK\r\n> "));
+        // past the newest is an empty line
+        assert_eq!(type_in(&mut console, b"\x1b[A\x1b[B\r"), "\r\x1b[K> pinch 3\r\x1b[K> \r\nOK\r\n> ");
+
+        // only the last HISTORY lines are kept, the oldest is as far back as it goes
+        type_in(&mut console, b"pinch 4\rpinch 5\rpinch 6\r");
+        assert!(type_in(&mut console, &b"\x1b[A".repeat(10)).ends_with("> pinch 3"));
+        assert!(type_in(&mut console, b"\r").ends_with("[3]\r\nOK\r\n> "));
+    }
+
+    #[test]
+    fn tab_completion() {
+        let mut console = console(true);
+        // common part first, the choices on the second tab, a single match gets a space
+        assert_eq!(type_in(&mut console, b"pi\t\tc\tx\r"), "pin\r\nping  pinch  \r\n> pinch x\r\n[x]\r\nOK\r\n> ");
+        assert_eq!(type_in(&mut console, b"he\t"), "help ");
+        type_in(&mut console, b"\x03");
+        // no match, and only the command name is completed
+        assert_eq!(type_in(&mut console, b"x\t"), "x");
+        type_in(&mut console, b"\x03");
+        assert_eq!(type_in(&mut console, b"pinch p\t"), "pinch p");
+    }
+
+    #[test]
+    fn quoted_words() {
+        let found: std::vec::Vec<&str> = words(r#"text "two words"  5 "open end"#).collect();
+        assert_eq!(found, ["text", "two words", "5", "open end"]);
+        assert_eq!(split_first("display  text x "), ("display", "text x"));
+    }
+}
@@ -0,0 +1,98 @@
+// defmt frames to RTT channel 1 and, when switched on, as hex lines over the USB serial, see tools/defmt-cdc.py
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use heapless::Vec;
+use rtt_target::UpChannel;
+
+use crate::serial;
+
+/// Longer frames are only sent to RTT
+const MAX_FRAME: usize = 96;
+/// Start of a tunnelled frame, the rest of the line is the frame in hex
+pub const CDC_PREFIX: &[u8] = b"#defmt ";
+
+static mut CHANNEL: Option<UpChannel> = None;
+static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+static mut FRAME: Vec<u8, MAX_FRAME> = Vec::new();
+static mut FRAME_TOO_LONG: bool = false;
+static mut TAKEN: bool = false;
+static mut INTERRUPTS_WERE_ON: bool = false;
+
+static CDC: AtomicBool = AtomicBool::new(false);
+
+defmt::timestamp!("{=u32}", crate::clock::now());
+
+pub fn init(channel: UpChannel) {
+    unsafe { CHANNEL = Some(channel) };
+}
+
+/// Tunnel frames over the USB serial as well
+pub fn set_cdc(on: bool) {
+    CDC.store(on, Ordering::Relaxed);
+}
+
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {
+        let interrupts_on = cortex_m::register::primask::read().is_active();
+        cortex_m::interrupt::disable();
+
+        unsafe {
+            if TAKEN {
+                panic!("defmt logger taken reentrantly");
+            }
+            TAKEN = true;
+            INTERRUPTS_WERE_ON = interrupts_on;
+
+            FRAME.clear();
+            FRAME_TOO_LONG = false;
+            ENCODER.start_frame(write_encoded);
+        }
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        ENCODER.end_frame(write_encoded);
+
+        if CDC.load(Ordering::Relaxed) && !FRAME_TOO_LONG {
+            send_line(&FRAME);
+        }
+
+        TAKEN = false;
+        if INTERRUPTS_WERE_ON {
+            cortex_m::interrupt::enable();
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        ENCODER.write(bytes, write_encoded);
+    }
+}
+
+fn write_encoded(bytes: &[u8]) {
+    unsafe {
+        if let Some(channel) = CHANNEL.as_mut() {
+            channel.write(bytes);
+        }
+        if FRAME.extend_from_slice(bytes).is_err() {
+            FRAME_TOO_LONG = true;
+        }
+    }
+}
+
+/// Hex, so the frame fits into the line based protocol
+fn send_line(frame: &[u8]) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+    let mut line: Vec<u8, { MAX_FRAME * 2 + 16 }> = Vec::new();
+    let _ = line.extend_from_slice(CDC_PREFIX);
+    for byte in frame {
+        let _ = line.push(DIGITS[(byte >> 4) as usize]);
+        let _ = line.push(DIGITS[(byte & 0xF) as usize]);
+    }
+    let _ = line.extend_from_slice(b"\r\n");
+    serial::write(&line);
+}
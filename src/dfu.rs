@@ -0,0 +1,88 @@
+// USB DFU runtime interface, `dfu-util` detaches the firmware and flashes it in the ROM bootloader, see bootloader.rs
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::Result;
+
+const CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+const SUBCLASS_DFU: u8 = 0x01;
+const PROTOCOL_RUNTIME: u8 = 0x01;
+const DESCRIPTOR_FUNCTIONAL: u8 = 0x21;
+
+const REQUEST_DETACH: u8 = 0x00;
+const REQUEST_GET_STATUS: u8 = 0x03;
+const REQUEST_GET_STATE: u8 = 0x05;
+
+/// The firmware detaches by itself, the host only waits for the bootloader to show up
+const WILL_DETACH: u8 = 1 << 3;
+const CAN_DOWNLOAD: u8 = 1 << 0;
+/// ms the host waits for the bootloader
+const DETACH_TIMEOUT: u16 = 2000;
+/// Block size of the ROM bootloader
+const TRANSFER_SIZE: u16 = 2048;
+
+const STATE_APP_IDLE: u8 = 0;
+
+/// Set from the OTG_FS interrupt, the main loop saves the settings and restarts into the bootloader
+static DETACH: AtomicBool = AtomicBool::new(false);
+
+pub struct DfuRuntime {
+    interface: InterfaceNumber
+}
+
+impl DfuRuntime {
+    pub fn new<B: UsbBus>(allocator: &UsbBusAllocator<B>) -> DfuRuntime {
+        DfuRuntime { interface: allocator.interface() }
+    }
+
+    fn is_for_me(&self, request: &Request) -> bool {
+        request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.index == u8::from(self.interface) as u16
+    }
+}
+
+/// True once after the host asked to detach
+pub fn take_detach() -> bool {
+    DETACH.swap(false, Ordering::Relaxed)
+}
+
+impl<B: UsbBus> UsbClass<B> for DfuRuntime {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.interface, CLASS_APPLICATION_SPECIFIC, SUBCLASS_DFU, PROTOCOL_RUNTIME)?;
+        writer.write(DESCRIPTOR_FUNCTIONAL, &[
+            WILL_DETACH | CAN_DOWNLOAD,
+            DETACH_TIMEOUT as u8, (DETACH_TIMEOUT >> 8) as u8,
+            TRANSFER_SIZE as u8, (TRANSFER_SIZE >> 8) as u8,
+            0x1A, 0x01 // DFU 1.1a, like the ROM bootloader
+        ])
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        if !self.is_for_me(xfer.request()) {
+            return;
+        }
+
+        let _ = match xfer.request().request {
+            // status OK, poll timeout 0, state, no string
+            REQUEST_GET_STATUS => xfer.accept_with(&[0, 0, 0, 0, STATE_APP_IDLE, 0]),
+            REQUEST_GET_STATE => xfer.accept_with(&[STATE_APP_IDLE]),
+            _ => xfer.reject()
+        };
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        if !self.is_for_me(xfer.request()) {
+            return;
+        }
+
+        let _ = match xfer.request().request {
+            REQUEST_DETACH => {
+                DETACH.store(true, Ordering::Relaxed);
+                xfer.accept()
+            },
+            _ => xfer.reject()
+        };
+    }
+}
@@ -9,6 +9,9 @@ use embedded_hal::blocking::spi::Transfer;
 use embedded_hal::blocking::spi::Write;
 use embedded_hal::blocking::delay::DelayMs;
 
+use crate::framebuffer;
+use crate::orientation::Orientation;
+
 
 pub struct Display<SPI, DC, RST>
 where
@@ -33,19 +36,24 @@ where
         }
     }
 
-    pub fn init<DL> (&mut self, delay: &mut DL) -> Result<(), ()> 
+    pub fn init<DL> (&mut self, delay: &mut DL, orientation: Orientation) -> Result<(), ()> 
     where DL: DelayMs<u8> {
 
         self.display.reset(&mut self.reset, delay).map_err(| _ | ())?;
         self.display.init()?;
 
+        // set_rotation of the crate can not mirror, and drawing goes through the framebuffer anyway
+        framebuffer::command(framebuffer::SET_REMAP, &[orientation.remap()]);
+
         Ok(())
     }
 
+    #[allow(dead_code)]
     pub fn clear(&mut self) {
         self.display.clear();
     }
 
+    #[allow(dead_code)]
     pub fn get(&mut self) -> &mut GraphicsMode<SpiInterface<SPI, DC>> {
         &mut self.display
     }
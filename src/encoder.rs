@@ -1,23 +1,39 @@
 use embedded_hal::Qei;
 use embedded_hal::digital::v2::InputPin;
 
+// quadrature counts per mechanical click
+const COUNTS_PER_DETENT: i16 = 4;
+
 pub struct Encoder<Q, B> {
     qei: Q,
-    button: B
+    button: B,
+    last_detent: u16
 }
 
 impl<Q, B> Encoder<Q, B> 
 where 
     Q: Qei<>,
+    Q::Count: Into<u32>,
     B: InputPin<>
 {
     pub fn new(qei: Q, button: B) -> Encoder<Q, B> {
         Encoder {
+            last_detent: qei.count().into() as u16,
             qei,
             button
         }
     }
 
+    /// Whole detents turned since the last call, negative when turned backwards.
+    /// Only the lower 16 bits of the count are used, so this works for 16 and 32 bit timers alike.
+    pub fn detents(&mut self) -> i16 {
+        let count = self.qei.count().into() as u16;
+        let steps = (count.wrapping_sub(self.last_detent) as i16) / COUNTS_PER_DETENT;
+
+        self.last_detent = self.last_detent.wrapping_add((steps * COUNTS_PER_DETENT) as u16);
+        steps
+    }
+
     pub fn count(&self) -> Q::Count {
         self.qei.count()
     }
@@ -0,0 +1,187 @@
+// panic screen, and the last panic message kept in RAM over a reset
+use core::fmt::{self, Write};
+
+use cortex_m::peripheral::SCB;
+use embedded_graphics::{pixelcolor::Rgb565, prelude::*, primitives::Rectangle};
+use stm32f4xx_hal::stm32;
+
+use crate::framebuffer::{self, Framebuffer};
+use crate::orientation::Orientation;
+use crate::slots;
+use crate::text::{self, Align, Layout};
+use crate::ui;
+
+const MESSAGE_LENGTH: usize = 256;
+/// "PANC", anything else is left over from power up or was cleared
+const MAGIC: u32 = 0x5041_4E43;
+
+#[repr(C)]
+struct Record {
+    magic: u32,
+    length: u32,
+    message: [u8; MESSAGE_LENGTH]
+}
+
+/// In `.uninit`, cortex-m-rt neither zeroes nor initialises it, so a reset keeps the content
+#[link_section = ".uninit.FAULT"]
+static mut RECORD: Record = Record { magic: 0, length: 0, message: [0; MESSAGE_LENGTH] };
+
+/// How long the message of an image on trial stays before the reset, cycles at 96 MHz
+const TRIAL_RESET_DELAY: u32 = 96_000_000 * 3;
+
+/// Set on the first fault, a panic while drawing the panic screen only stops
+static mut HANDLING: bool = false;
+
+/// Of the active profile, the panic screen is drawn the same way up. `None` before the display is set up
+static mut ORIENTATION: Option<Orientation> = None;
+
+/// Message of the panic or fatal error before the last reset, until `clear`
+pub fn last() -> Option<&'static str> {
+    let record = unsafe { &RECORD };
+    if record.magic != MAGIC || record.length as usize > MESSAGE_LENGTH {
+        return None;
+    }
+    core::str::from_utf8(&record.message[..record.length as usize]).ok()
+}
+
+pub fn clear() {
+    unsafe { RECORD.magic = 0 };
+}
+
+/// Called whenever the display changes its orientation
+pub fn set_orientation(orientation: Orientation) {
+    unsafe { ORIENTATION = Some(orientation) };
+}
+
+/// Stores `message`, shows it on the display and stops. For the panic handler and errors nothing can recover from
+pub fn fatal(message: fmt::Arguments) -> ! {
+    cortex_m::interrupt::disable();
+
+    if unsafe { HANDLING } {
+        loop {}
+    }
+    unsafe { HANDLING = true };
+
+    stop_outputs();
+    let message = store(message);
+
+    // the framebuffer may be half way through a DMA transfer, it is drawn again anyway
+    let mut framebuffer = Framebuffer::new(unsafe { &mut crate::FRAMEBUFFER });
+    draw(&mut framebuffer, message);
+
+    init_panel(unsafe { ORIENTATION }.unwrap_or(crate::ORIENTATION));
+    framebuffer::write_blocking(&framebuffer);
+
+    // a new image from proto-boot counts the failed start, after MAX_ATTEMPTS the last good one is back
+    if slots::on_trial() {
+        cortex_m::asm::delay(TRIAL_RESET_DELAY);
+        SCB::sys_reset();
+    }
+    // a confirmed one keeps the message on the display, also with the watchdog running
+    loop {
+        slots::feed_watchdog();
+    }
+}
+
+fn store(message: fmt::Arguments) -> &'static str {
+    let record = unsafe { &mut RECORD };
+    let mut writer = Truncating { buffer: &mut record.message, length: 0 };
+    let _ = writer.write_fmt(message);
+
+    record.length = writer.length as u32;
+    record.magic = MAGIC;
+    last().unwrap_or("")
+}
+
+/// Writes whole characters as long as they fit, the rest is dropped
+struct Truncating<'a> {
+    buffer: &'a mut [u8],
+    length: usize
+}
+
+impl<'a> Write for Truncating<'a> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for c in text.chars() {
+            let end = self.length + c.len_utf8();
+            if end > self.buffer.len() {
+                return Err(fmt::Error);
+            }
+            c.encode_utf8(&mut self.buffer[self.length..end]);
+            self.length = end;
+        }
+        Ok(())
+    }
+}
+
+fn draw(target: &mut Framebuffer, message: &str) {
+    target.fill(Rgb565::BLACK);
+
+    let title = Rectangle::new(Point::zero(), Point::new(127, ui::CHAR_HEIGHT + 3));
+    let _ = ui::fill(target, title, Rgb565::RED);
+    let _ = text::draw(target, ui::FONT, "Firmware error", &title, Layout::new(Align::Center, Align::Center, false), Rgb565::WHITE);
+
+    let body = Rectangle::new(Point::new(2, ui::CHAR_HEIGHT + 6), Point::new(125, 125));
+    let _ = text::draw(target, ui::FONT, message, &body, Layout::new(Align::Start, Align::Start, true), Rgb565::WHITE);
+}
+
+/// Vibration motor and piezo off, they would keep going with the PWM outputs on
+fn stop_outputs() {
+    let tim1 = unsafe { &*stm32::TIM1::ptr() };
+    tim1.bdtr.modify(| _, w | w.moe().clear_bit());
+}
+
+/// Sets SPI2 and the panel up from scratch with registers, the panic may have happened before or during `main`'s setup
+fn init_panel(orientation: Orientation) {
+    let rcc = unsafe { &*stm32::RCC::ptr() };
+    let gpioa = unsafe { &*stm32::GPIOA::ptr() };
+    let gpiob = unsafe { &*stm32::GPIOB::ptr() };
+    let spi = unsafe { &*stm32::SPI2::ptr() };
+    let dma = unsafe { &*stm32::DMA1::ptr() };
+
+    dma.st[4].cr.modify(| _, w | w.en().clear_bit());
+    while dma.st[4].cr.read().en().bit_is_set() {}
+
+    rcc.ahb1enr.modify(| _, w | w.gpioaen().set_bit().gpioben().set_bit());
+    rcc.apb1enr.modify(| _, w | w.spi2en().set_bit());
+
+    // PA8 DC and PA9 reset outputs, PB13 SCK and PB15 MOSI alternate function 5
+    gpioa.moder.modify(| r, w | unsafe { w.bits(r.bits() & !(0b1111 << 16) | 0b0101 << 16) });
+    gpiob.moder.modify(| r, w | unsafe { w.bits(r.bits() & !(0b11 << 26 | 0b11 << 30) | 0b10 << 26 | 0b10 << 30) });
+    gpiob.afrh.modify(| r, w | unsafe { w.bits(r.bits() & !(0xF << 20 | 0xF << 28) | 5 << 20 | 5 << 28) });
+
+    // master, mode 0, software chip select, slowest clock so it works whatever the bus runs at
+    spi.cr1.write(| w | unsafe { w.bits(0) });
+    spi.cr2.write(| w | unsafe { w.bits(0) });
+    spi.cr1.write(| w | unsafe { w.bits(1 << 2 | 0b111 << 3 | 1 << 8 | 1 << 9) });
+    spi.cr1.modify(| r, w | unsafe { w.bits(r.bits() | 1 << 6) });
+
+    gpioa.bsrr.write(| w | w.br9().set_bit());
+    delay_ms(2);
+    gpioa.bsrr.write(| w | w.bs9().set_bit());
+    delay_ms(2);
+
+    // same sequence as the ssd1351 crate
+    framebuffer::command(0xFD, &[0x12]);
+    framebuffer::command(0xFD, &[0xB1]);
+    framebuffer::command(0xAE, &[]);
+    framebuffer::command(0xB3, &[0xF1]);
+    framebuffer::command(0xCA, &[0x7F]);
+    framebuffer::command(0xA2, &[0x00]);
+    framebuffer::command(0xA1, &[0x00]);
+    framebuffer::command(framebuffer::SET_REMAP, &[orientation.remap()]);
+    framebuffer::command(0xB5, &[0x00]);
+    framebuffer::command(0xAB, &[0x01]);
+    framebuffer::command(0xB1, &[0x32]);
+    framebuffer::command(0xBE, &[0x05]);
+    framebuffer::command(0xA6, &[]);
+    framebuffer::command(0xC1, &[0xC8, 0x80, 0xC8]);
+    framebuffer::command(0xC7, &[0x0F]);
+    framebuffer::command(0xB4, &[0xA0, 0xB5, 0x55]);
+    framebuffer::command(0xB6, &[0x01]);
+    framebuffer::command(0xAF, &[]);
+}
+
+/// Long enough at 96MHz, longer on the 16MHz HSI
+fn delay_ms(ms: u32) {
+    cortex_m::asm::delay(ms * 96_000);
+}
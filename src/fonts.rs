@@ -0,0 +1,5 @@
+// fonts from assets/fonts/, converted by build.rs
+#![allow(dead_code)]
+use crate::text::{Font, Glyph};
+
+include!(concat!(env!("OUT_DIR"), "/fonts.rs"));
@@ -0,0 +1,236 @@
+use core::convert::Infallible;
+
+use embedded_graphics::{
+    pixelcolor::raw::{RawData, RawU16},
+    pixelcolor::Rgb565,
+    prelude::*,
+};
+use stm32f4xx_hal::stm32;
+
+use crate::orientation::Orientation;
+use crate::ui::DirtyRegions;
+
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = 128;
+pub const SIZE: usize = WIDTH * HEIGHT * 2;
+
+// SSD1351 commands
+const SET_COLUMN: u8 = 0x15;
+const SET_ROW: u8 = 0x75;
+const WRITE_RAM: u8 = 0x5C;
+pub const SET_REMAP: u8 = 0xA0;
+const MASTER_CONTRAST: u8 = 0xC7;
+const DISPLAY_OFF: u8 = 0xAE;
+const DISPLAY_ON: u8 = 0xAF;
+
+/// Whole screen in RAM, RGB565 big endian like the SSD1351 expects it
+pub struct Framebuffer {
+    pixels: &'static mut [u8; SIZE]
+}
+
+impl Framebuffer {
+    pub fn new(pixels: &'static mut [u8; SIZE]) -> Framebuffer {
+        Framebuffer { pixels }
+    }
+
+    pub fn fill(&mut self, color: Rgb565) {
+        let raw = RawU16::from(color).into_inner();
+        for pixel in self.pixels.chunks_exact_mut(2) {
+            pixel[0] = (raw >> 8) as u8;
+            pixel[1] = raw as u8;
+        }
+    }
+
+    /// Rows `top` to `bottom` (inclusive), one contiguous slice
+    pub fn rows(&self, top: usize, bottom: usize) -> &[u8] {
+        &self.pixels[top * WIDTH * 2..(bottom + 1) * WIDTH * 2]
+    }
+}
+
+impl DrawTarget<Rgb565> for Framebuffer {
+    type Error = Infallible;
+
+    fn draw_pixel(&mut self, Pixel(point, color): Pixel<Rgb565>) -> Result<(), Self::Error> {
+        if point.x >= 0 && point.y >= 0 && (point.x as usize) < WIDTH && (point.y as usize) < HEIGHT {
+            let index = (point.y as usize * WIDTH + point.x as usize) * 2;
+            let raw = RawU16::from(color).into_inner();
+            self.pixels[index] = (raw >> 8) as u8;
+            self.pixels[index + 1] = raw as u8;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> Size {
+        Size::new(WIDTH as u32, HEIGHT as u32)
+    }
+
+    fn clear(&mut self, color: Rgb565) -> Result<(), Self::Error> {
+        self.fill(color);
+        Ok(())
+    }
+}
+
+/// Sends framebuffer rows to the panel with SPI2 TX DMA (DMA1 stream 4, channel 0).
+///
+/// The ssd1351 crate has no way to write a window of raw pixels,
+/// so this talks to SPI2 and the DC pin (PA8) directly, after the display was initialised through the crate.
+/// Nothing else may use the SPI while `is_busy`.
+pub struct PanelDma {
+    dma: stm32::DMA1,
+    /// framebuffer rows are panel columns, see `Orientation`
+    transposed: bool
+}
+
+impl PanelDma {
+    /// `orientation` has to be the one the display was initialised with
+    pub fn new(dma: stm32::DMA1, orientation: Orientation) -> PanelDma {
+        let rcc = unsafe { &*stm32::RCC::ptr() };
+        rcc.ahb1enr.modify(| _, w | w.dma1en().set_bit());
+
+        let spi = unsafe { &*stm32::SPI2::ptr() };
+        spi.cr2.modify(| _, w | w.txdmaen().set_bit());
+
+        PanelDma { dma, transposed: orientation.transposed() }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        let spi = unsafe { &*stm32::SPI2::ptr() };
+        // the stream disables itself once all bytes are handed to the SPI, which still has to shift them out
+        self.dma.st[4].cr.read().en().bit_is_set()
+            || spi.sr.read().txe().bit_is_clear()
+            || spi.sr.read().bsy().bit_is_set()
+    }
+
+    pub fn wait(&self) {
+        while self.is_busy() {}
+        clear_overrun();
+    }
+
+    /// Master contrast current, 0 to 15. Waits for a running transfer
+    pub fn set_contrast(&mut self, level: u8) {
+        self.wait();
+        command(MASTER_CONTRAST, &[level.min(15)]);
+    }
+
+    /// Changes the rotation, the whole framebuffer has to be sent again afterwards
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        self.wait();
+        command(SET_REMAP, &[orientation.remap()]);
+        self.transposed = orientation.transposed();
+    }
+
+    /// Off is the panel's sleep mode, the RAM is kept. Waits for a running transfer
+    pub fn set_on(&mut self, on: bool) {
+        self.wait();
+        command(if on { DISPLAY_ON } else { DISPLAY_OFF }, &[]);
+    }
+
+    /// Starts sending the rows touched by `dirty` and clears it.
+    /// Whole rows are sent so the data is one contiguous block. Does nothing while a transfer is running.
+    pub fn flush(&mut self, framebuffer: &Framebuffer, dirty: &mut DirtyRegions) {
+        if dirty.is_empty() || self.is_busy() {
+            return;
+        }
+
+        let top = dirty.regions().iter().map(| r | r.top_left.y).min().unwrap_or(0).max(0) as usize;
+        let bottom = dirty.regions().iter().map(| r | r.bottom_right.y).max().unwrap_or(0).min(HEIGHT as i32 - 1) as usize;
+        dirty.clear();
+
+        if top > bottom {
+            return;
+        }
+
+        clear_overrun();
+        if self.transposed {
+            // the panel writes down the rows first, so a framebuffer row fills a column
+            command(SET_COLUMN, &[top as u8, bottom as u8]);
+            command(SET_ROW, &[0, WIDTH as u8 - 1]);
+        } else {
+            command(SET_COLUMN, &[0, WIDTH as u8 - 1]);
+            command(SET_ROW, &[top as u8, bottom as u8]);
+        }
+        command(WRITE_RAM, &[]);
+        set_dc(true);
+
+        self.start(framebuffer.rows(top, bottom));
+    }
+
+    fn start(&mut self, data: &[u8]) {
+        let spi = unsafe { &*stm32::SPI2::ptr() };
+        let stream = &self.dma.st[4];
+
+        stream.cr.modify(| _, w | w.en().clear_bit());
+        while stream.cr.read().en().bit_is_set() {}
+
+        self.dma.hifcr.write(| w | w
+            .ctcif4().set_bit()
+            .chtif4().set_bit()
+            .cteif4().set_bit()
+            .cdmeif4().set_bit()
+            .cfeif4().set_bit()
+        );
+
+        stream.par.write(| w | unsafe { w.bits(&spi.dr as *const _ as u32) });
+        // the framebuffer is 'static, the transfer may outlive this borrow
+        stream.m0ar.write(| w | unsafe { w.bits(data.as_ptr() as u32) });
+        stream.ndtr.write(| w | unsafe { w.ndt().bits(data.len() as u16) });
+
+        // channel 0, byte sized, memory increment, memory to peripheral
+        stream.cr.write(| w | unsafe { w
+            .chsel().bits(0)
+            .msize().bits(0)
+            .psize().bits(0)
+            .minc().set_bit()
+            .dir().bits(0b01)
+            .pl().bits(0b01)
+        });
+        stream.cr.modify(| _, w | w.en().set_bit());
+    }
+}
+
+/// Sends the whole framebuffer without DMA, for when nothing else runs anymore, see fault.rs
+pub fn write_blocking(framebuffer: &Framebuffer) {
+    // the full window is the same whether the panel is transposed or not
+    command(SET_COLUMN, &[0, WIDTH as u8 - 1]);
+    command(SET_ROW, &[0, HEIGHT as u8 - 1]);
+    command(WRITE_RAM, &[]);
+    set_dc(true);
+    write(framebuffer.rows(0, HEIGHT - 1));
+}
+
+fn set_dc(data: bool) {
+    let gpioa = unsafe { &*stm32::GPIOA::ptr() };
+    if data {
+        gpioa.bsrr.write(| w | w.bs8().set_bit());
+    } else {
+        gpioa.bsrr.write(| w | w.br8().set_bit());
+    }
+}
+
+/// Blocking write of a command with its arguments
+pub fn command(command: u8, arguments: &[u8]) {
+    set_dc(false);
+    write(&[command]);
+    if !arguments.is_empty() {
+        set_dc(true);
+        write(arguments);
+    }
+}
+
+fn write(bytes: &[u8]) {
+    let spi = unsafe { &*stm32::SPI2::ptr() };
+
+    for byte in bytes {
+        while spi.sr.read().txe().bit_is_clear() {}
+        spi.dr.write(| w | w.dr().bits(*byte as u16));
+    }
+    // DC must not change before the last byte is out
+    while spi.sr.read().bsy().bit_is_set() {}
+}
+
+/// Received bytes are never read while writing raw, the hal would report that overrun on its next transfer
+fn clear_overrun() {
+    let spi = unsafe { &*stm32::SPI2::ptr() };
+    let _ = spi.dr.read();
+    let _ = spi.sr.read();
+}
@@ -0,0 +1,188 @@
+use heapless::Vec;
+
+/// Longest envelope a `Player` plays, longer ones are refused
+pub const MAX_SEGMENTS: usize = 8;
+
+/// Segments of a custom effect that can be passed around by value, e.g. from the console
+pub type Envelope = Vec<Segment, MAX_SEGMENTS>;
+
+/// One step of a waveform: hold `duty` percent for `duration` milliseconds
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Segment {
+    pub duty: u8,
+    pub duration: u16
+}
+
+impl Segment {
+    pub const fn new(duty: u8, duration: u16) -> Segment {
+        Segment { duty, duration }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Effect {
+    /// Very light and short, for encoder detents
+    Tick,
+    Click,
+    DoubleClick,
+    Buzz,
+    RampUp,
+    RampDown,
+    Heartbeat,
+    Custom(&'static [Segment])
+}
+
+static TICK: [Segment; 1] = [Segment::new(40, 8)];
+
+static CLICK: [Segment; 1] = [Segment::new(100, 20)];
+
+static DOUBLE_CLICK: [Segment; 3] = [
+    Segment::new(100, 20),
+    Segment::new(0, 60),
+    Segment::new(100, 20)
+];
+
+static BUZZ: [Segment; 1] = [Segment::new(80, 300)];
+
+// the duty steps of the swell examples/motor.rs used to hard-code, quicker
+static RAMP_UP: [Segment; 5] = [
+    Segment::new(6, 100),
+    Segment::new(12, 100),
+    Segment::new(25, 100),
+    Segment::new(50, 100),
+    Segment::new(100, 200)
+];
+
+static RAMP_DOWN: [Segment; 5] = [
+    Segment::new(100, 200),
+    Segment::new(50, 100),
+    Segment::new(25, 100),
+    Segment::new(12, 100),
+    Segment::new(6, 100)
+];
+
+static HEARTBEAT: [Segment; 4] = [
+    Segment::new(100, 40),
+    Segment::new(0, 80),
+    Segment::new(60, 40),
+    Segment::new(0, 400)
+];
+
+impl Effect {
+    pub fn segments(&self) -> &'static [Segment] {
+        match self {
+            Effect::Tick => &TICK,
+            Effect::Click => &CLICK,
+            Effect::DoubleClick => &DOUBLE_CLICK,
+            Effect::Buzz => &BUZZ,
+            Effect::RampUp => &RAMP_UP,
+            Effect::RampDown => &RAMP_DOWN,
+            Effect::Heartbeat => &HEARTBEAT,
+            Effect::Custom(segments) => segments
+        }
+    }
+}
+
+/// Plays back the segments of an effect against a millisecond tick.
+/// It keeps its own copy, the segments do not have to outlive it
+pub struct Player {
+    segments: Envelope,
+    index: usize,
+    segment_start: u32
+}
+
+impl Player {
+    /// Fails for more than `MAX_SEGMENTS` segments
+    pub fn new(segments: &[Segment], now: u32) -> Result<Player, &'static str> {
+        Ok(Player {
+            segments: Vec::from_slice(segments).map_err(| _ | "too many segments")?,
+            index: 0,
+            segment_start: now
+        })
+    }
+
+    /// Returns the duty (percent) to apply at `now`, `None` once the effect is over
+    pub fn update(&mut self, now: u32) -> Option<u8> {
+        while let Some(segment) = self.segments.get(self.index) {
+            // wrapping_sub so the tick may overflow mid effect
+            let elapsed = now.wrapping_sub(self.segment_start);
+
+            if elapsed < segment.duration as u32 {
+                return Some(segment.duty.min(100));
+            }
+
+            self.segment_start = self.segment_start.wrapping_add(segment.duration as u32);
+            self.index += 1;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Duty of every millisecond from `start` until the effect ends
+    fn play(effect: Effect, start: u32) -> std::vec::Vec<u8> {
+        let mut player = Player::new(effect.segments(), start).unwrap();
+        let mut duties = std::vec::Vec::new();
+        let mut now = start;
+        while let Some(duty) = player.update(now) {
+            duties.push(duty);
+            now = now.wrapping_add(1);
+        }
+        duties
+    }
+
+    #[test]
+    fn segments_hold_their_duration() {
+        let duties = play(Effect::DoubleClick, 0);
+        assert_eq!(duties.len(), 100);
+        assert!(duties[..20].iter().all(| duty | *duty == 100));
+        assert!(duties[20..80].iter().all(| duty | *duty == 0));
+        assert!(duties[80..].iter().all(| duty | *duty == 100));
+    }
+
+    #[test]
+    fn ends_and_stays_ended() {
+        let mut player = Player::new(Effect::Click.segments(), 0).unwrap();
+        assert_eq!(player.update(19), Some(100));
+        assert_eq!(player.update(20), None);
+        assert_eq!(player.update(21), None);
+        assert_eq!(player.update(1000), None);
+    }
+
+    #[test]
+    fn late_updates_skip_segments() {
+        // the main loop can hold the tick interrupt back for a while
+        let mut player = Player::new(Effect::RampUp.segments(), 0).unwrap();
+        assert_eq!(player.update(0), Some(6));
+        assert_eq!(player.update(250), Some(25));
+        assert_eq!(player.update(450), Some(100));
+        assert_eq!(player.update(600), None);
+    }
+
+    #[test]
+    fn tick_wraps_mid_effect() {
+        let wrapped = play(Effect::Heartbeat, u32::MAX - 100);
+        assert_eq!(wrapped, play(Effect::Heartbeat, 0));
+        assert_eq!(wrapped.len(), 560);
+    }
+
+    #[test]
+    fn custom_envelopes_are_clamped() {
+        static ENVELOPE: [Segment; 3] = [Segment::new(150, 2), Segment::new(0, 0), Segment::new(30, 1)];
+        assert_eq!(play(Effect::Custom(&ENVELOPE), 7), [100, 100, 30]);
+        assert_eq!(play(Effect::Custom(&[]), 7), []);
+    }
+
+    #[test]
+    fn long_envelopes_are_refused() {
+        static LONG: [Segment; MAX_SEGMENTS + 1] = [Segment::new(10, 1); MAX_SEGMENTS + 1];
+        assert_eq!(Player::new(&LONG, 0).err(), Some("too many segments"));
+        assert_eq!(play(Effect::Custom(&LONG[..MAX_SEGMENTS]), 0).len(), MAX_SEGMENTS);
+    }
+}
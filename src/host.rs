@@ -0,0 +1,142 @@
+// console commands for programs on the host: status overlay, logging and the last panic
+use core::fmt::Write;
+use core::str::FromStr;
+
+use crate::console::{self, Command, Context, Output};
+use crate::fault;
+use crate::logger;
+use crate::overlay::{Content, Item, IMAGE_BYTES, MAX_IMAGE_HEIGHT};
+use crate::text::truncated;
+
+/// `overlay text <id> <priority> <seconds> <text>`
+/// `overlay gauge <id> <priority> <seconds> <value> <max> <label>`
+/// `overlay progress <id> <priority> <seconds> <percent> <label>`
+/// `overlay image <id> <priority> <seconds> <width> <height> <hex bits> <label>`
+/// `overlay clear [id]`
+///
+/// `seconds` 0 keeps the item until it is cleared. Image bits are 1 per pixel, rows padded to whole bytes.
+pub const COMMANDS: &[Command] = &[
+    Command { name: "overlay", help: "text|gauge|progress|image <id> <priority> <seconds> ... | clear [id], see src/host.rs", run: overlay },
+    Command { name: "log", help: "<level> | <module> <level> | clear | rtt on|off, levels off error warn info debug trace", run: log },
+    #[cfg(feature = "defmt")]
+    Command { name: "defmt", help: "cdc on|off tunnels defmt frames over this port", run: defmt },
+    Command { name: "panic", help: "[clear] the panic message from before the last reset", run: panic }
+];
+
+fn panic(arguments: &str, _: &mut Context, out: Output) -> Result<(), &'static str> {
+    match arguments {
+        "" => {
+            let _ = write!(out, "{}\r\n", fault::last().unwrap_or("none"));
+            Ok(())
+        },
+        "clear" => {
+            fault::clear();
+            Ok(())
+        },
+        _ => Err("expected nothing or clear")
+    }
+}
+
+#[cfg(feature = "defmt")]
+fn defmt(arguments: &str, _: &mut Context, _: Output) -> Result<(), &'static str> {
+    match console::split_first(arguments) {
+        ("cdc", on) => {
+            crate::defmt_logger::set_cdc(console::on_off(on)?);
+            Ok(())
+        },
+        _ => Err("expected cdc on or off")
+    }
+}
+
+fn overlay(arguments: &str, context: &mut Context, _: Output) -> Result<(), &'static str> {
+    let mut words = arguments.splitn(2, ' ');
+    let kind = words.next().unwrap_or("");
+    let rest = words.next().unwrap_or("").trim();
+
+    if kind == "clear" {
+        match rest {
+            "" => context.overlay.clear(),
+            id => context.overlay.remove(number(Some(id))?)
+        }
+        return Ok(());
+    }
+
+    let mut words = rest.splitn(4, ' ');
+    let id = number(words.next())?;
+    let priority = number(words.next())?;
+    let seconds: u32 = number(words.next())?;
+    let rest = words.next().unwrap_or("");
+
+    let content = match kind {
+        "text" => Content::Text(truncated(rest)),
+        "gauge" => {
+            let mut words = rest.splitn(3, ' ');
+            let value = number(words.next())?;
+            let max = number(words.next())?;
+            Content::Gauge { value, max, label: truncated(words.next().unwrap_or("")) }
+        },
+        "progress" => {
+            let mut words = rest.splitn(2, ' ');
+            let percent: u8 = number(words.next())?;
+            Content::Progress { percent: percent.min(100), label: truncated(words.next().unwrap_or("")) }
+        },
+        "image" => {
+            let mut words = rest.splitn(4, ' ');
+            let width: u8 = number(words.next())?;
+            let height: u8 = number(words.next())?;
+            let hex = words.next().unwrap_or("").as_bytes();
+
+            let length = (width as usize + 7) / 8 * height as usize;
+            if width == 0 || height == 0 || height > MAX_IMAGE_HEIGHT || length > IMAGE_BYTES {
+                return Err("image too large");
+            }
+            if hex.len() != length * 2 {
+                return Err("image size does not match");
+            }
+
+            let mut bits = [0u8; IMAGE_BYTES];
+            for (byte, digits) in bits.iter_mut().zip(hex.chunks(2)) {
+                *byte = hex_digit(digits[0])? << 4 | hex_digit(digits[1])?;
+            }
+            Content::Image { width, height, bits, label: truncated(words.next().unwrap_or("")) }
+        },
+        _ => return Err("unknown overlay")
+    };
+
+    let item = Item {
+        id,
+        priority,
+        // a day at most, longer would wrap the clock
+        expires: if seconds > 0 { Some(context.now.wrapping_add(seconds.min(24 * 3600) * 1000)) } else { None },
+        content
+    };
+    context.overlay.set(item).map_err(| _ | "overlay full")
+}
+
+fn log(arguments: &str, _: &mut Context, _: Output) -> Result<(), &'static str> {
+    let mut words = arguments.split_whitespace();
+
+    match (words.next(), words.next()) {
+        (Some("clear"), None) => logger::clear_filters(),
+        (Some("rtt"), Some(on)) => logger::set_rtt(console::on_off(on)?),
+        (Some(level), None) => logger::set_default_level(level.parse().map_err(| _ | "bad level")?),
+        (Some(module), Some(level)) => {
+            logger::set_filter(module, level.parse().map_err(| _ | "bad level")?)?
+        },
+        (None, _) => return Err("missing level")
+    }
+    Ok(())
+}
+
+fn number<T: FromStr>(word: Option<&str>) -> Result<T, &'static str> {
+    word.and_then(| word | word.parse().ok()).ok_or("bad number")
+}
+
+fn hex_digit(digit: u8) -> Result<u8, &'static str> {
+    match digit {
+        b'0'..=b'9' => Ok(digit - b'0'),
+        b'a'..=b'f' => Ok(digit - b'a' + 10),
+        b'A'..=b'F' => Ok(digit - b'A' + 10),
+        _ => Err("bad hex digit")
+    }
+}
@@ -0,0 +1,408 @@
+use embedded_graphics::{pixelcolor::Rgb565, prelude::*};
+
+use crate::asset::{Asset, Qoi};
+
+/// Widest row the decoders keep in memory, wider images are cut off
+pub const MAX_WIDTH: usize = 128;
+/// The panel, taller images are refused instead of decoded into nothing
+pub const MAX_HEIGHT: u32 = 128;
+
+#[derive(Debug)]
+pub enum Error {
+    Read,
+    /// Not a valid image
+    Format,
+    /// Valid image, but a variant the decoders do not handle
+    Unsupported
+}
+
+/// Where encoded image bytes come from, e.g. a file on the SD card
+pub trait Source {
+    /// Fills `buf` as far as possible, returns 0 at the end
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error>;
+}
+
+impl Source for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = buf.len().min(self.len());
+        buf[..len].copy_from_slice(&self[..len]);
+        *self = &self[len..];
+        Ok(len)
+    }
+}
+
+/// Buffers a `Source` so decoders can read byte by byte
+struct Reader<'a, S> {
+    source: &'a mut S,
+    buf: [u8; 64],
+    pos: usize,
+    len: usize
+}
+
+impl<'a, S: Source> Reader<'a, S> {
+    fn new(source: &'a mut S) -> Reader<'a, S> {
+        Reader { source, buf: [0; 64], pos: 0, len: 0 }
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        if self.pos == self.len {
+            self.len = self.source.read(&mut self.buf)?;
+            self.pos = 0;
+            if self.len == 0 {
+                return Err(Error::Format);
+            }
+        }
+        self.pos += 1;
+        Ok(self.buf[self.pos - 1])
+    }
+
+    fn skip(&mut self, count: usize) -> Result<(), Error> {
+        for _ in 0..count {
+            self.byte()?;
+        }
+        Ok(())
+    }
+
+    fn u16_le(&mut self) -> Result<u16, Error> {
+        Ok(self.byte()? as u16 | (self.byte()? as u16) << 8)
+    }
+
+    fn u32_le(&mut self) -> Result<u32, Error> {
+        Ok(self.u16_le()? as u32 | (self.u16_le()? as u32) << 16)
+    }
+
+    fn u32_be(&mut self) -> Result<u32, Error> {
+        Ok((self.byte()? as u32) << 24 | (self.byte()? as u32) << 16 | (self.byte()? as u32) << 8 | self.byte()? as u32)
+    }
+}
+
+fn rgb(r: u8, g: u8, b: u8) -> Rgb565 {
+    Rgb565::new(r >> 3, g >> 2, b >> 3)
+}
+
+fn rgb565(raw: u16) -> Rgb565 {
+    Rgb565::new((raw >> 11) as u8, (raw >> 5) as u8 & 0x3F, raw as u8 & 0x1F)
+}
+
+fn draw_row<D: DrawTarget<Rgb565>>(target: &mut D, origin: Point, y: u32, row: &[Rgb565]) -> Result<(), Error> {
+    target
+        .draw_iter(row.iter().enumerate().map(| (x, color) | Pixel(origin + Point::new(x as i32, y as i32), *color)))
+        .map_err(| _ | Error::Read)
+}
+
+/// Size of an image in pixels
+#[derive(Clone, Copy)]
+pub struct ImageSize {
+    pub width: u32,
+    pub height: u32
+}
+
+/// Uncompressed 24 bit BMP, or 16 bit with RGB565 bitfields
+pub fn draw_bmp<S: Source, D: DrawTarget<Rgb565>>(source: &mut S, target: &mut D, origin: Point) -> Result<ImageSize, Error> {
+    let mut reader = Reader::new(source);
+
+    if reader.byte()? != b'B' || reader.byte()? != b'M' {
+        return Err(Error::Format);
+    }
+    reader.skip(8)?;
+    let data_offset = reader.u32_le()? as usize;
+
+    let header_size = reader.u32_le()? as usize;
+    let width = reader.u32_le()? as i32;
+    let height = reader.u32_le()? as i32;
+    reader.skip(2)?;
+    let bits = reader.u16_le()?;
+    let compression = reader.u32_le()?;
+
+    // 14 byte file header + the part of the info header read so far
+    let position = 14 + 20;
+
+    match (bits, compression) {
+        (24, 0) => (),
+        // BI_BITFIELDS, assumed to be 565
+        (16, 3) => (),
+        _ => return Err(Error::Unsupported)
+    }
+    if width <= 0 || height == 0 || header_size < 40 || data_offset < position {
+        return Err(Error::Format);
+    }
+
+    reader.skip(data_offset - position)?;
+
+    let width = width as u32;
+    // positive height means the rows are stored bottom up
+    let bottom_up = height > 0;
+    let height = height.unsigned_abs();
+    if height > MAX_HEIGHT {
+        return Err(Error::Unsupported);
+    }
+
+    let bytes_per_pixel = bits as usize / 8;
+    let stride = (width as usize * bytes_per_pixel + 3) & !3;
+    let visible = (width as usize).min(MAX_WIDTH);
+
+    let mut row = [Rgb565::BLACK; MAX_WIDTH];
+    for line in 0..height {
+        for x in 0..width as usize {
+            let color = if bits == 24 {
+                let (b, g, r) = (reader.byte()?, reader.byte()?, reader.byte()?);
+                rgb(r, g, b)
+            } else {
+                rgb565(reader.u16_le()?)
+            };
+
+            if x < MAX_WIDTH {
+                row[x] = color;
+            }
+        }
+        reader.skip(stride - width as usize * bytes_per_pixel)?;
+
+        let y = if bottom_up { height - 1 - line } else { line };
+        draw_row(target, origin, y, &row[..visible])?;
+    }
+
+    Ok(ImageSize { width, height })
+}
+
+/// Headerless RGB565, big endian, the size has to be known up front
+pub fn draw_raw<S: Source, D: DrawTarget<Rgb565>>(source: &mut S, target: &mut D, origin: Point, size: ImageSize) -> Result<ImageSize, Error> {
+    let mut reader = Reader::new(source);
+    let mut row = [Rgb565::BLACK; MAX_WIDTH];
+    let visible = (size.width as usize).min(MAX_WIDTH);
+
+    for y in 0..size.height {
+        for x in 0..size.width as usize {
+            let raw = (reader.byte()? as u16) << 8 | reader.byte()? as u16;
+            if x < MAX_WIDTH {
+                row[x] = rgb565(raw);
+            }
+        }
+        draw_row(target, origin, y, &row[..visible])?;
+    }
+
+    Ok(size)
+}
+
+/// QOI, see https://qoiformat.org/qoi-specification.pdf. Alpha is ignored.
+/// The chunks are decoded by `asset::Qoi`, the same as for assets in flash
+pub fn draw_qoi<S: Source, D: DrawTarget<Rgb565>>(source: &mut S, target: &mut D, origin: Point) -> Result<ImageSize, Error> {
+    let mut reader = Reader::new(source);
+
+    for magic in b"qoif" {
+        if reader.byte()? != *magic {
+            return Err(Error::Format);
+        }
+    }
+    let width = reader.u32_be()?;
+    let height = reader.u32_be()?;
+    // channels and colorspace do not change the encoding
+    reader.skip(2)?;
+    if height > MAX_HEIGHT {
+        return Err(Error::Unsupported);
+    }
+
+    let mut qoi = Qoi::new();
+    let mut row = [Rgb565::BLACK; MAX_WIDTH];
+    let visible = (width as usize).min(MAX_WIDTH);
+
+    for y in 0..height {
+        for x in 0..width as usize {
+            let pixel = qoi.next(|| reader.byte())?;
+            if x < MAX_WIDTH {
+                row[x] = rgb(pixel[0], pixel[1], pixel[2]);
+            }
+        }
+        draw_row(target, origin, y, &row[..visible])?;
+    }
+
+    Ok(ImageSize { width, height })
+}
+
+/// Opaque pixels of an asset at `origin`, masks in `tint`
+pub fn asset_pixels<'a>(asset: &Asset<'a>, origin: Point, tint: Rgb565) -> impl Iterator<Item = Pixel<Rgb565>> + 'a {
+    let width = (asset.width as usize).max(1);
+    let tint = (tint.r() as u16) << 11 | (tint.g() as u16) << 5 | tint.b() as u16;
+
+    asset.pixels(tint).enumerate().filter_map(move | (i, pixel) | {
+        pixel.map(| raw | Pixel(origin + Point::new((i % width) as i32, (i / width) as i32), rgb565(raw)))
+    })
+}
+
+/// Draws an asset from flash, see build.rs
+pub fn draw_asset<D: DrawTarget<Rgb565>>(asset: &Asset, target: &mut D, origin: Point, tint: Rgb565) -> Result<(), Error> {
+    target.draw_iter(asset_pixels(asset, origin, tint)).map_err(| _ | Error::Read)
+}
+
+/// Picks the decoder from the file extension
+#[allow(dead_code)]
+pub fn draw_file<S: Source, D: DrawTarget<Rgb565>>(name: &str, source: &mut S, target: &mut D, origin: Point, raw: ImageSize) -> Result<ImageSize, Error> {
+    let extension = name.rsplit('.').next().unwrap_or("");
+
+    if extension.eq_ignore_ascii_case("bmp") {
+        draw_bmp(source, target, origin)
+    } else if extension.eq_ignore_ascii_case("qoi") {
+        draw_qoi(source, target, origin)
+    } else if extension.eq_ignore_ascii_case("565") {
+        draw_raw(source, target, origin, raw)
+    } else {
+        Err(Error::Unsupported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::Encoding;
+
+    const WIDTH: usize = 8;
+    const HEIGHT: usize = 4;
+
+    /// What the files in tests/images hold: a run, small and larger steps, repeats and arbitrary colors
+    fn pattern(x: usize, y: usize) -> Rgb565 {
+        let step = x as u8;
+        let (r, g, b) = match y {
+            0 => (10, 20, 30),
+            1 => (10 + step, 20 + step, 30 + step),
+            2 => (10 + 5 * step, 20 + 6 * step, 30 + 4 * step),
+            _ if x % 2 == 0 => (10, 20, 30),
+            _ => (200, 30 * step, 99)
+        };
+        rgb(r, g, b)
+    }
+
+    /// Remembers every pixel, drawing one twice or outside is a mistake of the decoder
+    struct Canvas {
+        pixels: Vec<Option<Rgb565>>
+    }
+
+    impl Canvas {
+        fn new() -> Canvas {
+            Canvas { pixels: vec![None; 128 * 128] }
+        }
+
+        fn assert_pattern(&self, origin: Point) {
+            for y in 0..128 {
+                for x in 0..128 {
+                    let (px, py) = (x as i32 - origin.x, y as i32 - origin.y);
+                    let expected = if px >= 0 && py >= 0 && (px as usize) < WIDTH && (py as usize) < HEIGHT {
+                        Some(pattern(px as usize, py as usize))
+                    } else {
+                        None
+                    };
+                    assert_eq!(self.pixels[y * 128 + x], expected, "pixel {} {}", x, y);
+                }
+            }
+        }
+    }
+
+    impl DrawTarget<Rgb565> for Canvas {
+        type Error = core::convert::Infallible;
+
+        fn draw_pixel(&mut self, Pixel(point, color): Pixel<Rgb565>) -> Result<(), Self::Error> {
+            assert!(point.x >= 0 && point.y >= 0 && point.x < 128 && point.y < 128, "outside {:?}", point);
+            let pixel = &mut self.pixels[point.y as usize * 128 + point.x as usize];
+            assert!(pixel.is_none(), "drawn twice {:?}", point);
+            *pixel = Some(color);
+            Ok(())
+        }
+
+        fn size(&self) -> Size {
+            Size::new(128, 128)
+        }
+    }
+
+    const SIZE: ImageSize = ImageSize { width: WIDTH as u32, height: HEIGHT as u32 };
+
+    fn file(name: &str) -> &'static [u8] {
+        match name {
+            "pattern24.bmp" => include_bytes!("../tests/images/pattern24.bmp"),
+            "pattern16.bmp" => include_bytes!("../tests/images/pattern16.bmp"),
+            "pattern.565" => include_bytes!("../tests/images/pattern.565"),
+            "pattern.qoi" => include_bytes!("../tests/images/pattern.qoi"),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn sample_files() {
+        // 24 bit bottom up with padded rows, 16 bit bitfields top down
+        for name in ["pattern24.bmp", "pattern16.bmp", "pattern.565", "pattern.qoi"].iter() {
+            let mut canvas = Canvas::new();
+            let origin = Point::new(3, 5);
+            let size = draw_file(name, &mut file(name), &mut canvas, origin, SIZE).unwrap();
+            assert_eq!((size.width, size.height), (SIZE.width, SIZE.height), "{}", name);
+            canvas.assert_pattern(origin);
+        }
+    }
+
+    #[test]
+    fn qoi_assets_decode_like_files() {
+        let asset = Asset {
+            width: WIDTH as u16,
+            height: HEIGHT as u16,
+            encoding: Encoding::Qoi,
+            palette: &[],
+            data: file("pattern.qoi")
+        };
+        let mut canvas = Canvas::new();
+        draw_asset(&asset, &mut canvas, Point::new(100, 0), Rgb565::RED).unwrap();
+        canvas.assert_pattern(Point::new(100, 0));
+    }
+
+    #[test]
+    fn decodes_from_small_reads() {
+        /// Hands out a single byte per read, like a slow card would hand out short blocks
+        struct Trickle(&'static [u8]);
+
+        impl Source for Trickle {
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+                let count = self.0.len().min(buf.len()).min(1);
+                buf[..count].copy_from_slice(&self.0[..count]);
+                self.0 = &self.0[count..];
+                Ok(count)
+            }
+        }
+
+        let mut canvas = Canvas::new();
+        draw_qoi(&mut Trickle(file("pattern.qoi")), &mut canvas, Point::zero()).unwrap();
+        canvas.assert_pattern(Point::zero());
+    }
+
+    #[test]
+    fn truncated_files_fail() {
+        for name in ["pattern24.bmp", "pattern16.bmp", "pattern.565", "pattern.qoi"].iter() {
+            let data = file(name);
+            for length in [0, 10, data.len() / 2, data.len() - 9].iter() {
+                let result = draw_file(name, &mut &data[..*length], &mut Canvas::new(), Point::zero(), SIZE);
+                assert!(matches!(result, Err(Error::Format)), "{} cut at {}", name, length);
+            }
+        }
+    }
+
+    #[test]
+    fn bad_headers() {
+        let mut qoi = file("pattern.qoi").to_vec();
+        qoi[0] = b'Q';
+        assert!(matches!(draw_qoi(&mut &qoi[..], &mut Canvas::new(), Point::zero()), Err(Error::Format)));
+
+        // 32 bit BMPs are not handled
+        let mut bmp = file("pattern24.bmp").to_vec();
+        bmp[28] = 32;
+        assert!(matches!(draw_bmp(&mut &bmp[..], &mut Canvas::new(), Point::zero()), Err(Error::Unsupported)));
+
+        assert!(matches!(draw_file("a.png", &mut file("pattern.qoi"), &mut Canvas::new(), Point::zero(), SIZE), Err(Error::Unsupported)));
+    }
+
+    #[test]
+    fn refuses_images_taller_than_the_panel() {
+        let mut bmp = file("pattern24.bmp").to_vec();
+        for height in [i32::MIN, -129, 129, i32::MAX].iter() {
+            bmp[22..26].copy_from_slice(&height.to_le_bytes());
+            assert!(matches!(draw_bmp(&mut &bmp[..], &mut Canvas::new(), Point::zero()), Err(Error::Unsupported)), "{}", height);
+        }
+
+        let mut qoi = file("pattern.qoi").to_vec();
+        qoi[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(draw_qoi(&mut &qoi[..], &mut Canvas::new(), Point::zero()), Err(Error::Unsupported)));
+    }
+}
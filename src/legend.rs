@@ -0,0 +1,75 @@
+use embedded_graphics::{pixelcolor::Rgb565, prelude::*, primitives::Rectangle};
+
+use crate::orientation::Orientation;
+use crate::profile::Profile;
+use crate::ui::{self, DirtyRegions, KeyGrid, KeyIcon, Screen, StatusBar, Widget, THEME};
+
+/// Shows what every key does on the active layer, with profile and layer name on top
+pub struct LegendScreen {
+    header: StatusBar,
+    grid: KeyGrid,
+    /// icons set from outside, they win over the built in ones of the actions
+    icons: [[Option<&'static [u8]>; 4]; 4]
+}
+
+impl LegendScreen {
+    pub fn new(bounds: Rectangle) -> LegendScreen {
+        let (header, grid) = ui::split_top(bounds, StatusBar::HEIGHT);
+
+        LegendScreen {
+            header: StatusBar::new(header, THEME),
+            // small gap between header and grid
+            grid: KeyGrid::new(Rectangle::new(grid.top_left + Point::new(0, 2), grid.bottom_right), THEME),
+            icons: [[None; 4]; 4]
+        }
+    }
+
+    /// Updates the legend for `layer` of `profile`, only what changed gets redrawn
+    pub fn show(&mut self, profile: &Profile, layer: usize) {
+        let name = profile.layers.get(layer).map_or("", | layer | layer.name);
+        self.header.set(profile.name, name);
+
+        for y in 0..4 {
+            for x in 0..4 {
+                let action = profile.action(layer, x, y);
+                self.grid.set_label(x, y, action.label());
+                self.grid.set_icon(x, y, self.icons[y][x].map(KeyIcon::Rgb).or(action.icon().map(KeyIcon::Asset)));
+            }
+        }
+    }
+
+    /// RGB icon drawn instead of the label, `None` goes back to the action's icon or label
+    #[allow(dead_code)]
+    pub fn set_icon(&mut self, x: usize, y: usize, icon: Option<&'static [u8]>) {
+        self.icons[y][x] = icon;
+        // right away, so setting `None` and then the same buffer again redraws it
+        self.grid.set_icon(x, y, icon.map(KeyIcon::Rgb));
+    }
+
+    /// Leaves unbound keys undrawn, for a background image behind the legend
+    #[allow(dead_code)]
+    pub fn set_transparent(&mut self, transparent: bool) {
+        self.grid.set_transparent(transparent);
+    }
+
+    /// Moves the keys along with the panel rotation
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        self.grid.set_orientation(orientation);
+    }
+
+    pub fn set_pressed(&mut self, x: usize, y: usize, pressed: bool) {
+        self.grid.set_pressed(x, y, pressed);
+    }
+}
+
+impl Screen for LegendScreen {
+    fn render<D: DrawTarget<Rgb565>>(&mut self, target: &mut D, dirty: &mut DirtyRegions) -> Result<(), D::Error> {
+        self.header.render(target, dirty)?;
+        self.grid.render(target, dirty)
+    }
+
+    fn invalidate(&mut self) {
+        self.header.set_dirty(true);
+        self.grid.set_dirty(true);
+    }
+}
@@ -0,0 +1,113 @@
+// `log` records over the USB serial with a timestamp, optionally to RTT as well
+use core::fmt::Write;
+use core::str::FromStr;
+
+use heapless::String;
+use log::{LevelFilter, Log, Metadata, Record};
+use rtt_target::rprintln;
+
+use crate::clock;
+use crate::serial;
+
+const MAX_FILTERS: usize = 8;
+const LINE_LENGTH: usize = 160;
+
+/// Level of a module and everything below it, e.g. `legend` or `proto::legend`
+#[derive(Clone)]
+struct Filter {
+    module: String<24>,
+    level: LevelFilter
+}
+
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+static mut DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+static mut FILTERS: [Option<Filter>; MAX_FILTERS] = [None, None, None, None, None, None, None, None];
+static mut RTT: bool = true;
+
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        // filtering happens per module in `enabled`
+        log::set_max_level(LevelFilter::Trace);
+    }
+}
+
+/// Level for modules without a filter
+pub fn set_default_level(level: LevelFilter) {
+    cortex_m::interrupt::free(| _ | unsafe { DEFAULT_LEVEL = level });
+}
+
+/// Sets or replaces the filter for `module`, fails when all filters are in use
+pub fn set_filter(module: &str, level: LevelFilter) -> Result<(), &'static str> {
+    let module = String::from_str(module.strip_prefix("proto::").unwrap_or(module)).map_err(| _ | "module name too long")?;
+
+    cortex_m::interrupt::free(| _ | {
+        let filters = unsafe { &mut FILTERS };
+        let slot = filters.iter().position(| filter | filter.as_ref().map_or(false, | filter | filter.module == module))
+            .or(filters.iter().position(| filter | filter.is_none()))
+            .ok_or("too many filters")?;
+        filters[slot] = Some(Filter { module, level });
+        Ok(())
+    })
+}
+
+/// Removes every module filter
+pub fn clear_filters() {
+    cortex_m::interrupt::free(| _ | unsafe { FILTERS = [None, None, None, None, None, None, None, None] });
+}
+
+pub fn set_rtt(on: bool) {
+    unsafe { RTT = on };
+}
+
+/// The level of the filter with the longest matching module, or the default
+fn level(target: &str) -> LevelFilter {
+    let path = target.strip_prefix("proto::").unwrap_or(target);
+
+    cortex_m::interrupt::free(| _ | {
+        let mut best: Option<&Filter> = None;
+        for filter in unsafe { FILTERS.iter() }.flatten() {
+            let matches = path == filter.module.as_str()
+                || (path.starts_with(filter.module.as_str()) && path[filter.module.len()..].starts_with("::"));
+            if matches && best.map_or(true, | best | filter.module.len() > best.module.len()) {
+                best = Some(filter);
+            }
+        }
+        best.map_or(unsafe { DEFAULT_LEVEL }, | filter | filter.level)
+    })
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let now = clock::now();
+        let mut line: String<LINE_LENGTH> = String::new();
+        let complete = write!(
+            line, "[{:>5}.{:03}] {:<5} {}: {}",
+            now / 1000, now % 1000, record.level(), record.target(), record.args()
+        ).is_ok();
+        if !complete {
+            // the rest of the message did not fit
+            while line.len() > LINE_LENGTH - 3 {
+                line.pop();
+            }
+            let _ = line.push_str("...");
+        }
+
+        if unsafe { RTT } {
+            rprintln!("{}", line);
+        }
+        let _ = write!(serial::Writer, "{}\r\n", line);
+    }
+
+    fn flush(&self) {}
+}
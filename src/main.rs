@@ -1,15 +1,19 @@
-#![no_std]
-#![no_main]
+// the unit tests of the modules run on the host with std, see Readme
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
-use core::panic::PanicInfo;
-use embedded_graphics::primitives::Circle;
-use rtt_target::{rprintln, rtt_init_print};
+use embedded_graphics::primitives::{Circle, Rectangle};
+use log::{debug, error, info, warn};
+use rtt_target::rtt_init_print;
+#[cfg(feature = "defmt")]
+use rtt_target::{rtt_init, set_print_channel};
 
+#[cfg(not(test))]
 use cortex_m_rt::entry;
 use stm32f4xx_hal::otg_fs::{UsbBus, USB, UsbBusType};
-use stm32f4xx_hal::{prelude::*, stm32, qei::Qei, interrupt, delay::Delay};
+use stm32f4xx_hal::{prelude::*, stm32, qei::Qei, interrupt, delay::Delay, timer::{Timer, Event}};
 use usb_device::prelude::*;
-use usbd_serial::{SerialPort, USB_CLASS_CDC};
+use usbd_serial::SerialPort;
 use usb_device::bus::UsbBusAllocator;
 
 use embedded_graphics::{
@@ -19,11 +23,30 @@ use embedded_graphics::{
 };
 
 use stm32f4xx_hal::spi::{Mode, Phase, Polarity, Spi};
-use stm32f4xx_hal::pwm;
+use stm32f4xx_hal::pwm::{self, PwmChannels};
 
 static mut USB_BUS: Option<UsbBusAllocator<UsbBusType>> = None;
 static mut USB_SERIAL: Option<usbd_serial::SerialPort<UsbBusType>> = None;
+static mut USB_HID: Option<RawHid<UsbBusType>> = None;
+static mut USB_DFU: Option<DfuRuntime> = None;
 static mut USB_DEVICE: Option<UsbDevice<UsbBusType>> = None;
+/// Last state seen by the OTG_FS interrupt
+static mut USB_STATE: UsbDeviceState = UsbDeviceState::Default;
+
+static mut VIBRATOR: Option<Vibrator<PwmChannels<stm32::TIM1, pwm::C1>>> = None;
+static mut BUZZER: Option<Buzzer<PwmChannels<stm32::TIM1, pwm::C2>>> = None;
+static mut FRAMEBUFFER: [u8; framebuffer::SIZE] = [0; framebuffer::SIZE];
+static mut TICK_TIMER: Option<Timer<stm32::TIM4>> = None;
+#[cfg(feature = "sdcard")]
+static mut ICONS: [[u8; ui::ICON_BYTES]; 16] = [[0; ui::ICON_BYTES]; 16];
+
+/// defmt info with `--features defmt`, nothing otherwise
+macro_rules! event {
+    ($($arguments:tt)*) => {
+        #[cfg(feature = "defmt")]
+        defmt::info!($($arguments)*);
+    };
+}
 
 mod matrix;
 use matrix::{Matrix, KeyState};
@@ -37,9 +60,100 @@ use display::Display;
 mod vibrator;
 use vibrator::Vibrator;
 
-#[entry]
+mod haptic;
+use haptic::Effect;
+
+mod clock;
+
+mod buzzer;
+use buzzer::{Buzzer, Sound};
+
+mod profile;
+use profile::{Action, PROFILES, PROFILE_COUNT, Settings};
+
+mod storage;
+use storage::{Storage, Stored};
+
+mod ui;
+use ui::{DirtyRegions, Screen};
+
+mod legend;
+use legend::LegendScreen;
+
+mod overlay;
+use overlay::Overlay;
+
+mod host;
+
+mod console;
+use console::{Console, Context, Request};
+
+mod bootloader;
+mod slots;
+
+mod stream;
+use stream::Event;
+
+mod power;
+use power::{Power, Screensaver};
+
+mod orientation;
+use orientation::{Orientation, Rotation};
+
+/// How the panel sits in the enclosure, profiles can override it
+const ORIENTATION: Orientation = Orientation::new(Rotation::Deg0, false);
+
+mod framebuffer;
+use framebuffer::{Framebuffer, PanelDma};
+
+mod asset;
+mod assets;
+
+mod text;
+mod fonts;
+
+mod fault;
+
+mod serial;
+mod raw_hid;
+use raw_hid::RawHid;
+mod usb;
+mod dfu;
+use dfu::DfuRuntime;
+mod logger;
+#[cfg(feature = "defmt")]
+mod defmt_logger;
+
+mod image;
+#[cfg(feature = "sdcard")]
+use image::ImageSize;
+
+#[cfg(feature = "sdcard")]
+mod sdcard;
+#[cfg(feature = "sdcard")]
+use sdcard::SdCard;
+
+#[cfg_attr(not(test), entry)]
 fn main() -> ! {
+    bootloader::check();
+
+    #[cfg(not(feature = "defmt"))]
     rtt_init_print!();
+    #[cfg(feature = "defmt")]
+    {
+        let channels = rtt_init! {
+            up: {
+                0: { size: 1024 name: "Terminal" }
+                1: { size: 1024 name: "defmt" }
+            }
+        };
+        set_print_channel(channels.up.0);
+        defmt_logger::init(channels.up.1);
+    }
+    logger::init();
+    if let Some(message) = fault::last() {
+        error!("Before the reset: {}", message);
+    }
 
     let peripherals = stm32::Peripherals::take().unwrap();
     let cortex_peripherals = cortex_m::Peripherals::take().unwrap();
@@ -63,7 +177,7 @@ fn main() -> ! {
     let gpioe = peripherals.GPIOE.split();
 
     // Encoder
-    let rotary_a = Encoder::new(
+    let mut rotary_a = Encoder::new(
         Qei::tim2(peripherals.TIM2, (
             gpioa.pa0.into_alternate_af1(),
             gpioa.pa1.into_alternate_af1(),
@@ -71,7 +185,7 @@ fn main() -> ! {
         gpioc.pc15.into_pull_up_input()
     );
 
-    let rotary_b = Encoder::new(
+    let mut rotary_b = Encoder::new(
         Qei::tim3(peripherals.TIM3, (
             gpiob.pb4.into_alternate_af2(),
             gpiob.pb5.into_alternate_af2(),
@@ -101,10 +215,15 @@ fn main() -> ! {
 
     let channels = (
         gpioa.pa10.into_alternate_af1(),
-        gpioe.pe14.into_alternate_af1(), // Channel not used
+        gpioe.pe14.into_alternate_af1(), // Piezo
     );
 
-    let mut vibrator = Vibrator::new(pwm::tim1(peripherals.TIM1, channels, clocks, 500u32.hz()));
+    let (motor, speaker) = pwm::tim1(peripherals.TIM1, channels, clocks, 500u32.hz());
+    let mut vibrator = Vibrator::new(motor);
+
+    // APB2 timers run at twice pclk2 when it is divided
+    let tim1_clock = clocks.pclk2().0 * if clocks.ppre2() == 1 { 1 } else { 2 };
+    let mut buzzer = Buzzer::new(speaker, tim1_clock, 500);
 
     let mut matrix = Matrix::new(
         [
@@ -117,10 +236,30 @@ fn main() -> ! {
             gpioa.pa3.into_push_pull_output().downgrade(), 
             gpioa.pa4.into_push_pull_output().downgrade(),
             gpioa.pa5.into_push_pull_output().downgrade(),
-            gpioa.pa6.into_push_pull_output().downgrade()
+            #[cfg(not(feature = "sdcard"))]
+            gpioa.pa6.into_push_pull_output().downgrade(),
+            // PA6 is the SD card MISO on the sdcard board revision
+            #[cfg(feature = "sdcard")]
+            gpioa.pa2.into_push_pull_output().downgrade()
         ]
     );
 
+    #[cfg(feature = "sdcard")]
+    let mut sdcard = {
+        let spi = Spi::spi1(
+            peripherals.SPI1,
+            (gpiob.pb3.into_alternate_af5(), gpioa.pa6.into_alternate_af5(), gpioa.pa7.into_alternate_af5()),
+            embedded_hal::spi::MODE_0,
+            400_000.hz(),
+            clocks
+        );
+        let card = SdCard::new(spi, gpioa.pa15.into_push_pull_output());
+        if card.is_err() {
+            warn!("No SD card");
+        }
+        card.ok()
+    };
+
     let usb = USB {
         hclk: clocks.hclk(),
         usb_global: peripherals.OTG_FS_GLOBAL,
@@ -131,15 +270,22 @@ fn main() -> ! {
     };
     
     unsafe {
-        static mut USB_BUF: [u32; 32] = [0; 32];
+        // receive buffers of the control, CDC and raw HID endpoints
+        static mut USB_BUF: [u32; 64] = [0; 64];
         USB_BUS = Some(UsbBus::new(usb, &mut USB_BUF ));
         
         USB_SERIAL = Some(SerialPort::new(USB_BUS.as_ref().unwrap()));
-        USB_DEVICE = Some(UsbDeviceBuilder::new(USB_BUS.as_ref().unwrap(), UsbVidPid(0x16c0, 0x27dd))
-            .manufacturer("Lukas Sturm")
-            .product("Macro Proto")
-            .serial_number("ONE")
-            .device_class(USB_CLASS_CDC)
+        USB_HID = Some(RawHid::new(USB_BUS.as_ref().unwrap()));
+        USB_DFU = Some(DfuRuntime::new(USB_BUS.as_ref().unwrap()));
+        USB_DEVICE = Some(UsbDeviceBuilder::new(USB_BUS.as_ref().unwrap(), UsbVidPid(usb::VID, usb::PID))
+            .manufacturer(usb::MANUFACTURER)
+            .product(usb::PRODUCT)
+            .serial_number(usb::serial_number())
+            .supports_remote_wakeup(true)
+            // composite device, CDC is grouped by an interface association descriptor
+            .device_class(0xEF)
+            .device_sub_class(0x02)
+            .device_protocol(0x01)
             .build());
     }
 
@@ -149,115 +295,569 @@ fn main() -> ! {
     };
 
 
-    display.init(&mut delay).unwrap();
+    let mut storage = Storage::new(peripherals.FLASH);
+    let mut stored = storage.load().unwrap_or(Stored::default());
+    let mut layer = 0;
+
+    apply_settings(&mut vibrator, &mut buzzer, &stored.settings[stored.profile]);
+
+    // 1kHz tick, drives the clock and haptic playback
+    let mut tick_timer = Timer::tim4(peripherals.TIM4, 1.khz(), clocks);
+    tick_timer.listen(Event::TimeOut);
+
+    unsafe {
+        VIBRATOR = Some(vibrator);
+        BUZZER = Some(buzzer);
+        TICK_TIMER = Some(tick_timer);
+        stm32::NVIC::unmask(stm32f4xx_hal::stm32::Interrupt::TIM4);
+    }
+
+    let mut orientation = PROFILES[stored.profile].orientation.unwrap_or(ORIENTATION);
+    fault::set_orientation(orientation);
+    if display.init(&mut delay, orientation).is_err() {
+        fault::fatal(format_args!("Display init failed"));
+    }
+
+    // draw into RAM, DMA sends the changed rows
+    let mut framebuffer = Framebuffer::new(unsafe { &mut FRAMEBUFFER });
+    let mut panel_dma = PanelDma::new(peripherals.DMA1, orientation);
+
+    let screen = Rectangle::new(Point::zero(), Point::new(127, 127));
+    let mut legend = LegendScreen::new(screen);
+    legend.set_orientation(orientation);
+    let mut overlay = Overlay::new(screen, ui::THEME);
+    let mut console = Console::new();
+    console.register(console::COMMANDS).unwrap();
+    console.register(host::COMMANDS).unwrap();
+    console.register(stream::COMMANDS).unwrap();
+    // the same commands over raw HID, see raw_hid.rs
+    let mut hid_console = Console::new();
+    hid_console.set_echo(false);
+    hid_console.register(console::COMMANDS).unwrap();
+    hid_console.register(host::COMMANDS).unwrap();
+    hid_console.register(stream::COMMANDS).unwrap();
+    let mut dirty = DirtyRegions::new();
+
+    // logo from flash, replaced by the splash from the SD card if there is one
+    let logo = &assets::LOGO;
+    let logo_position = Point::new((128 - logo.width as i32) / 2, (128 - logo.height as i32) / 2);
+    framebuffer.fill(Rgb565::BLACK);
+    image::draw_asset(logo, &mut framebuffer, logo_position, Rgb565::CYAN).unwrap();
+
+    #[cfg(feature = "sdcard")]
+    if let Some(sdcard) = sdcard.as_mut() {
+        // a broken file may have drawn half of it
+        if sdcard.draw("SPLASH.QOI", &mut framebuffer, Point::zero(), FULL_SCREEN)
+            .or_else(| _ | sdcard.draw("SPLASH.BMP", &mut framebuffer, Point::zero(), FULL_SCREEN))
+            .is_err()
+        {
+            framebuffer.fill(Rgb565::BLACK);
+            image::draw_asset(logo, &mut framebuffer, logo_position, Rgb565::CYAN).unwrap();
+        }
+    }
 
+    dirty.add(screen);
+    panel_dma.flush(&framebuffer, &mut dirty);
+    panel_dma.wait();
+    delay.delay_ms(1500u16);
+    framebuffer.fill(Rgb565::BLACK);
+
+    let mut power = Power::new(power::TIMEOUTS, clock::now());
+    let mut screensaver = Screensaver::new();
+    panel_dma.set_contrast(power::contrast(stored.settings[stored.profile].brightness));
+    let mut brightness_changed = false;
+    let mut save_at: Option<u32> = None;
+    // for events on changes only
+    let mut last_buttons = [false, false];
+    // both encoder buttons held since, restarts into the bootloader after BOOTLOADER_HOLD
+    let mut both_held_since: Option<u32> = None;
+    let mut streamed_layer = (stored.profile, layer);
+    let mut usb_state = UsbDeviceState::Default;
+    // the image in the proto-boot slot works, see slots.rs
+    let mut boot_confirmed = false;
+
+    // profile and layer the SD card images were loaded for
+    #[cfg(feature = "sdcard")]
+    let mut loaded_images = None;
 
     loop {
+        slots::feed_watchdog();
+
+        let now = clock::now();
+        let mut settings_changed = false;
+
+        let new_usb_state = cortex_m::interrupt::free(| _ | unsafe { USB_STATE });
+        if new_usb_state != usb_state {
+            // only a configured host suspends, a charger never configures and the pad stays usable on it
+            if new_usb_state == UsbDeviceState::Suspend && usb_state == UsbDeviceState::Configured {
+                info!("USB suspended");
+                power.set_suspended(true, now);
+                cortex_m::interrupt::free(| _ | {
+                    unsafe { VIBRATOR.as_mut().unwrap() }.disable();
+                    unsafe { BUZZER.as_mut().unwrap() }.stop();
+                });
+            } else if power.is_suspended() {
+                info!("USB resumed");
+                power.set_suspended(false, now);
+            }
+            usb_state = new_usb_state;
+        }
 
         matrix.update(&mut delay);
-        vibrator.update();
 
+        let detents_a = rotary_a.detents();
+        let detents_b = rotary_b.detents();
+        // input that only wakes the display is not handled any further
+        let wake_only = (matrix.has_changes() || detents_a != 0 || detents_b != 0) && power.wake(now);
+        if detents_a != 0 || detents_b != 0 {
+            event!("encoders {=i16} {=i16}", detents_a, detents_b);
+        }
+        if detents_a != 0 {
+            stream::send(now, Event::Encoder { name: 'a', steps: detents_a });
+        }
+        if detents_b != 0 {
+            stream::send(now, Event::Encoder { name: 'b', steps: detents_b });
+        }
+
+        // wakes a suspended host
+        let mut key_pressed = false;
         cortex_m::interrupt::free(| _ | {
+            let vibrator = unsafe { VIBRATOR.as_mut().unwrap() };
+            let buzzer = unsafe { BUZZER.as_mut().unwrap() };
+            let profile = &PROFILES[stored.profile];
+
             for change in matrix.changes() {
+                event!("key {=usize} {=usize} {}", change.matrix_x, change.matrix_y, change.new_state);
+                stream::send(now, Event::Key { x: change.matrix_x, y: change.matrix_y, state: change.new_state });
+                key_pressed |= matches!(change.new_state, KeyState::Pressing);
+                if wake_only {
+                    continue;
+                }
+
+                let held = match change.new_state {
+                    KeyState::Pressing | KeyState::Pressed => true,
+                    KeyState::Releasing | KeyState::Released => false
+                };
+                legend.set_pressed(change.matrix_x, change.matrix_y, held);
 
                 match change.new_state {
                     // KeyState::Pressed => {
-                    //     serial_write(b"Pressed: ");
-                    //     serial_write(&[(0x30 + change.matrix_x) as u8, b' ', (0x30 + change.matrix_y) as u8, b'\n', b'\r']);
+                    //     trace!("Pressed: {} {}", change.matrix_x, change.matrix_y);
                     // },
                     KeyState::Pressing => {
-                        vibrator.enable(4);
+                        let action = profile.action(layer, change.matrix_x, change.matrix_y);
+
+                        if let Some(effect) = profile.haptics.for_key(change.matrix_x, change.matrix_y, action) {
+                            vibrate(vibrator, effect, now);
+                        }
+                        buzzer.play(Sound::KeyClick, now);
+
+                        match action {
+                            Action::Macro(text) => {
+                                let (x, y) = (change.matrix_x, change.matrix_y);
+                                stream::send(now, Event::MacroStart { x, y });
+                                let result = run_macro(text);
+                                stream::send(now, Event::MacroFinish { x, y, ok: result.is_ok() });
+                                if result.is_err() {
+                                    if let Some(effect) = profile.haptics.macro_failed {
+                                        vibrate(vibrator, effect, now);
+                                    }
+                                    buzzer.play(Sound::Error, now);
+                                }
+                                continue;
+                            },
+                            Action::Layer(next) => {
+                                if let Some(next_layer) = profile.layers.get(next as usize) {
+                                    layer = next as usize;
+                                    if let Some(effect) = profile.haptics.layer_change {
+                                        vibrate(vibrator, effect, now);
+                                    }
+                                    buzzer.play(Sound::LayerChange, now);
+                                    info!("Layer: {}", next_layer.name);
+                                }
+                                continue;
+                            },
+                            Action::NextProfile => {
+                                stored.profile = (stored.profile + 1) % PROFILE_COUNT;
+                                layer = 0;
+                                settings_changed = true;
+                                apply_settings(vibrator, buzzer, &stored.settings[stored.profile]);
+
+                                if let Some(effect) = PROFILES[stored.profile].haptics.profile_change {
+                                    vibrate(vibrator, effect, now);
+                                }
+                                buzzer.play(Sound::LayerChange, now);
+                                info!("Profile: {}", PROFILES[stored.profile].name);
+                                brightness_changed = true;
+                                // remaining changes belong to the old profile
+                                break;
+                            },
+                            Action::HapticMute => {
+                                let settings = &mut stored.settings[stored.profile];
+                                settings.haptic_muted = !settings.haptic_muted;
+                                settings_changed = true;
+                                apply_settings(vibrator, buzzer, settings);
+                                continue;
+                            },
+                            Action::HapticIntensity(step) => {
+                                let settings = &mut stored.settings[stored.profile];
+                                settings.haptic_intensity = (settings.haptic_intensity as i16 + step as i16).max(0).min(100) as u8;
+                                settings_changed = true;
+                                apply_settings(vibrator, buzzer, settings);
+                                vibrate(vibrator, Effect::Click, now);
+                                continue;
+                            },
+                            Action::SoundMute => {
+                                let settings = &mut stored.settings[stored.profile];
+                                settings.sound_muted = !settings.sound_muted;
+                                settings_changed = true;
+                                apply_settings(vibrator, buzzer, settings);
+                                continue;
+                            },
+                            Action::SoundVolume(step) => {
+                                let settings = &mut stored.settings[stored.profile];
+                                settings.sound_volume = (settings.sound_volume as i16 + step as i16).max(0).min(100) as u8;
+                                settings_changed = true;
+                                apply_settings(vibrator, buzzer, settings);
+                                continue;
+                            },
+                            Action::Brightness(step) => {
+                                let settings = &mut stored.settings[stored.profile];
+                                settings.brightness = (settings.brightness as i16 + step as i16).max(0).min(100) as u8;
+                                settings_changed = true;
+                                brightness_changed = true;
+                                continue;
+                            },
+                            Action::None => ()
+                        }
 
                         if change.matrix_x == 0 && change.matrix_y == 0 {
-                            serial_write(b"A: ");
-                            serial_write(&format_u32(rotary_a.count()));
-                            serial_write(b"\n\r");
+                            debug!("A: {}", rotary_a.count());
                         } else if change.matrix_x == 1 && change.matrix_y == 0 {
-                            serial_write(b"B: ");
-                            serial_write(&format_u16(rotary_b.count()));
-                            serial_write(b"\n\r");
+                            debug!("B: {}", rotary_b.count());
                         }
                         else if change.matrix_x == 2 && change.matrix_y == 2 {
-                            Circle::new(Point::new((rotary_a.count() / 4) as i32, (rotary_b.count() / 4) as i32).into(), 16)
+                            let center = Point::new((rotary_a.count() / 4) as i32, (rotary_b.count() / 4) as i32);
+                            Circle::new(center, 16)
                             .into_styled(PrimitiveStyle::with_fill(Rgb565::RED))
-                            .draw(display.get()).unwrap();
-                            serial_write(b"Circle \n\r");
+                            .draw(&mut framebuffer).unwrap();
+                            dirty.add(Rectangle::new(center - Point::new(16, 16), center + Point::new(16, 16)));
+                            debug!("Circle");
 
                         } else if change.matrix_x == 2 && change.matrix_y == 0 {
-                            display.clear();
-                            serial_write(b"Clearing\n\r");
+                            framebuffer.fill(Rgb565::BLACK);
+                            dirty.add(screen);
+                            legend.invalidate();
+                            debug!("Clearing");
 
                         } else {
-                            serial_write(b"Pressing: ");
-                            serial_write(&[(0x30 + change.matrix_x) as u8, b' ', (0x30 + change.matrix_y) as u8, b'\n', b'\r']);
+                            debug!("Pressing: {} {}", change.matrix_x, change.matrix_y);
                         }
 
                     },
                     // KeyState::Released => {
-                    //     serial_write(b"Released: ");
-                    //     serial_write(&[(0x30 + change.matrix_x) as u8, b' ', (0x30 + change.matrix_y) as u8, b'\n', b'\r']);
+                    //     trace!("Released: {} {}", change.matrix_x, change.matrix_y);
                     // },
                     // KeyState::Releasing => {
-                    //     serial_write(b"Releasing: ");
-                    //     serial_write(&[(0x30 + change.matrix_x) as u8, b' ', (0x30 + change.matrix_y) as u8, b'\n', b'\r']);
+                    //     trace!("Releasing: {} {}", change.matrix_x, change.matrix_y);
                     // }
                     _ => ()
                 }
             }
-            if rotary_a.is_pressed().unwrap() {
-                serial_write(b"A gedruckt \n\r");
+            if !wake_only && (detents_a != 0 || detents_b != 0) {
+                if let Some(effect) = PROFILES[stored.profile].haptics.encoder_detent {
+                    vibrate(vibrator, effect, now);
+                }
             }
-            if rotary_b.is_pressed().unwrap() {
-                serial_write(b"B gedruckt \n\r");
+
+            // encoder B sets the brightness, 5% per detent
+            if !wake_only && detents_b != 0 {
+                let settings = &mut stored.settings[stored.profile];
+                settings.brightness = (settings.brightness as i16 + detents_b * 5).max(0).min(100) as u8;
+                settings_changed = true;
+                brightness_changed = true;
+            }
+
+            let buttons = [rotary_a.is_pressed().unwrap(), rotary_b.is_pressed().unwrap()];
+            for (index, name) in ['a', 'b'].iter().enumerate() {
+                if buttons[index] != last_buttons[index] {
+                    debug!("{} {}", name.to_ascii_uppercase(), if buttons[index] { "gedruckt" } else { "losgelassen" });
+                    stream::send(now, Event::Button { name: *name, pressed: buttons[index] });
+                }
             }
+            last_buttons = buttons;
         });
 
-        delay.delay_ms(50u16);
+        if !(last_buttons[0] && last_buttons[1]) {
+            both_held_since = None;
+        } else if both_held_since.is_none() {
+            both_held_since = Some(now);
+        }
+        let enter_bootloader = both_held_since.map_or(false, | since | now.wrapping_sub(since) >= BOOTLOADER_HOLD);
+
+        let wakeup_allowed = cortex_m::interrupt::free(| _ | unsafe { USB_DEVICE.as_ref().unwrap().remote_wakeup_enabled() });
+        if key_pressed && power.is_suspended() && wakeup_allowed {
+            info!("Waking the host");
+            usb::remote_wakeup(&mut delay);
+        }
+
+        let mut context = Context {
+            now,
+            overlay: &mut overlay,
+            keys: matrix.get_state(),
+            encoders: [rotary_a.count(), rotary_b.count() as u32],
+            profile: stored.profile,
+            layer,
+            requests: heapless::Vec::new(),
+            live_keys: false
+        };
+        // dfu-util or the encoder buttons ask for the bootloader
+        if dfu::take_detach() || enter_bootloader {
+            if slots::SLOT.is_some() {
+                // the ROM bootloader writes the image to the start of the flash, over proto-boot
+                warn!("No ROM bootloader under proto-boot, use update");
+            } else {
+                let _ = context.requests.push(Request::Bootloader);
+            }
+        }
+        while let Some(byte) = serial::read() {
+            console.input(byte, &mut context, &mut serial::Writer);
+        }
+        console.update(matrix.get_state(), now, &mut serial::Writer);
+        while let Some(byte) = raw_hid::read() {
+            hid_console.input(byte, &mut context, &mut raw_hid::Writer);
+        }
+        hid_console.update(matrix.get_state(), now, &mut raw_hid::Writer);
+
+        for request in context.requests {
+            match request {
+                Request::Vibrate(segments) => cortex_m::interrupt::free(| _ | {
+                    if let Err(reason) = unsafe { VIBRATOR.as_mut().unwrap() }.play_segments(&segments, now) {
+                        warn!("Haptic effect: {}", reason);
+                    }
+                }),
+                Request::Layer(next) => layer = next,
+                Request::Profile(next) => {
+                    stored.profile = next;
+                    layer = 0;
+                    settings_changed = true;
+                    brightness_changed = true;
+                    cortex_m::interrupt::free(| _ | {
+                        let vibrator = unsafe { VIBRATOR.as_mut().unwrap() };
+                        let buzzer = unsafe { BUZZER.as_mut().unwrap() };
+                        apply_settings(vibrator, buzzer, &stored.settings[stored.profile]);
+                    });
+                    info!("Profile: {}", PROFILES[stored.profile].name);
+                },
+                Request::Reset | Request::Bootloader | Request::Update => {
+                    if save_at.is_some() && storage.save(&stored).is_err() {
+                        error!("Saving settings failed");
+                    }
+                    // time for the OK to go out
+                    delay.delay_ms(20u16);
+                    if let Request::Bootloader = request {
+                        info!("Restarting into the bootloader");
+                        bootloader::enter();
+                    }
+                    if let Request::Update = request {
+                        info!("Restarting into the updater");
+                        slots::request_update();
+                    }
+                    cortex_m::peripheral::SCB::sys_reset();
+                }
+            }
+        }
+        // keys, the console and profile changes all switch layers
+        if streamed_layer != (stored.profile, layer) {
+            streamed_layer = (stored.profile, layer);
+            stream::send(now, Event::Layer { layer, profile: stored.profile });
+        }
+        overlay.update(now);
+
+        // the framebuffer is read by DMA during a flush, wait with drawing until it is done
+        if !panel_dma.is_busy() {
+            let brightness = stored.settings[stored.profile].brightness;
+            let was_asleep = power.state() == power::State::Screensaver || power.state() == power::State::Off;
+
+            match power.update(now) {
+                Some(power::State::On) => {
+                    panel_dma.set_on(true);
+                    panel_dma.set_contrast(power::contrast(brightness));
+                    // the screensaver drew over everything
+                    if was_asleep {
+                        framebuffer.fill(Rgb565::BLACK);
+                        dirty.add(screen);
+                        legend.invalidate();
+                        #[cfg(feature = "sdcard")]
+                        {
+                            loaded_images = None;
+                        }
+                    }
+                },
+                Some(power::State::Dimmed) => panel_dma.set_contrast(power::dimmed_contrast(brightness)),
+                Some(power::State::Screensaver) => screensaver.start(&mut framebuffer, screen, &mut dirty, now).unwrap(),
+                Some(power::State::Off) => panel_dma.set_on(false),
+                None => ()
+            }
+
+            let wanted = PROFILES[stored.profile].orientation.unwrap_or(ORIENTATION);
+            if wanted != orientation {
+                orientation = wanted;
+                fault::set_orientation(orientation);
+                panel_dma.set_orientation(orientation);
+                legend.set_orientation(orientation);
+                // everything is in the wrong place on the panel now
+                framebuffer.fill(Rgb565::BLACK);
+                dirty.add(screen);
+                legend.invalidate();
+                #[cfg(feature = "sdcard")]
+                {
+                    loaded_images = None;
+                }
+            }
+
+            if brightness_changed && power.state() == power::State::On {
+                panel_dma.set_contrast(power::contrast(brightness));
+            }
+            brightness_changed = false;
+        }
+
+        if !panel_dma.is_busy() && power.state() == power::State::Screensaver {
+            screensaver.update(&mut framebuffer, screen, &mut dirty, now).unwrap();
+            panel_dma.flush(&framebuffer, &mut dirty);
+        } else if !panel_dma.is_busy() && power.state() != power::State::Off {
+            #[cfg(feature = "sdcard")]
+            if loaded_images != Some((stored.profile, layer)) {
+                loaded_images = Some((stored.profile, layer));
+                if let Some(sdcard) = sdcard.as_mut() {
+                    load_images(sdcard, &mut framebuffer, &mut legend, stored.profile, layer);
+                    dirty.add(screen);
+                }
+            }
+
+            legend.show(&PROFILES[stored.profile], layer);
+            overlay.render(&mut legend, &mut framebuffer, &mut dirty).unwrap();
+            panel_dma.flush(&framebuffer, &mut dirty);
+        }
+
+        // every save erases the flash sector, wait until the encoder or keys rest for a moment
+        if settings_changed {
+            save_at = Some(now.wrapping_add(SAVE_DELAY));
+        }
+        if save_at.map_or(false, | at | now.wrapping_sub(at) as i32 >= 0) {
+            save_at = None;
+            if storage.save(&stored).is_err() {
+                error!("Saving settings failed");
+            }
+        }
+
+        if !boot_confirmed && now >= slots::CONFIRM_AFTER {
+            boot_confirmed = true;
+            if slots::confirm(&mut storage).is_err() {
+                error!("Confirming the boot failed");
+            }
+        }
+
+        // nothing to show while the host sleeps, keys are only scanned for the wakeup
+        delay.delay_ms(if power.is_suspended() { 200u16 } else { 50u16 });
     }
 }
 
+/// Plays `effect`, one that can not be played is only logged
+fn vibrate<C1>(vibrator: &mut Vibrator<C1>, effect: Effect, now: u32)
+where
+    C1: embedded_hal::PwmPin<Duty=u16>
+{
+    if let Err(reason) = vibrator.play(effect, now) {
+        warn!("Haptic effect: {}", reason);
+    }
+}
 
-// fn serial_write(serial: &mut SerialPort<UsbBus<USB>, DefaultBufferStore, DefaultBufferStore>, data: &[u8]) {
-fn serial_write(data: &[u8]) {
-    let serial = unsafe { USB_SERIAL.as_mut().unwrap() };
+fn apply_settings<C1, C2>(vibrator: &mut Vibrator<C1>, buzzer: &mut Buzzer<C2>, settings: &Settings)
+where
+    C1: embedded_hal::PwmPin<Duty=u16>,
+    C2: embedded_hal::PwmPin<Duty=u16>
+{
+    vibrator.set_intensity(settings.haptic_intensity);
+    vibrator.set_muted(settings.haptic_muted);
+    buzzer.set_volume(settings.sound_volume);
+    buzzer.set_muted(settings.sound_muted);
+}
 
-    let mut write_offset = 0;
-    while write_offset < data.len() {
-        match serial.write(&data[write_offset..data.len()]) {
-            Ok(len) if len > 0 => {
-                write_offset += len;
+#[cfg(feature = "sdcard")]
+const FULL_SCREEN: ImageSize = ImageSize { width: 128, height: 128 };
+
+/// Background `BG<profile>` and key icons `P<profile>L<layer>K<y><x>` from the SD card.
+/// Missing files are fine, the legend falls back to a black background and labels.
+#[cfg(feature = "sdcard")]
+fn load_images<SPI, CS>(sdcard: &mut SdCard<SPI, CS>, framebuffer: &mut Framebuffer, legend: &mut LegendScreen, profile: usize, layer: usize)
+where
+    SPI: embedded_hal::spi::FullDuplex<u8>,
+    SPI::Error: core::fmt::Debug,
+    CS: embedded_hal::digital::v2::OutputPin
+{
+    use core::fmt::Write;
+    let mut name: heapless::String<12> = heapless::String::new();
+
+    let _ = write!(name, "BG{}.QOI", profile);
+    framebuffer.fill(Rgb565::BLACK);
+    let background = sdcard.draw(&name, framebuffer, Point::zero(), FULL_SCREEN).is_ok();
+    legend.set_transparent(background);
+
+    let icon_size = ImageSize { width: ui::ICON_SIZE as u32, height: ui::ICON_SIZE as u32 };
+    for y in 0..4 {
+        for x in 0..4 {
+            name.clear();
+            let _ = write!(name, "P{}L{}K{}{}.QOI", profile, layer, y, x);
+
+            // the legend keeps pointing at the cache, drop the old icon before overwriting it
+            legend.set_icon(x, y, None);
+            let icon = unsafe { &mut ICONS[y * 4 + x] };
+            icon.iter_mut().for_each(| byte | *byte = 0);
+            if sdcard.draw(&name, &mut ui::IconBuffer(icon), Point::zero(), icon_size).is_ok() {
+                legend.set_icon(x, y, Some(unsafe { &ICONS[y * 4 + x][..] }));
             }
-            _ => {}
         }
     }
+    legend.invalidate();
 }
 
-fn format_u16(number: u16) -> [u8; 5]{
-    let mut res = [0u8; 5];
-    let mut num = number;
+// ms
+const SAVE_DELAY: u32 = 2000;
+const BOOTLOADER_HOLD: u32 = 3000;
+
+/// Types `text` to the host, fails if no terminal has the port open
+fn run_macro(text: &str) -> Result<(), ()> {
+    let serial = unsafe { USB_SERIAL.as_mut().unwrap() };
 
-    for n in (0..5).rev() {
-        res[n] = 0x30 + (num % 10) as u8;
-        num /= 10;
-        if num == 0 { break; }
+    if !serial.dtr() {
+        return Err(());
     }
 
-    res
+    serial_write(text.as_bytes());
+    Ok(())
 }
 
-fn format_u32(number: u32) -> [u8; 10]{
-    let mut res = [0u8; 10];
-    let mut num = number;
 
-    for n in (0..10).rev() {
-        res[n] = 0x30 + (num % 10) as u8;
-        num /= 10;
-        if num == 0 { break; }
-    }
-
-    res
+/// Never waits, see serial.rs
+fn serial_write(data: &[u8]) {
+    serial::write(data);
 }
 
+#[interrupt]
+fn TIM4() {
+    let now = clock::tick();
+
+    let timer = unsafe { TICK_TIMER.as_mut().unwrap() };
+    timer.clear_interrupt(Event::TimeOut);
+
+    // the main loop only touches the vibrator inside interrupt::free
+    let vibrator = unsafe { VIBRATOR.as_mut().unwrap() };
+    vibrator.update(now);
+
+    let buzzer = unsafe { BUZZER.as_mut().unwrap() };
+    buzzer.update(now);
+}
 
 #[interrupt]
 fn OTG_FS() {
@@ -268,8 +868,27 @@ fn OTG_FS() {
 fn usb_interrupt() {
     let usb_dev = unsafe { USB_DEVICE.as_mut().unwrap() };
     let serial = unsafe { USB_SERIAL.as_mut().unwrap() };
+    let hid = unsafe { USB_HID.as_mut().unwrap() };
+    let dfu = unsafe { USB_DFU.as_mut().unwrap() };
+
+    let new_data = usb_dev.poll(&mut [serial, hid, dfu]);
+
+    let state = usb_dev.state();
+    if state != unsafe { USB_STATE } {
+        unsafe { USB_STATE = state };
+        event!("USB {=str}", match state {
+            UsbDeviceState::Default => "default",
+            UsbDeviceState::Addressed => "addressed",
+            UsbDeviceState::Configured => "configured",
+            UsbDeviceState::Suspend => "suspended"
+        });
+    }
 
-    if !usb_dev.poll(&mut [serial]) {
+    // sending is not tied to an event, whatever the host takes goes out
+    serial::drain(| data | serial.write(data).unwrap_or(0));
+    raw_hid::drain(| data | hid.send(data));
+
+    if !new_data {
         return;
     }
 
@@ -277,30 +896,21 @@ fn usb_interrupt() {
 
     match serial.read(&mut buf) {
         Ok(count) if count > 0 => {
-            // Echo back in upper case
-            for c in buf[0..count].iter_mut() {
-                if 0x61 <= *c && *c <= 0x7a {
-                    *c &= !0x20;
-                }
-            }
-
-            let mut write_offset = 0;
-            while write_offset < count {
-                match serial.write(&buf[write_offset..count]) {
-                    Ok(len) if len > 0 => {
-                        write_offset += len;
-                    }
-                    _ => {}
-                }
-            }
+            // handled in the main loop, see console.rs
+            serial::receive(&buf[0..count]);
         }
         _ => {}
     }
+
+    if let Some((payload, count)) = hid.poll_report() {
+        raw_hid::receive(&payload[..count]);
+    }
 }
 
+#[cfg(not(test))]
 #[inline(never)]
 #[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    rprintln!("{}", info);
-    loop {} // You might need a compiler fence in here.
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    rtt_target::rprintln!("{}", info);
+    fault::fatal(format_args!("{}", info))
 }
\ No newline at end of file
@@ -11,6 +11,7 @@ pub struct Matrix<R, C> {
 }
 
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum KeyState {
     Pressed,
     Released,
@@ -74,6 +75,10 @@ where
         &self.states
     }
 
+    pub fn has_changes(&self) -> bool {
+        self.states_changed.iter().any(| row | row.iter().any(| changed | *changed))
+    }
+
     pub fn changes(&mut self) -> Changes {
         Changes{
             matrix_y: 0, matrix_x: 0,
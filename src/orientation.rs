@@ -0,0 +1,76 @@
+/// Clockwise rotation of the picture on the panel
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Rotation {
+    Deg0,
+    Deg90,
+    Deg180,
+    Deg270
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Orientation {
+    pub rotation: Rotation,
+    /// flipped left to right, before rotating
+    pub mirrored: bool
+}
+
+// SSD1351 re-map register: 65k colors, COM split, C-B-A order, plus the bits below
+const REMAP_BASE: u8 = 0b0110_0100;
+const VERTICAL_INCREMENT: u8 = 1 << 0;
+const COLUMN_REMAP: u8 = 1 << 1;
+const SCAN_REVERSED: u8 = 1 << 4;
+
+impl Orientation {
+    pub const fn new(rotation: Rotation, mirrored: bool) -> Orientation {
+        Orientation { rotation, mirrored }
+    }
+
+    /// Rows of the framebuffer go to panel columns, see `PanelDma::flush`
+    pub fn transposed(&self) -> bool {
+        self.rotation == Rotation::Deg90 || self.rotation == Rotation::Deg270
+    }
+
+    /// Value for the re-map command, the panel does the rotating
+    pub fn remap(&self) -> u8 {
+        // where a framebuffer pixel ends up, as swap of x and y followed by flips
+        let (mut flip_x, mut flip_y) = match self.rotation {
+            Rotation::Deg0 => (false, false),
+            Rotation::Deg90 => (true, false),
+            Rotation::Deg180 => (true, true),
+            Rotation::Deg270 => (false, true)
+        };
+        if self.mirrored {
+            // mirroring happens before the swap
+            if self.transposed() {
+                flip_y = !flip_y;
+            } else {
+                flip_x = !flip_x;
+            }
+        }
+
+        let mut remap = REMAP_BASE;
+        if self.transposed() {
+            remap |= VERTICAL_INCREMENT;
+        }
+        if flip_x {
+            remap |= COLUMN_REMAP;
+        }
+        // the panel is mounted so reversed scan is upright, like the ssd1351 crate sets it
+        if !flip_y {
+            remap |= SCAN_REVERSED;
+        }
+        remap
+    }
+
+    /// Where key `x`, `y` of a `size` x `size` matrix is seen when keys and panel are turned together
+    pub fn key_position(&self, x: usize, y: usize, size: usize) -> (usize, usize) {
+        let last = size - 1;
+        match self.rotation {
+            Rotation::Deg0 => (x, y),
+            Rotation::Deg90 => (y, last - x),
+            Rotation::Deg180 => (last - x, last - y),
+            Rotation::Deg270 => (last - y, x)
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use core::cmp::Reverse;
+use core::fmt::Write;
+
+use embedded_graphics::{pixelcolor::Rgb565, prelude::*, primitives::Rectangle};
+use heapless::String;
+
+use crate::text::{self, Align, Layout};
+use crate::ui::{self, DirtyRegions, ProgressBar, Screen, Theme, Widget, CHAR_HEIGHT};
+
+pub const MAX_ITEMS: usize = 4;
+/// 1 bit per pixel, rows padded to whole bytes like `ui::Icon`
+pub const IMAGE_BYTES: usize = 64;
+pub const MAX_IMAGE_HEIGHT: u8 = 16;
+
+const ROW_HEIGHT: i32 = CHAR_HEIGHT + 4;
+
+pub enum Content {
+    Text(String<24>),
+    /// Two lines, wrapped and centered
+    Message(String<64>),
+    /// Number with a small bar of `value` out of `max` below it
+    Gauge { label: String<12>, value: i32, max: i32 },
+    Progress { label: String<12>, percent: u8 },
+    Image { label: String<16>, width: u8, height: u8, bits: [u8; IMAGE_BYTES] }
+}
+
+pub struct Item {
+    /// set by the host, setting an id again replaces the item
+    pub id: u8,
+    /// higher wins when there is no room for everything
+    pub priority: u8,
+    /// clock time the item disappears at
+    pub expires: Option<u32>,
+    pub content: Content
+}
+
+impl Item {
+    fn height(&self) -> i32 {
+        match &self.content {
+            Content::Image { height, .. } => ROW_HEIGHT.max(*height as i32 + 2),
+            Content::Message(_) => ROW_HEIGHT + CHAR_HEIGHT,
+            _ => ROW_HEIGHT
+        }
+    }
+}
+
+/// Status items pushed by the host, stacked on the bottom of whatever screen is shown
+pub struct Overlay {
+    bounds: Rectangle,
+    items: [Option<Item>; MAX_ITEMS],
+    /// area covered at the last render
+    area: Option<Rectangle>,
+    theme: Theme,
+    dirty: bool
+}
+
+impl Overlay {
+    pub fn new(bounds: Rectangle, theme: Theme) -> Overlay {
+        Overlay {
+            bounds,
+            items: [None, None, None, None],
+            area: None,
+            theme,
+            dirty: false
+        }
+    }
+
+    /// Adds or replaces `item`. When full it takes the place of a lower priority item
+    pub fn set(&mut self, item: Item) -> Result<(), ()> {
+        let slot = match self.items.iter().position(| slot | slot.as_ref().map_or(false, | other | other.id == item.id)) {
+            Some(slot) => slot,
+            None => match self.items.iter().position(| slot | slot.is_none()) {
+                Some(slot) => slot,
+                None => {
+                    let (slot, lowest) = self.items.iter()
+                        .enumerate()
+                        .filter_map(| (i, slot) | slot.as_ref().map(| other | (i, other.priority)))
+                        .min_by_key(| (_, priority) | *priority)
+                        .ok_or(())?;
+                    if lowest >= item.priority {
+                        return Err(());
+                    }
+                    slot
+                }
+            }
+        };
+
+        self.items[slot] = Some(item);
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: u8) {
+        for slot in self.items.iter_mut() {
+            if slot.as_ref().map_or(false, | item | item.id == id) {
+                *slot = None;
+                self.dirty = true;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.items = [None, None, None, None];
+        self.dirty = true;
+    }
+
+    /// Drops expired items
+    pub fn update(&mut self, now: u32) {
+        for slot in self.items.iter_mut() {
+            let expired = slot.as_ref()
+                .and_then(| item | item.expires)
+                .map_or(false, | expires | now.wrapping_sub(expires) as i32 >= 0);
+            if expired {
+                *slot = None;
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Items that fit into the lower half of the screen, highest priority first
+    fn visible(&self) -> ([Option<&Item>; MAX_ITEMS], i32) {
+        let mut sorted: [Option<&Item>; MAX_ITEMS] = [None; MAX_ITEMS];
+        for (i, item) in self.items.iter().enumerate() {
+            sorted[i] = item.as_ref();
+        }
+        // by id for equal priorities, so items do not swap places between renders
+        sorted.sort_unstable_by_key(| item | (item.is_none(), item.map(| item | (Reverse(item.priority), item.id))));
+
+        let mut visible: [Option<&Item>; MAX_ITEMS] = [None; MAX_ITEMS];
+        let mut used = 0;
+        for (i, item) in sorted.iter().filter_map(| item | *item).enumerate() {
+            // one line for the separator
+            if used + item.height() + 1 > ui::height(&self.bounds) / 2 {
+                break;
+            }
+            used += item.height();
+            visible[i] = Some(item);
+        }
+
+        (visible, if used > 0 { used + 1 } else { 0 })
+    }
+
+    /// Renders `screen` and the overlay on top of it.
+    /// When the overlay shrinks the screen is invalidated so it shows again where the overlay was.
+    pub fn render<S: Screen, D: DrawTarget<Rgb565>>(&mut self, screen: &mut S, target: &mut D, dirty: &mut DirtyRegions) -> Result<(), D::Error> {
+        let (visible, used) = self.visible();
+        let area = if used > 0 {
+            Some(Rectangle::new(Point::new(self.bounds.top_left.x, self.bounds.bottom_right.y - used + 1), self.bounds.bottom_right))
+        } else {
+            None
+        };
+
+        if self.dirty {
+            let shrunk = match (self.area, area) {
+                (Some(old), Some(new)) => old.top_left.y < new.top_left.y,
+                (Some(_), None) => true,
+                _ => false
+            };
+            if shrunk {
+                screen.invalidate();
+            }
+        }
+
+        screen.render(target, dirty)?;
+
+        let area = match area {
+            Some(area) => area,
+            None => {
+                self.area = None;
+                self.dirty = false;
+                return Ok(());
+            }
+        };
+
+        // the screen may have drawn over the overlay
+        let covered = dirty.regions().iter().any(| region | ui::overlaps(region, &area));
+        if self.dirty || covered {
+            self.draw(target, &visible, area)?;
+            dirty.add(area);
+        }
+
+        self.area = Some(area);
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn draw<D: DrawTarget<Rgb565>>(&self, target: &mut D, visible: &[Option<&Item>], area: Rectangle) -> Result<(), D::Error> {
+        let (separator, mut rows) = ui::split_top(area, 1);
+        ui::fill(target, separator, self.theme.accent)?;
+        ui::fill(target, rows, self.theme.background)?;
+
+        for item in visible.iter().filter_map(| item | *item) {
+            let (row, rest) = ui::split_top(rows, item.height());
+            self.draw_item(target, item, row)?;
+            rows = rest;
+        }
+        Ok(())
+    }
+
+    fn draw_item<D: DrawTarget<Rgb565>>(&self, target: &mut D, item: &Item, row: Rectangle) -> Result<(), D::Error> {
+        let (color, background) = (self.theme.foreground, self.theme.background);
+        let inner = Rectangle::new(row.top_left + Point::new(2, 0), row.bottom_right - Point::new(2, 0));
+        let text_top = inner.top_left + Point::new(0, (ui::height(&row) - CHAR_HEIGHT) / 2);
+
+        match &item.content {
+            Content::Text(line) => {
+                text::draw(target, ui::FONT, line, &inner, Layout::new(Align::Start, Align::Center, false), color)
+            },
+            Content::Message(message) => {
+                text::draw(target, ui::FONT, message, &inner, Layout::new(Align::Center, Align::Center, true), color)
+            },
+            Content::Gauge { label, value, max } => {
+                let mut number: String<12> = String::new();
+                let _ = write!(number, "{}", value);
+                let number_width = ui::text_width(&number);
+
+                ui::text(target, ui::fit(label, ui::width(&inner) - number_width - 4), inner.top_left + Point::new(0, 1), color, background)?;
+                ui::text(target, &number, Point::new(inner.bottom_right.x - number_width + 1, inner.top_left.y + 1), color, background)?;
+
+                let filled = if *max > 0 { ui::width(&inner) * (*value).max(0).min(*max) / *max } else { 0 };
+                if filled > 0 {
+                    let top = Point::new(inner.top_left.x, inner.bottom_right.y - 1);
+                    ui::fill(target, Rectangle::new(top, top + Point::new(filled - 1, 1)), self.theme.accent)?;
+                }
+                Ok(())
+            },
+            Content::Progress { label, percent } => {
+                let label = ui::fit(label, ui::width(&inner) / 2);
+                ui::text(target, label, text_top, color, background)?;
+
+                let left = inner.top_left.x + ui::text_width(label) + 4;
+                let mut bar = ProgressBar::new(
+                    Rectangle::new(Point::new(left, inner.top_left.y + 2), inner.bottom_right - Point::new(0, 2)),
+                    self.theme
+                );
+                bar.set_value(*percent);
+                bar.draw(target)
+            },
+            Content::Image { label, width, height, bits } => {
+                let stride = (*width as usize + 7) / 8;
+                let top_left = inner.top_left + Point::new(0, (ui::height(&row) - *height as i32) / 2);
+
+                target.draw_iter((0..*height as usize * *width as usize).filter_map(| i | {
+                    let (x, y) = (i % *width as usize, i / *width as usize);
+                    let set = bits.get(y * stride + x / 8).map_or(false, | byte | byte & (0x80 >> (x % 8)) != 0);
+                    Some(Pixel(top_left + Point::new(x as i32, y as i32), color)).filter(| _ | set)
+                }))?;
+
+                let left = Point::new(*width as i32 + 4, 0);
+                ui::text(target, ui::fit(label, ui::width(&inner) - left.x), text_top + left, color, background)
+            }
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use embedded_graphics::{pixelcolor::Rgb565, prelude::*, primitives::Rectangle};
+
+use crate::assets;
+use crate::image;
+use crate::ui::{self, DirtyRegions};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum State {
+    On,
+    /// lower contrast, the legend is still readable
+    Dimmed,
+    Screensaver,
+    /// panel in sleep mode, keeps its RAM
+    Off
+}
+
+/// Milliseconds without input until each state
+pub struct Timeouts {
+    pub dim: u32,
+    pub screensaver: u32,
+    pub off: u32
+}
+
+pub const TIMEOUTS: Timeouts = Timeouts {
+    dim: 30_000,
+    screensaver: 2 * 60_000,
+    off: 10 * 60_000
+};
+
+/// Idle timers against OLED burn in
+pub struct Power {
+    timeouts: Timeouts,
+    state: State,
+    last_input: u32,
+    /// the USB host sleeps, the panel stays off
+    suspended: bool
+}
+
+impl Power {
+    pub fn new(timeouts: Timeouts, now: u32) -> Power {
+        Power { timeouts, state: State::On, last_input: now, suspended: false }
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    /// The host suspended or resumed the bus. Resuming counts as input, someone woke the host
+    pub fn set_suspended(&mut self, suspended: bool, now: u32) {
+        self.suspended = suspended;
+        if !suspended {
+            self.last_input = now;
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Records key or encoder input.
+    /// True if nothing useful was on screen (screensaver or off) or the host is suspended,
+    /// then the input should only wake the display or the host
+    pub fn wake(&mut self, now: u32) -> bool {
+        self.last_input = now;
+        self.suspended || self.state == State::Screensaver || self.state == State::Off
+    }
+
+    /// The new state, if it changed since the last call
+    pub fn update(&mut self, now: u32) -> Option<State> {
+        let idle = now.wrapping_sub(self.last_input);
+
+        let state = if self.suspended || idle >= self.timeouts.off {
+            State::Off
+        } else if idle >= self.timeouts.screensaver {
+            State::Screensaver
+        } else if idle >= self.timeouts.dim {
+            State::Dimmed
+        } else {
+            State::On
+        };
+
+        if state == self.state {
+            return None;
+        }
+        self.state = state;
+        Some(state)
+    }
+}
+
+/// SSD1351 master contrast for a brightness in percent
+pub fn contrast(brightness: u8) -> u8 {
+    (brightness.min(100) as u16 * 15 / 100) as u8
+}
+
+pub fn dimmed_contrast(brightness: u8) -> u8 {
+    contrast(brightness) / 4
+}
+
+/// Logo bouncing around a black screen
+pub struct Screensaver {
+    position: Point,
+    direction: Point,
+    last_step: u32
+}
+
+// ms per pixel
+const STEP: u32 = 50;
+
+impl Screensaver {
+    pub fn new() -> Screensaver {
+        Screensaver { position: Point::zero(), direction: Point::new(1, 1), last_step: 0 }
+    }
+
+    /// Starts over on a black `bounds`
+    pub fn start<D: DrawTarget<Rgb565>>(&mut self, target: &mut D, bounds: Rectangle, dirty: &mut DirtyRegions, now: u32) -> Result<(), D::Error> {
+        ui::fill(target, bounds, Rgb565::BLACK)?;
+        dirty.add(bounds);
+
+        self.position = bounds.top_left;
+        self.last_step = now;
+        self.draw(target, dirty)
+    }
+
+    /// Moves the logo a pixel when it is time
+    pub fn update<D: DrawTarget<Rgb565>>(&mut self, target: &mut D, bounds: Rectangle, dirty: &mut DirtyRegions, now: u32) -> Result<(), D::Error> {
+        if now.wrapping_sub(self.last_step) < STEP {
+            return Ok(());
+        }
+        self.last_step = now;
+
+        let logo = &assets::LOGO;
+        let max = bounds.bottom_right - Point::new(logo.width as i32 - 1, logo.height as i32 - 1);
+
+        if self.position.x + self.direction.x < bounds.top_left.x || self.position.x + self.direction.x > max.x {
+            self.direction.x = -self.direction.x;
+        }
+        if self.position.y + self.direction.y < bounds.top_left.y || self.position.y + self.direction.y > max.y {
+            self.direction.y = -self.direction.y;
+        }
+
+        ui::fill(target, self.bounds(), Rgb565::BLACK)?;
+        dirty.add(self.bounds());
+        self.position += self.direction;
+        self.draw(target, dirty)
+    }
+
+    fn bounds(&self) -> Rectangle {
+        let logo = &assets::LOGO;
+        Rectangle::new(self.position, self.position + Point::new(logo.width as i32 - 1, logo.height as i32 - 1))
+    }
+
+    fn draw<D: DrawTarget<Rgb565>>(&self, target: &mut D, dirty: &mut DirtyRegions) -> Result<(), D::Error> {
+        target.draw_iter(image::asset_pixels(&assets::LOGO, self.position, Rgb565::CYAN))?;
+        dirty.add(self.bounds());
+        Ok(())
+    }
+}
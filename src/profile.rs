@@ -0,0 +1,231 @@
+use crate::asset::Asset;
+use crate::assets;
+use crate::haptic::Effect;
+use crate::orientation::Orientation;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum Action {
+    None,
+    /// Types the text on the serial port
+    Macro(&'static str),
+    /// Switches to a layer of the active profile
+    Layer(u8),
+    NextProfile,
+    HapticMute,
+    /// Changes the haptic intensity by the given percent
+    HapticIntensity(i8),
+    SoundMute,
+    /// Changes the buzzer volume by the given percent
+    SoundVolume(i8),
+    /// Changes the display brightness by the given percent
+    Brightness(i8)
+}
+
+static LAYER_LABELS: [&str; 8] = ["L0", "L1", "L2", "L3", "L4", "L5", "L6", "L7"];
+
+impl Action {
+    /// Short text for the key legend, fits into 5 characters
+    pub fn label(&self) -> &'static str {
+        match self {
+            Action::None => "",
+            Action::Macro(_) => "Macro",
+            Action::Layer(layer) => LAYER_LABELS.get(*layer as usize).copied().unwrap_or("L?"),
+            Action::NextProfile => "Prof",
+            Action::HapticMute => "Vib",
+            Action::HapticIntensity(step) if *step < 0 => "Vib-",
+            Action::HapticIntensity(_) => "Vib+",
+            Action::SoundMute => "Snd",
+            Action::SoundVolume(step) if *step < 0 => "Vol-",
+            Action::SoundVolume(_) => "Vol+",
+            Action::Brightness(step) if *step < 0 => "Lum-",
+            Action::Brightness(_) => "Lum+"
+        }
+    }
+
+    /// Built in icon shown instead of the label
+    pub fn icon(&self) -> Option<&'static Asset<'static>> {
+        match self {
+            Action::NextProfile => Some(&assets::PROFILE),
+            Action::HapticMute => Some(&assets::VIBRATION),
+            Action::SoundMute => Some(&assets::SPEAKER),
+            _ => None
+        }
+    }
+}
+
+pub struct Layer {
+    pub name: &'static str,
+    /// indexed like `matrix::Change`, `keys[matrix_y][matrix_x]`
+    pub keys: [[Action; 4]; 4]
+}
+
+/// Which effect is played for which event, `None` plays nothing
+pub struct HapticBindings {
+    /// Per key effect, takes precedence over the per action effects
+    pub keys: [[Option<Effect>; 4]; 4],
+    pub key: Option<Effect>,
+    pub macro_key: Option<Effect>,
+    pub layer_key: Option<Effect>,
+    pub layer_change: Option<Effect>,
+    pub profile_change: Option<Effect>,
+    pub encoder_detent: Option<Effect>,
+    pub macro_failed: Option<Effect>
+}
+
+impl HapticBindings {
+    /// Effect for pressing the key at `x`, `y` bound to `action`
+    pub fn for_key(&self, x: usize, y: usize, action: Action) -> Option<Effect> {
+        self.keys[y][x].or(match action {
+            Action::Macro(_) => self.macro_key,
+            Action::Layer(_) | Action::NextProfile => self.layer_key,
+            _ => self.key
+        })
+    }
+}
+
+pub struct Profile {
+    pub name: &'static str,
+    pub layers: &'static [Layer],
+    pub haptics: HapticBindings,
+    /// `None` keeps the board's orientation
+    pub orientation: Option<Orientation>
+}
+
+impl Profile {
+    pub fn action(&self, layer: usize, x: usize, y: usize) -> Action {
+        self.layers
+            .get(layer)
+            .map(| layer | layer.keys[y][x])
+            .unwrap_or(Action::None)
+    }
+}
+
+/// Per profile settings that are changed at runtime and kept in flash
+#[derive(Clone, Copy)]
+pub struct Settings {
+    pub haptic_intensity: u8,
+    pub haptic_muted: bool,
+    pub sound_volume: u8,
+    pub sound_muted: bool,
+    /// percent
+    pub brightness: u8
+}
+
+pub const SETTINGS_SIZE: usize = 8;
+
+impl Settings {
+    pub const fn default() -> Settings {
+        Settings {
+            haptic_intensity: 100,
+            haptic_muted: false,
+            sound_volume: 50,
+            sound_muted: false,
+            brightness: 100
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; SETTINGS_SIZE] {
+        let mut bytes = [0xFF; SETTINGS_SIZE];
+        bytes[0] = self.haptic_intensity;
+        bytes[1] = self.haptic_muted as u8;
+        bytes[2] = self.sound_volume;
+        bytes[3] = self.sound_muted as u8;
+        bytes[4] = self.brightness;
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; SETTINGS_SIZE]) -> Settings {
+        Settings {
+            haptic_intensity: bytes[0].min(100),
+            haptic_muted: bytes[1] == 1,
+            // erased bytes of older layouts read as 0xFF
+            sound_volume: if bytes[2] == 0xFF { 50 } else { bytes[2].min(100) },
+            sound_muted: bytes[3] == 1,
+            // 0xFF when erased, which is full brightness
+            brightness: bytes[4].min(100)
+        }
+    }
+}
+
+use Action::None as N;
+
+const HAPTICS: HapticBindings = HapticBindings {
+    keys: [[None; 4]; 4],
+    key: Some(Effect::Click),
+    macro_key: Some(Effect::Click),
+    layer_key: Some(Effect::DoubleClick),
+    layer_change: Some(Effect::DoubleClick),
+    profile_change: Some(Effect::Heartbeat),
+    encoder_detent: Some(Effect::Tick),
+    macro_failed: Some(Effect::Buzz)
+};
+
+pub const PROFILE_COUNT: usize = 2;
+
+pub static PROFILES: [Profile; PROFILE_COUNT] = [
+    Profile {
+        name: "Default",
+        layers: &[
+            Layer {
+                name: "Base",
+                keys: [
+                    [N, N, N, Action::Layer(1)],
+                    [N, N, N, N],
+                    [N, N, N, N],
+                    [Action::Macro("Hello from Macro Proto\n\r"), N, N, Action::NextProfile]
+                ]
+            },
+            Layer {
+                name: "Settings",
+                keys: [
+                    [N, N, N, Action::Layer(0)],
+                    [Action::HapticIntensity(-10), Action::HapticIntensity(10), Action::HapticMute, N],
+                    [Action::SoundVolume(-10), Action::SoundVolume(10), Action::SoundMute, N],
+                    [Action::Brightness(-10), Action::Brightness(10), N, Action::NextProfile]
+                ]
+            }
+        ],
+        haptics: HAPTICS,
+        orientation: None
+    },
+    Profile {
+        name: "Quiet",
+        layers: &[
+            Layer {
+                name: "Base",
+                keys: [
+                    [N, N, N, N],
+                    [N, N, N, N],
+                    [N, N, N, N],
+                    [N, N, N, Action::NextProfile]
+                ]
+            }
+        ],
+        haptics: HapticBindings {
+            key: None,
+            macro_key: None,
+            encoder_detent: None,
+            ..HAPTICS
+        },
+        orientation: None
+    }
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::haptic::MAX_SEGMENTS;
+
+    #[test]
+    fn every_effect_can_be_played() {
+        for profile in PROFILES.iter() {
+            let haptics = &profile.haptics;
+            let bound = [haptics.key, haptics.macro_key, haptics.layer_key, haptics.layer_change,
+                haptics.profile_change, haptics.encoder_detent, haptics.macro_failed];
+            for effect in haptics.keys.iter().flatten().chain(bound.iter()).flatten() {
+                assert!(effect.segments().len() <= MAX_SEGMENTS, "{}", profile.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,178 @@
+// vendor defined HID interface with 64 byte reports, carries the console like the serial port but needs no driver (WebHID, hidapi)
+use core::fmt;
+
+use heapless::spsc::Queue;
+use proto_ring::{Overflow, TxRing};
+use stm32f4xx_hal::stm32;
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::Result;
+
+pub const REPORT_LENGTH: usize = 64;
+/// Every report starts with the number of bytes used, the rest is padding
+pub const PAYLOAD_LENGTH: usize = REPORT_LENGTH - 1;
+
+/// Usage page 0xFF60, usage 0x61 like other raw HID keyboards, so host tools find the interface
+const REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF, // usage page (vendor 0xFF60)
+    0x09, 0x61,       // usage (0x61)
+    0xA1, 0x01,       // collection (application)
+    0x09, 0x62,       //   usage (0x62), to the host
+    0x15, 0x00,       //   logical minimum 0
+    0x26, 0xFF, 0x00, //   logical maximum 255
+    0x95, 0x40,       //   report count 64
+    0x75, 0x08,       //   report size 8
+    0x81, 0x02,       //   input (data, variable, absolute)
+    0x09, 0x63,       //   usage (0x63), from the host
+    0x15, 0x00,
+    0x26, 0xFF, 0x00,
+    0x95, 0x40,
+    0x75, 0x08,
+    0x91, 0x02,       //   output (data, variable, absolute)
+    0xC0              // end collection
+];
+
+const USB_CLASS_HID: u8 = 0x03;
+const DESCRIPTOR_HID: u8 = 0x21;
+const DESCRIPTOR_REPORT: u8 = 0x22;
+const REQUEST_SET_IDLE: u8 = 0x0A;
+/// ms
+const POLL_INTERVAL: u8 = 1;
+
+/// Replies waiting for the host, filled by the main loop and drained by the OTG_FS interrupt
+static mut TX: TxRing<1024> = TxRing::new(Overflow::DropOldest);
+/// Bytes from the host, filled by the OTG_FS interrupt
+static mut RECEIVED: Queue<u8, 256> = Queue::new();
+
+pub struct RawHid<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    to_host: EndpointIn<'a, B>,
+    from_host: EndpointOut<'a, B>
+}
+
+impl<'a, B: UsbBus> RawHid<'a, B> {
+    pub fn new(allocator: &'a UsbBusAllocator<B>) -> RawHid<'a, B> {
+        RawHid {
+            interface: allocator.interface(),
+            to_host: allocator.interrupt(REPORT_LENGTH as u16, POLL_INTERVAL),
+            from_host: allocator.interrupt(REPORT_LENGTH as u16, POLL_INTERVAL)
+        }
+    }
+
+    /// Sends up to `PAYLOAD_LENGTH` bytes of `data` as one report, returns how many. 0 while the last report is still waiting
+    pub fn send(&mut self, data: &[u8]) -> usize {
+        let count = data.len().min(PAYLOAD_LENGTH);
+        let mut report = [0u8; REPORT_LENGTH];
+        report[0] = count as u8;
+        report[1..=count].copy_from_slice(&data[..count]);
+
+        match self.to_host.write(&report) {
+            Ok(_) => count,
+            Err(_) => 0
+        }
+    }
+
+    /// Payload of the next report from the host, if there is one
+    pub fn poll_report(&mut self) -> Option<([u8; PAYLOAD_LENGTH], usize)> {
+        let mut report = [0u8; REPORT_LENGTH];
+        match self.from_host.read(&mut report) {
+            Ok(length) if length > 0 => {
+                let count = (report[0] as usize).min(length - 1);
+                let mut payload = [0u8; PAYLOAD_LENGTH];
+                payload[..count].copy_from_slice(&report[1..=count]);
+                Some((payload, count))
+            },
+            _ => None
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for RawHid<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        // no boot protocol
+        writer.interface(self.interface, USB_CLASS_HID, 0, 0)?;
+        let length = REPORT_DESCRIPTOR.len() as u16;
+        writer.write(DESCRIPTOR_HID, &[
+            0x11, 0x01, // HID 1.11
+            0x00,       // no country
+            0x01,       // one class descriptor
+            DESCRIPTOR_REPORT, length as u8, (length >> 8) as u8
+        ])?;
+        writer.endpoint(&self.to_host)?;
+        writer.endpoint(&self.from_host)?;
+        Ok(())
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let request = xfer.request();
+        if request.request_type != RequestType::Standard
+            || request.recipient != Recipient::Interface
+            || request.index != u8::from(self.interface) as u16
+            || request.request != Request::GET_DESCRIPTOR {
+            return;
+        }
+
+        if (request.value >> 8) as u8 == DESCRIPTOR_REPORT {
+            let _ = xfer.accept_with_static(REPORT_DESCRIPTOR);
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = xfer.request();
+        if request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.index == u8::from(self.interface) as u16
+            && request.request == REQUEST_SET_IDLE {
+            // reports are only sent when there is something to say anyway
+            let _ = xfer.accept();
+        }
+    }
+}
+
+/// Called from the OTG_FS interrupt, drops bytes when the main loop falls behind
+pub fn receive(bytes: &[u8]) {
+    let queue = unsafe { &mut RECEIVED };
+    for byte in bytes {
+        let _ = queue.enqueue(*byte);
+    }
+}
+
+/// Next received byte
+pub fn read() -> Option<u8> {
+    cortex_m::interrupt::free(| _ | unsafe { RECEIVED.dequeue() })
+}
+
+/// Queues `data` for the host, see `serial::write`
+pub fn write(data: &[u8]) {
+    cortex_m::interrupt::free(| _ | unsafe { TX.push(data) });
+    stm32::NVIC::pend(stm32::Interrupt::OTG_FS);
+}
+
+/// Queues `data` only if all of it fits without dropping older output, false if not
+pub fn try_write(data: &[u8]) -> bool {
+    let queued = cortex_m::interrupt::free(| _ | unsafe { TX.free() >= data.len() && TX.push(data) });
+    stm32::NVIC::pend(stm32::Interrupt::OTG_FS);
+    queued
+}
+
+/// `write!` to the HID interface
+pub struct Writer;
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        write(text.as_bytes());
+        Ok(())
+    }
+}
+
+/// Hands queued bytes to `send` report by report, see `serial::drain`. Only from the OTG_FS interrupt
+pub fn drain<F: FnMut(&[u8]) -> usize>(mut send: F) {
+    let tx = unsafe { &mut TX };
+    while tx.len() > 0 {
+        let sent = send(tx.peek());
+        if sent == 0 {
+            break;
+        }
+        tx.consume(sent);
+    }
+}
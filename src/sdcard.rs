@@ -0,0 +1,102 @@
+use core::fmt::Debug;
+
+use embedded_graphics::{pixelcolor::Rgb565, prelude::*};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+use embedded_sdmmc::{Controller, Directory, File, Mode, SdMmcSpi, TimeSource, Timestamp, Volume, VolumeIdx};
+
+use crate::image::{self, ImageSize, Source};
+
+/// There is no RTC, files are only read anyway
+pub struct NoClock;
+
+impl TimeSource for NoClock {
+    fn get_timestamp(&self) -> Timestamp {
+        Timestamp {
+            year_since_1970: 50,
+            zero_indexed_month: 0,
+            zero_indexed_day: 0,
+            hours: 0,
+            minutes: 0,
+            seconds: 0
+        }
+    }
+}
+
+/// FAT formatted SD card on its own SPI bus, first partition only
+pub struct SdCard<SPI, CS>
+where
+    SPI: FullDuplex<u8>,
+    SPI::Error: Debug,
+    CS: OutputPin
+{
+    controller: Controller<SdMmcSpi<SPI, CS>, NoClock>,
+    volume: Volume
+}
+
+struct FileSource<'a, SPI, CS>
+where
+    SPI: FullDuplex<u8>,
+    SPI::Error: Debug,
+    CS: OutputPin
+{
+    controller: &'a mut Controller<SdMmcSpi<SPI, CS>, NoClock>,
+    volume: &'a Volume,
+    file: &'a mut File
+}
+
+impl<'a, SPI, CS> Source for FileSource<'a, SPI, CS>
+where
+    SPI: FullDuplex<u8>,
+    SPI::Error: Debug,
+    CS: OutputPin
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, image::Error> {
+        if self.file.eof() {
+            return Ok(0);
+        }
+        self.controller.read(self.volume, self.file, buf).map_err(| _ | image::Error::Read)
+    }
+}
+
+impl<SPI, CS> SdCard<SPI, CS>
+where
+    SPI: FullDuplex<u8>,
+    SPI::Error: Debug,
+    CS: OutputPin
+{
+    /// The SPI has to run at 400kHz or less for the card to initialise
+    pub fn new(spi: SPI, cs: CS) -> Result<SdCard<SPI, CS>, ()> {
+        let mut controller = Controller::new(SdMmcSpi::new(spi, cs), NoClock);
+        controller.device().init().map_err(| _ | ())?;
+        let volume = controller.get_volume(VolumeIdx(0)).map_err(| _ | ())?;
+
+        Ok(SdCard { controller, volume })
+    }
+
+    /// Decodes the image file `name` (8.3, in the root directory) row by row into `target`
+    pub fn draw<D: DrawTarget<Rgb565>>(&mut self, name: &str, target: &mut D, origin: Point, raw_size: ImageSize) -> Result<ImageSize, image::Error> {
+        let root = self.controller.open_root_dir(&self.volume).map_err(| _ | image::Error::Read)?;
+        let result = self.draw_in(&root, name, target, origin, raw_size);
+        self.controller.close_dir(&self.volume, root);
+        result
+    }
+
+    fn draw_in<D: DrawTarget<Rgb565>>(&mut self, dir: &Directory, name: &str, target: &mut D, origin: Point, raw_size: ImageSize) -> Result<ImageSize, image::Error> {
+        let mut file = self.controller
+            .open_file_in_dir(&mut self.volume, dir, name, Mode::ReadOnly)
+            .map_err(| _ | image::Error::Read)?;
+
+        let result = {
+            let mut source = FileSource {
+                controller: &mut self.controller,
+                volume: &self.volume,
+                file: &mut file
+            };
+            image::draw_file(name, &mut source, target, origin, raw_size)
+        };
+
+        let _ = self.controller.close_file(&self.volume, file);
+        result
+    }
+}
@@ -0,0 +1,69 @@
+// USB serial, buffered both ways so neither the main loop nor the interrupt waits for the other or the host
+use core::fmt;
+
+use heapless::spsc::Queue;
+use proto_ring::{Overflow, TxRing};
+use stm32f4xx_hal::stm32;
+
+pub const OVERFLOW: Overflow = Overflow::DropOldest;
+pub const TX_LENGTH: usize = 1024;
+
+/// Bytes waiting for the host, filled by the main loop and drained by the OTG_FS interrupt
+static mut TX: TxRing<TX_LENGTH> = TxRing::new(OVERFLOW);
+/// Bytes from the host, filled by the OTG_FS interrupt
+static mut RECEIVED: Queue<u8, 256> = Queue::new();
+
+/// Called from the OTG_FS interrupt, drops bytes when the main loop falls behind
+pub fn receive(bytes: &[u8]) {
+    let queue = unsafe { &mut RECEIVED };
+    for byte in bytes {
+        let _ = queue.enqueue(*byte);
+    }
+}
+
+/// Next received byte
+pub fn read() -> Option<u8> {
+    cortex_m::interrupt::free(| _ | unsafe { RECEIVED.dequeue() })
+}
+
+/// Queues `data` and returns right away, the OTG_FS interrupt sends it when the host reads
+pub fn write(data: &[u8]) {
+    cortex_m::interrupt::free(| _ | unsafe { TX.push(data) });
+    // the interrupt only runs on USB events, start draining now
+    stm32::NVIC::pend(stm32::Interrupt::OTG_FS);
+}
+
+/// Queues `data` only if all of it fits without dropping older output, false if not
+pub fn try_write(data: &[u8]) -> bool {
+    let queued = cortex_m::interrupt::free(| _ | unsafe { TX.free() >= data.len() && TX.push(data) });
+    stm32::NVIC::pend(stm32::Interrupt::OTG_FS);
+    queued
+}
+
+/// `write!` to the serial port, never fails, see `write`
+pub struct Writer;
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        write(text.as_bytes());
+        Ok(())
+    }
+}
+
+/// Bytes dropped because the host did not read fast enough
+#[allow(dead_code)]
+pub fn dropped() -> u32 {
+    cortex_m::interrupt::free(| _ | unsafe { TX.dropped() })
+}
+
+/// Hands as much as the serial port takes to `send`, which returns how many bytes it took. Only from the OTG_FS interrupt
+pub fn drain<F: FnMut(&[u8]) -> usize>(mut send: F) {
+    let tx = unsafe { &mut TX };
+    while tx.len() > 0 {
+        let sent = send(tx.peek());
+        if sent == 0 {
+            break;
+        }
+        tx.consume(sent);
+    }
+}
@@ -0,0 +1,81 @@
+// The firmware's side of proto-boot, the A/B bootloader in proto-boot/.
+// Built with `PROTO_SLOT=a` or `b` the firmware runs from a slot and has to confirm that it works,
+// otherwise the bootloader goes back to the last confirmed image after a few resets. See proto-image/src/state.rs.
+// proto-boot starts an image on trial with the independent watchdog, the main loop has to feed it
+use cortex_m::peripheral::SCB;
+use proto_image::layout::{BACKUP_REGISTER, STATE, UPDATE_REQUEST};
+use proto_image::state::{self, BootState, Record};
+use stm32f4xx_hal::stm32;
+
+use crate::storage::Storage;
+
+include!(concat!(env!("OUT_DIR"), "/slot.rs"));
+
+/// Running this long without a panic or reset counts as a successful boot, in ms
+pub const CONFIRM_AFTER: u32 = 10_000;
+
+/// Reloads the counter of the independent watchdog
+const WATCHDOG_FEED: u32 = 0xAAAA;
+
+/// The words of both state sectors
+fn sectors() -> [&'static [u32]; 2] {
+    let words = | sector: usize | unsafe { core::slice::from_raw_parts(STATE[sector].address as *const u32, (STATE[sector].size / 4) as usize) };
+    [words(0), words(1)]
+}
+
+pub fn state() -> BootState {
+    state::load(sectors()).1
+}
+
+/// Running from a slot that did not confirm yet
+pub fn on_trial() -> bool {
+    match SLOT {
+        Some(slot) => state().trial.map_or(false, | (trial, _) | trial == slot),
+        None => false
+    }
+}
+
+/// Once per main loop, harmless when the watchdog was not started
+pub fn feed_watchdog() {
+    unsafe { (*stm32::IWDG::ptr()).kr.write(| w | w.bits(WATCHDOG_FEED)) };
+}
+
+/// Notes that the image in `SLOT` works, nothing to do if it already did or runs without proto-boot
+pub fn confirm(storage: &mut Storage) -> Result<(), ()> {
+    let slot = match SLOT {
+        Some(slot) => slot,
+        None => return Ok(())
+    };
+    let (current, state) = state::load(sectors());
+    let on_trial = state.trial.map_or(false, | (trial, _) | trial == slot);
+    if state.confirmed == Some(slot) && !on_trial {
+        return Ok(());
+    }
+
+    let record = Record::Confirmed(slot).to_word();
+    match current {
+        // a full sector is compacted by the bootloader on its next record
+        Some(sector) if state.used + 1 >= sectors()[sector].len() => Err(()),
+        Some(sector) => storage.write_word(STATE[sector].address as usize + (state.used + 1) * 4, record),
+        // no records at all, e.g. flashed with a debugger: the first sector starts with this one, if it is erased
+        None if sectors()[0][..2] == [0xFFFF_FFFF; 2] => {
+            storage.write_word(STATE[0].address as usize + 4, record)?;
+            storage.write_word(STATE[0].address as usize, state::sector_word(0))
+        },
+        None => Err(())
+    }
+}
+
+/// Resets into the updater of proto-boot, it waits for an image on the USB serial port
+pub fn request_update() -> ! {
+    unsafe {
+        // the backup domain is write protected after reset
+        let rcc = &*stm32::RCC::ptr();
+        rcc.apb1enr.modify(| _, w | w.pwren().set_bit());
+        let pwr = &*stm32::PWR::ptr();
+        pwr.cr.modify(| _, w | w.dbp().set_bit());
+
+        core::ptr::write_volatile(BACKUP_REGISTER as *mut u32, UPDATE_REQUEST);
+    }
+    SCB::sys_reset()
+}
@@ -0,0 +1,147 @@
+use core::ptr;
+
+use stm32f4xx_hal::stm32::FLASH;
+
+use crate::profile::{Settings, SETTINGS_SIZE, PROFILE_COUNT};
+
+/// Last 128K sector of the STM32F411CE, kept free of firmware by memory.x.in
+const SECTOR: u8 = 7;
+const ADDRESS: usize = 0x0806_0000;
+
+const MAGIC: [u8; 4] = *b"MPS1";
+const HEADER_SIZE: usize = 8;
+const SIZE: usize = HEADER_SIZE + PROFILE_COUNT * SETTINGS_SIZE;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+/// Everything that survives a power cycle
+#[derive(Clone, Copy)]
+pub struct Stored {
+    pub profile: usize,
+    pub settings: [Settings; PROFILE_COUNT]
+}
+
+impl Stored {
+    pub const fn default() -> Stored {
+        Stored {
+            profile: 0,
+            settings: [Settings::default(); PROFILE_COUNT]
+        }
+    }
+}
+
+pub struct Storage {
+    flash: FLASH
+}
+
+impl Storage {
+    pub fn new(flash: FLASH) -> Storage {
+        Storage { flash }
+    }
+
+    /// Reads the stored data, `None` if the sector is erased or holds an unknown layout
+    pub fn load(&self) -> Option<Stored> {
+        let bytes = unsafe { core::slice::from_raw_parts(ADDRESS as *const u8, SIZE) };
+
+        if bytes[0..4] != MAGIC {
+            return None;
+        }
+
+        let mut stored = Stored::default();
+        stored.profile = (bytes[4] as usize).min(PROFILE_COUNT - 1);
+
+        for (i, settings) in stored.settings.iter_mut().enumerate() {
+            let mut raw = [0u8; SETTINGS_SIZE];
+            let offset = HEADER_SIZE + i * SETTINGS_SIZE;
+            raw.copy_from_slice(&bytes[offset..offset + SETTINGS_SIZE]);
+            *settings = Settings::from_bytes(&raw);
+        }
+
+        Some(stored)
+    }
+
+    /// Erases the sector and writes `stored`.
+    /// Blocks for up to 2s while the 128K sector is erased, so only call it on changes.
+    pub fn save(&mut self, stored: &Stored) -> Result<(), ()> {
+        let mut bytes = [0xFFu8; SIZE];
+        bytes[0..4].copy_from_slice(&MAGIC);
+        bytes[4] = stored.profile as u8;
+
+        for (i, settings) in stored.settings.iter().enumerate() {
+            let offset = HEADER_SIZE + i * SETTINGS_SIZE;
+            bytes[offset..offset + SETTINGS_SIZE].copy_from_slice(&settings.to_bytes());
+        }
+
+        self.unlock();
+        let result = self.erase().and_then(| _ | self.program(ADDRESS, &bytes));
+        self.lock();
+
+        result
+    }
+
+    /// Programs one word outside the settings that is still erased, e.g. a boot record, see slots.rs
+    pub fn write_word(&mut self, address: usize, word: u32) -> Result<(), ()> {
+        self.unlock();
+        let result = self.wait().and_then(| _ | self.program(address, &word.to_le_bytes()));
+        self.lock();
+
+        result
+    }
+
+    fn unlock(&mut self) {
+        self.flash.keyr.write(| w | unsafe { w.key().bits(KEY1) });
+        self.flash.keyr.write(| w | unsafe { w.key().bits(KEY2) });
+    }
+
+    fn lock(&mut self) {
+        self.flash.cr.modify(| _, w | w.lock().set_bit());
+    }
+
+    fn wait(&self) -> Result<(), ()> {
+        while self.flash.sr.read().bsy().bit_is_set() {}
+
+        let sr = self.flash.sr.read();
+        let failed = sr.pgserr().bit_is_set() || sr.pgperr().bit_is_set()
+            || sr.pgaerr().bit_is_set() || sr.wrperr().bit_is_set() || sr.operr().bit_is_set();
+
+        // error flags are cleared by writing 1
+        self.flash.sr.write(| w | w
+            .pgserr().set_bit()
+            .pgperr().set_bit()
+            .pgaerr().set_bit()
+            .wrperr().set_bit()
+            .operr().set_bit()
+            .eop().set_bit()
+        );
+
+        if failed { Err(()) } else { Ok(()) }
+    }
+
+    fn erase(&mut self) -> Result<(), ()> {
+        self.wait()?;
+        self.flash.cr.modify(| _, w | unsafe { w.ser().set_bit().snb().bits(SECTOR).psize().bits(0b10) });
+        self.flash.cr.modify(| _, w | w.strt().set_bit());
+        let result = self.wait();
+        self.flash.cr.modify(| _, w | w.ser().clear_bit());
+        result
+    }
+
+    fn program(&mut self, address: usize, bytes: &[u8]) -> Result<(), ()> {
+        // byte wise (PSIZE x8), it is only a handful of bytes
+        self.flash.cr.modify(| _, w | unsafe { w.pg().set_bit().psize().bits(0b00) });
+
+        let mut result = Ok(());
+        for (i, byte) in bytes.iter().enumerate() {
+            unsafe { ptr::write_volatile((address + i) as *mut u8, *byte) };
+
+            result = self.wait();
+            if result.is_err() {
+                break;
+            }
+        }
+
+        self.flash.cr.modify(| _, w | w.pg().clear_bit());
+        result
+    }
+}
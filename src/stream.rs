@@ -0,0 +1,85 @@
+// input events as JSON lines on the serial port and raw HID, for programs on the host that react to the pad without HID
+use core::fmt::Write;
+
+use heapless::String;
+
+use crate::console::{self, Command, Context, Output};
+use crate::matrix::KeyState;
+use crate::raw_hid;
+use crate::serial;
+
+static mut ENABLED: bool = false;
+/// Records the serial port and raw HID had no room for, reported with the next one that fits
+static mut DROPPED: [u32; 2] = [0; 2];
+
+/// One record, written as a single line with the time in ms, e.g.
+/// `{"t":1234,"key":[2,1],"state":"pressing"}`
+/// `{"t":1240,"encoder":"a","steps":-1}`
+/// `{"t":1300,"button":"b","pressed":true}`
+/// `{"t":1310,"layer":1,"profile":0}`
+/// `{"t":1320,"macro":"start","key":[0,3]}`, `{"t":1321,"macro":"finish","key":[0,3],"ok":true}`
+/// Records that find the buffer full are dropped whole and counted, `{"t":1400,"dropped":3}` comes before the next one
+pub enum Event {
+    Key { x: usize, y: usize, state: KeyState },
+    Encoder { name: char, steps: i16 },
+    Button { name: char, pressed: bool },
+    Layer { layer: usize, profile: usize },
+    MacroStart { x: usize, y: usize },
+    MacroFinish { x: usize, y: usize, ok: bool }
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command { name: "stream", help: "on|off, input events as JSON lines, every line starts with {", run: stream }
+];
+
+fn stream(arguments: &str, _: &mut Context, _: Output) -> Result<(), &'static str> {
+    unsafe { ENABLED = console::on_off(arguments)? };
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    unsafe { ENABLED }
+}
+
+/// Does nothing unless streaming is on
+pub fn send(now: u32, event: Event) {
+    if !enabled() {
+        return;
+    }
+
+    let mut line: String<80> = String::new();
+    let out = &mut line;
+    let _ = write!(out, "{{\"t\":{},", now);
+    let _ = match event {
+        Event::Key { x, y, state } => write!(out, "\"key\":[{},{}],\"state\":\"{}\"", x, y, match state {
+            KeyState::Pressing => "pressing",
+            KeyState::Pressed => "pressed",
+            KeyState::Releasing => "releasing",
+            KeyState::Released => "released"
+        }),
+        Event::Encoder { name, steps } => write!(out, "\"encoder\":\"{}\",\"steps\":{}", name, steps),
+        Event::Button { name, pressed } => write!(out, "\"button\":\"{}\",\"pressed\":{}", name, pressed),
+        Event::Layer { layer, profile } => write!(out, "\"layer\":{},\"profile\":{}", layer, profile),
+        Event::MacroStart { x, y } => write!(out, "\"macro\":\"start\",\"key\":[{},{}]", x, y),
+        Event::MacroFinish { x, y, ok } => write!(out, "\"macro\":\"finish\",\"key\":[{},{}],\"ok\":{}", x, y, ok)
+    };
+    let _ = out.write_str("}\r\n");
+    queue(now, line.as_bytes(), serial::try_write, unsafe { &mut DROPPED[0] });
+    queue(now, line.as_bytes(), raw_hid::try_write, unsafe { &mut DROPPED[1] });
+}
+
+/// Writes `line` whole or not at all, the count of the ones before it that were dropped goes first
+fn queue(now: u32, line: &[u8], try_write: fn(&[u8]) -> bool, dropped: &mut u32) {
+    if *dropped > 0 {
+        let mut note: String<40> = String::new();
+        let _ = write!(note, "{{\"t\":{},\"dropped\":{}}}\r\n", now, dropped);
+        if !try_write(note.as_bytes()) {
+            *dropped += 1;
+            return;
+        }
+        *dropped = 0;
+    }
+    if !try_write(line) {
+        *dropped += 1;
+    }
+}
@@ -0,0 +1,391 @@
+// proportional bitmap fonts from build.rs, UTF-8, word wrap and alignment
+use embedded_graphics::{pixelcolor::Rgb565, prelude::*, primitives::Rectangle};
+
+/// One character, bitmap rows padded to whole bytes, MSB first
+pub struct Glyph {
+    pub code: u32,
+    /// how far the next glyph starts
+    pub advance: i8,
+    pub width: u8,
+    pub height: u8,
+    /// bounding box offset from the pen position, `y` up from the baseline to the bottom
+    pub x: i8,
+    pub y: i8,
+    /// into `Font::bitmap`
+    pub offset: u16
+}
+
+pub struct Font {
+    pub ascent: i8,
+    pub descent: i8,
+    /// index of the glyph drawn for characters the font does not have
+    pub default: usize,
+    /// sorted by code
+    pub glyphs: &'static [Glyph],
+    pub bitmap: &'static [u8]
+}
+
+impl Font {
+    pub fn line_height(&self) -> i32 {
+        (self.ascent + self.descent) as i32
+    }
+
+    pub fn glyph(&self, c: char) -> &Glyph {
+        let index = self.glyphs
+            .binary_search_by_key(&(c as u32), | glyph | glyph.code)
+            .unwrap_or(self.default);
+        &self.glyphs[index]
+    }
+
+    pub fn width(&self, text: &str) -> i32 {
+        text.chars().map(| c | self.glyph(c).advance as i32).sum()
+    }
+
+    /// Draws `text` on one line, `position` is the top left. Returns where the next character would go
+    pub fn draw<D: DrawTarget<Rgb565>>(&self, target: &mut D, text: &str, position: Point, color: Rgb565) -> Result<Point, D::Error> {
+        let mut pen = position;
+        let baseline = position.y + self.ascent as i32;
+
+        for c in text.chars() {
+            let glyph = self.glyph(c);
+            let stride = (glyph.width as usize + 7) / 8;
+            let rows = &self.bitmap[glyph.offset as usize..glyph.offset as usize + stride * glyph.height as usize];
+            let top_left = Point::new(pen.x + glyph.x as i32, baseline - glyph.y as i32 - glyph.height as i32);
+
+            target.draw_iter((0..glyph.height as usize * glyph.width as usize).filter_map(| i | {
+                let (x, y) = (i % glyph.width as usize, i / glyph.width as usize);
+                let set = rows[y * stride + x / 8] & (0x80 >> (x % 8)) != 0;
+                Some(Pixel(top_left + Point::new(x as i32, y as i32), color)).filter(| _ | set)
+            }))?;
+
+            pen.x += glyph.advance as i32;
+        }
+        Ok(pen)
+    }
+}
+
+/// Decodes UTF-8, every broken sequence becomes one U+FFFD
+pub struct Utf8Chars<'a> {
+    bytes: &'a [u8]
+}
+
+pub fn decode(bytes: &[u8]) -> Utf8Chars<'_> {
+    Utf8Chars { bytes }
+}
+
+impl<'a> Iterator for Utf8Chars<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let first = *self.bytes.first()?;
+        let length = match first {
+            0x00..=0x7F => 1,
+            0xC2..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF4 => 4,
+            _ => 0
+        };
+
+        let valid = length > 0 && self.bytes.len() >= length;
+        let decoded = if valid { core::str::from_utf8(&self.bytes[..length]).ok() } else { None };
+
+        match decoded.and_then(| text | text.chars().next()) {
+            Some(c) => {
+                self.bytes = &self.bytes[length..];
+                Some(c)
+            },
+            None => {
+                // skip the start byte and whatever continuation bytes follow it
+                let skip = 1 + self.bytes[1..].iter().take(3).take_while(| byte | *byte & 0xC0 == 0x80).count();
+                self.bytes = &self.bytes[skip..];
+                Some(char::REPLACEMENT_CHARACTER)
+            }
+        }
+    }
+}
+
+/// `bytes` as text, broken UTF-8 replaced and cut off at the capacity
+pub fn lossy<const N: usize>(bytes: &[u8]) -> heapless::String<N> {
+    let mut text = heapless::String::new();
+    for c in decode(bytes) {
+        if text.push(c).is_err() {
+            break;
+        }
+    }
+    text
+}
+
+/// Copy of `text` cut off at the capacity, never inside a character
+pub fn truncated<const N: usize>(text: &str) -> heapless::String<N> {
+    let mut result = heapless::String::new();
+    for c in text.chars() {
+        if result.push(c).is_err() {
+            break;
+        }
+    }
+    result
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Align {
+    Start,
+    Center,
+    End
+}
+
+/// How text is laid out inside a rectangle
+#[derive(Clone, Copy)]
+pub struct Layout {
+    pub horizontal: Align,
+    pub vertical: Align,
+    /// breaks at spaces, otherwise everything is on one line
+    pub wrap: bool
+}
+
+impl Layout {
+    pub const fn new(horizontal: Align, vertical: Align, wrap: bool) -> Layout {
+        Layout { horizontal, vertical, wrap }
+    }
+}
+
+const ELLIPSIS: &str = "\u{2026}";
+
+/// Longest start of `text` that is at most `width` wide
+pub fn fit<'a>(font: &Font, text: &'a str, width: i32) -> &'a str {
+    let mut used = 0;
+    for (i, c) in text.char_indices() {
+        used += font.glyph(c).advance as i32;
+        if used > width {
+            return &text[..i];
+        }
+    }
+    text
+}
+
+/// First line of `text` and the rest. Breaks after the last space that fits, or inside a word that is too long
+fn next_line<'a>(font: &Font, text: &'a str, width: i32, wrap: bool) -> (&'a str, &'a str) {
+    let segment = match text.find('\n') {
+        Some(newline) if wrap => &text[..newline],
+        _ => text
+    };
+
+    let line = fit(font, segment, width);
+    if line.len() == segment.len() {
+        let rest = &text[line.len()..];
+        return (line, rest.strip_prefix('\n').unwrap_or(rest));
+    }
+    if !wrap {
+        return (line, &text[line.len()..]);
+    }
+
+    let end = match line.rfind(' ') {
+        _ if segment[line.len()..].starts_with(' ') => line.len(),
+        Some(space) => space,
+        // not even one character fits, take it anyway so this ends
+        None if line.is_empty() => segment.chars().next().map_or(0, | c | c.len_utf8()),
+        None => line.len()
+    };
+
+    let rest = &text[end..];
+    (&text[..end], rest.strip_prefix(' ').unwrap_or(rest))
+}
+
+/// Draws `text` into `rect` with `layout`.
+/// Whatever does not fit is cut off and ends with an ellipsis, nothing is drawn outside of `rect`
+pub fn draw<D: DrawTarget<Rgb565>>(target: &mut D, font: &Font, text: &str, rect: &Rectangle, layout: Layout, color: Rgb565) -> Result<(), D::Error> {
+    let width = rect.bottom_right.x - rect.top_left.x + 1;
+    let height = rect.bottom_right.y - rect.top_left.y + 1;
+    let max_lines = (height / font.line_height()).max(1);
+
+    // count first, for the vertical alignment
+    let mut lines = 0;
+    let mut rest = text;
+    while !rest.is_empty() && lines < max_lines {
+        rest = next_line(font, rest, width, layout.wrap).1;
+        lines += 1;
+    }
+
+    let used = lines * font.line_height();
+    let mut top = rect.top_left.y + match layout.vertical {
+        Align::Start => 0,
+        Align::Center => (height - used) / 2,
+        Align::End => height - used
+    };
+
+    let mut clipped = Clipped { target, rect: *rect };
+    let mut rest = text;
+    for line_number in 0..lines {
+        let (line, next) = next_line(font, rest, width, layout.wrap);
+        rest = next;
+
+        let cut = line_number == lines - 1 && !rest.is_empty();
+        let line = if cut { fit(font, line, width - font.width(ELLIPSIS)) } else { line };
+        let line_width = font.width(line) + if cut { font.width(ELLIPSIS) } else { 0 };
+
+        let left = rect.top_left.x + match layout.horizontal {
+            Align::Start => 0,
+            Align::Center => (width - line_width) / 2,
+            Align::End => width - line_width
+        };
+
+        let pen = font.draw(&mut clipped, line, Point::new(left, top), color)?;
+        if cut {
+            font.draw(&mut clipped, ELLIPSIS, pen, color)?;
+        }
+        top += font.line_height();
+    }
+    Ok(())
+}
+
+/// Drops pixels outside of `rect`
+struct Clipped<'a, D> {
+    target: &'a mut D,
+    rect: Rectangle
+}
+
+impl<'a, D: DrawTarget<Rgb565>> DrawTarget<Rgb565> for Clipped<'a, D> {
+    type Error = D::Error;
+
+    fn draw_pixel(&mut self, pixel: Pixel<Rgb565>) -> Result<(), D::Error> {
+        let Pixel(point, _) = pixel;
+        if point.x >= self.rect.top_left.x && point.x <= self.rect.bottom_right.x
+            && point.y >= self.rect.top_left.y && point.y <= self.rect.bottom_right.y
+        {
+            self.target.draw_pixel(pixel)?;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> Size {
+        self.target.size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Line height 4: ' ' and two letters, the ellipsis and a box for everything else
+    static FONT: Font = Font {
+        ascent: 3,
+        descent: 1,
+        default: 4,
+        glyphs: &[
+            Glyph { code: ' ' as u32, advance: 2, width: 0, height: 0, x: 0, y: 0, offset: 0 },
+            Glyph { code: 'a' as u32, advance: 3, width: 2, height: 2, x: 0, y: 0, offset: 0 },
+            Glyph { code: 'b' as u32, advance: 3, width: 2, height: 3, x: 0, y: 0, offset: 2 },
+            Glyph { code: 0x2026, advance: 3, width: 3, height: 1, x: 0, y: 0, offset: 5 },
+            Glyph { code: 0xFFFD, advance: 4, width: 3, height: 3, x: 0, y: 0, offset: 6 }
+        ],
+        bitmap: &[0xC0, 0xC0, 0x80, 0x80, 0xC0, 0xA0, 0xE0, 0xA0, 0xE0]
+    };
+
+    /// Records the pixels drawn, sorted
+    struct Mock(Vec<(i32, i32)>);
+
+    impl DrawTarget<Rgb565> for Mock {
+        type Error = core::convert::Infallible;
+
+        fn draw_pixel(&mut self, Pixel(point, _): Pixel<Rgb565>) -> Result<(), Self::Error> {
+            self.0.push((point.x, point.y));
+            self.0.sort();
+            Ok(())
+        }
+
+        fn size(&self) -> Size {
+            Size::new(64, 64)
+        }
+    }
+
+    fn render(text: &str, rect: Rectangle, layout: Layout) -> Vec<(i32, i32)> {
+        let mut mock = Mock(Vec::new());
+        draw(&mut mock, &FONT, text, &rect, layout, Rgb565::WHITE).unwrap();
+        mock.0
+    }
+
+    fn rect(x0: i32, y0: i32, x1: i32, y1: i32) -> Rectangle {
+        Rectangle::new(Point::new(x0, y0), Point::new(x1, y1))
+    }
+
+    #[test]
+    fn fit_stops_before_the_edge() {
+        assert_eq!(fit(&FONT, "aab", 6), "aa");
+        assert_eq!(fit(&FONT, "aab", 9), "aab");
+        assert_eq!(fit(&FONT, "aab", 2), "");
+        assert_eq!(FONT.width("a b"), 8);
+    }
+
+    #[test]
+    fn wraps_at_the_last_space_that_fits() {
+        assert_eq!(next_line(&FONT, "aa ab b", 9, true), ("aa", "ab b"));
+        assert_eq!(next_line(&FONT, "ab b", 9, true), ("ab", "b"));
+        assert_eq!(next_line(&FONT, "b", 9, true), ("b", ""));
+        // the space right at the edge goes, not the word before it
+        assert_eq!(next_line(&FONT, "aa b", 6, true), ("aa", "b"));
+        assert_eq!(next_line(&FONT, "a\nb", 100, true), ("a", "b"));
+    }
+
+    #[test]
+    fn breaks_words_that_do_not_fit() {
+        assert_eq!(next_line(&FONT, "aaaa", 7, true), ("aa", "aa"));
+        // not even one character fits, it still moves on
+        assert_eq!(next_line(&FONT, "ab", 1, true), ("a", "b"));
+        assert_eq!(next_line(&FONT, "aaaa", 6, false), ("aa", "aa"));
+    }
+
+    #[test]
+    fn cut_off_text_ends_with_an_ellipsis() {
+        // one line of 9 pixels: "aa" and the ellipsis, "ab" is dropped
+        let pixels = render("aa ab", rect(0, 0, 8, 3), Layout::new(Align::Start, Align::Start, true));
+        assert_eq!(pixels, [(0, 1), (0, 2), (1, 1), (1, 2), (3, 1), (3, 2), (4, 1), (4, 2), (6, 2), (8, 2)]);
+    }
+
+    #[test]
+    fn nothing_outside_the_rect() {
+        // 'b' is 2 wide and 3 high, the rect only 1 by 2
+        let pixels = render("b", rect(0, 1, 0, 2), Layout::new(Align::Start, Align::Start, true));
+        assert_eq!(pixels, [(0, 1), (0, 2)]);
+    }
+
+    #[test]
+    fn aligns_inside_the_rect() {
+        // two lines would fit, the one there is goes to the bottom right
+        let pixels = render("a", rect(0, 0, 8, 7), Layout::new(Align::End, Align::End, true));
+        assert_eq!(pixels, [(6, 5), (6, 6), (7, 5), (7, 6)]);
+
+        let pixels = render("a", rect(0, 0, 8, 7), Layout::new(Align::Center, Align::Center, true));
+        assert_eq!(pixels, [(3, 3), (3, 4), (4, 3), (4, 4)]);
+    }
+
+    #[test]
+    fn missing_characters_use_the_default_glyph() {
+        assert_eq!(FONT.glyph('é').code, 0xFFFD);
+        assert_eq!(FONT.width("aé"), 7);
+
+        let boxed = [(0, 0), (0, 1), (0, 2), (1, 0), (1, 2), (2, 0), (2, 1), (2, 2)];
+        assert_eq!(render("é", rect(0, 0, 20, 3), Layout::new(Align::Start, Align::Start, false)), boxed);
+
+        // broken UTF-8 ends up as the same box
+        let text: heapless::String<8> = lossy(b"\xff");
+        assert_eq!(render(&text, rect(0, 0, 20, 3), Layout::new(Align::Start, Align::Start, false)), boxed);
+    }
+
+    #[test]
+    fn broken_utf8_becomes_replacement_characters() {
+        let chars: Vec<char> = decode(b"\xc3\xa4\xe2\x82 x\xf0").collect();
+        assert_eq!(chars, ['ä', '\u{FFFD}', ' ', 'x', '\u{FFFD}']);
+
+        // stray continuation bytes and surrogates are one replacement each
+        let chars: Vec<char> = decode(b"\x80\x80a\xed\xa0\x80").collect();
+        assert_eq!(chars, ['\u{FFFD}', 'a', '\u{FFFD}']);
+    }
+
+    #[test]
+    fn cut_at_the_capacity_between_characters() {
+        let text: heapless::String<4> = truncated("aä€");
+        assert_eq!(text.as_str(), "aä");
+        let text: heapless::String<3> = lossy(b"ab\xc3\xa4");
+        assert_eq!(text.as_str(), "ab");
+    }
+}
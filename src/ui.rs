@@ -0,0 +1,807 @@
+// widget library for the screens, see legend.rs and overlay.rs
+
+#[cfg(feature = "sdcard")]
+use embedded_graphics::pixelcolor::raw::{RawData, RawU16};
+use embedded_graphics::{
+    pixelcolor::Rgb565,
+    prelude::*,
+    primitives::Rectangle,
+    style::PrimitiveStyle,
+};
+
+use heapless::String;
+
+use crate::asset::Asset;
+use crate::fonts;
+use crate::image;
+use crate::orientation::{Orientation, Rotation};
+use crate::text::{self, Align, Font, Layout};
+
+/// Every widget uses this one
+pub const FONT: &Font = &fonts::PROTO;
+/// Line height of `FONT`, ascent plus descent
+pub const CHAR_HEIGHT: i32 = 11;
+
+const MAX_DIRTY: usize = 8;
+
+/// Key icons are square, RGB565 big endian
+pub const ICON_SIZE: usize = 20;
+pub const ICON_BYTES: usize = ICON_SIZE * ICON_SIZE * 2;
+
+#[derive(Clone, Copy)]
+pub struct Theme {
+    pub foreground: Rgb565,
+    pub background: Rgb565,
+    pub accent: Rgb565
+}
+
+pub const THEME: Theme = Theme {
+    foreground: Rgb565::WHITE,
+    background: Rgb565::BLACK,
+    accent: Rgb565::CYAN
+};
+
+pub fn overlaps(a: &Rectangle, b: &Rectangle) -> bool {
+    a.top_left.x <= b.bottom_right.x && b.top_left.x <= a.bottom_right.x
+        && a.top_left.y <= b.bottom_right.y && b.top_left.y <= a.bottom_right.y
+}
+
+fn union(a: &Rectangle, b: &Rectangle) -> Rectangle {
+    Rectangle::new(
+        Point::new(a.top_left.x.min(b.top_left.x), a.top_left.y.min(b.top_left.y)),
+        Point::new(a.bottom_right.x.max(b.bottom_right.x), a.bottom_right.y.max(b.bottom_right.y))
+    )
+}
+
+/// Areas of the screen that changed since the last flush
+pub struct DirtyRegions {
+    rects: [Rectangle; MAX_DIRTY],
+    len: usize
+}
+
+impl DirtyRegions {
+    pub fn new() -> DirtyRegions {
+        DirtyRegions {
+            rects: [Rectangle::new(Point::zero(), Point::zero()); MAX_DIRTY],
+            len: 0
+        }
+    }
+
+    /// Adds `rect`, merging it with overlapping regions.
+    /// Once full everything collapses into a single bounding box.
+    pub fn add(&mut self, rect: Rectangle) {
+        let mut rect = rect;
+
+        // merging can make the rect overlap regions it did not before, so repeat until stable
+        let mut i = 0;
+        while i < self.len {
+            if overlaps(&self.rects[i], &rect) {
+                rect = union(&self.rects[i], &rect);
+                self.len -= 1;
+                self.rects[i] = self.rects[self.len];
+                i = 0;
+            } else {
+                i += 1;
+            }
+        }
+
+        if self.len == MAX_DIRTY {
+            for other in self.rects[1..].iter() {
+                rect = union(other, &rect);
+            }
+            self.rects[0] = union(&self.rects[0], &rect);
+            self.len = 1;
+        } else {
+            self.rects[self.len] = rect;
+            self.len += 1;
+        }
+    }
+
+    pub fn regions(&self) -> &[Rectangle] {
+        &self.rects[..self.len]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+pub fn width(rect: &Rectangle) -> i32 {
+    rect.bottom_right.x - rect.top_left.x + 1
+}
+
+pub fn height(rect: &Rectangle) -> i32 {
+    rect.bottom_right.y - rect.top_left.y + 1
+}
+
+/// Splits `rect` into a `top` pixel high part and the rest below it
+pub fn split_top(rect: Rectangle, top: i32) -> (Rectangle, Rectangle) {
+    (
+        Rectangle::new(rect.top_left, Point::new(rect.bottom_right.x, rect.top_left.y + top - 1)),
+        Rectangle::new(Point::new(rect.top_left.x, rect.top_left.y + top), rect.bottom_right)
+    )
+}
+
+/// Cell `x`, `y` of `rect` divided into `columns` x `rows`, with `gap` pixels between cells
+pub fn grid_cell(rect: &Rectangle, columns: i32, rows: i32, gap: i32, x: i32, y: i32) -> Rectangle {
+    let cell_width = (width(rect) - gap * (columns - 1)) / columns;
+    let cell_height = (height(rect) - gap * (rows - 1)) / rows;
+
+    let top_left = rect.top_left + Point::new(x * (cell_width + gap), y * (cell_height + gap));
+    Rectangle::new(top_left, top_left + Point::new(cell_width - 1, cell_height - 1))
+}
+
+/// Longest prefix of `text` that fits into `width` pixels of `FONT`
+pub fn fit(text: &str, width: i32) -> &str {
+    text::fit(FONT, text, width)
+}
+
+pub fn text_width(text: &str) -> i32 {
+    FONT.width(text)
+}
+
+fn color_at(data: &[u8], index: usize) -> Rgb565 {
+    let raw = (data[index * 2] as u16) << 8 | data[index * 2 + 1] as u16;
+    Rgb565::new((raw >> 11) as u8, (raw >> 5) as u8 & 0x3F, raw as u8 & 0x1F)
+}
+
+/// RAM target for loading one key icon
+#[cfg(feature = "sdcard")]
+pub struct IconBuffer<'a>(pub &'a mut [u8; ICON_BYTES]);
+
+#[cfg(feature = "sdcard")]
+impl<'a> DrawTarget<Rgb565> for IconBuffer<'a> {
+    type Error = core::convert::Infallible;
+
+    fn draw_pixel(&mut self, Pixel(point, color): Pixel<Rgb565>) -> Result<(), Self::Error> {
+        if point.x >= 0 && point.y >= 0 && (point.x as usize) < ICON_SIZE && (point.y as usize) < ICON_SIZE {
+            let index = (point.y as usize * ICON_SIZE + point.x as usize) * 2;
+            let raw = RawU16::from(color).into_inner();
+            self.0[index] = (raw >> 8) as u8;
+            self.0[index + 1] = raw as u8;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> Size {
+        Size::new(ICON_SIZE as u32, ICON_SIZE as u32)
+    }
+}
+
+pub fn fill<D: DrawTarget<Rgb565>>(target: &mut D, rect: Rectangle, color: Rgb565) -> Result<(), D::Error> {
+    rect.into_styled(PrimitiveStyle::with_fill(color)).draw(target)
+}
+
+/// One line of text, `position` is the top left
+pub fn text<D: DrawTarget<Rgb565>>(target: &mut D, text: &str, position: Point, color: Rgb565, background: Rgb565) -> Result<(), D::Error> {
+    let size = Point::new(text_width(text).max(1) - 1, CHAR_HEIGHT - 1);
+    fill(target, Rectangle::new(position, position + size), background)?;
+    FONT.draw(target, text, position, color).map(| _ | ())
+}
+
+/// Text centered inside `rect`, wrapped at spaces and cut off with an ellipsis
+fn centered<D: DrawTarget<Rgb565>>(target: &mut D, rect: &Rectangle, label: &str, color: Rgb565) -> Result<(), D::Error> {
+    text::draw(target, FONT, label, rect, Layout::new(Align::Center, Align::Center, true), color)
+}
+
+/// Something that can be drawn and knows when it has to be redrawn
+pub trait Widget {
+    /// Everything the widget draws stays inside these bounds
+    fn bounds(&self) -> Rectangle;
+
+    fn draw<D: DrawTarget<Rgb565>>(&self, target: &mut D) -> Result<(), D::Error>;
+
+    fn is_dirty(&self) -> bool;
+
+    fn set_dirty(&mut self, dirty: bool);
+
+    /// Draws the widget if it changed and records the area in `dirty`
+    fn render<D: DrawTarget<Rgb565>>(&mut self, target: &mut D, dirty: &mut DirtyRegions) -> Result<(), D::Error> {
+        if self.is_dirty() {
+            self.draw(target)?;
+            dirty.add(self.bounds());
+            self.set_dirty(false);
+        }
+        Ok(())
+    }
+}
+
+/// A whole screen worth of widgets
+pub trait Screen {
+    /// Draws everything that changed since the last render
+    fn render<D: DrawTarget<Rgb565>>(&mut self, target: &mut D, dirty: &mut DirtyRegions) -> Result<(), D::Error>;
+
+    /// Marks every widget dirty, used when the screen gets shown
+    fn invalidate(&mut self);
+}
+
+/// One line of text
+// not on a screen yet, like `Icon` and `List`
+#[allow(dead_code)]
+pub struct Label {
+    bounds: Rectangle,
+    text: String<32>,
+    theme: Theme,
+    dirty: bool
+}
+
+#[allow(dead_code)]
+impl Label {
+    pub fn new(bounds: Rectangle, theme: Theme) -> Label {
+        Label { bounds, text: String::new(), theme, dirty: true }
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        if self.text.as_str() != text {
+            // too long text is cut off when drawing anyway
+            self.text = text::truncated(text);
+            self.dirty = true;
+        }
+    }
+}
+
+impl Widget for Label {
+    fn bounds(&self) -> Rectangle { self.bounds }
+    fn is_dirty(&self) -> bool { self.dirty }
+    fn set_dirty(&mut self, dirty: bool) { self.dirty = dirty; }
+
+    fn draw<D: DrawTarget<Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        fill(target, self.bounds, self.theme.background)?;
+        let label = fit(&self.text, width(&self.bounds));
+        text(target, label, self.bounds.top_left, self.theme.foreground, self.theme.background)
+    }
+}
+
+/// 1 bit per pixel image, rows padded to whole bytes, MSB first
+#[allow(dead_code)]
+pub struct Icon {
+    position: Point,
+    width: u32,
+    height: u32,
+    bitmap: &'static [u8],
+    color: Rgb565,
+    background: Rgb565,
+    dirty: bool
+}
+
+#[allow(dead_code)]
+impl Icon {
+    pub fn new(position: Point, width: u32, height: u32, bitmap: &'static [u8], color: Rgb565, background: Rgb565) -> Icon {
+        Icon { position, width, height, bitmap, color, background, dirty: true }
+    }
+
+    pub fn set_color(&mut self, color: Rgb565) {
+        if self.color != color {
+            self.color = color;
+            self.dirty = true;
+        }
+    }
+}
+
+impl Widget for Icon {
+    fn bounds(&self) -> Rectangle {
+        Rectangle::new(self.position, self.position + Point::new(self.width as i32 - 1, self.height as i32 - 1))
+    }
+    fn is_dirty(&self) -> bool { self.dirty }
+    fn set_dirty(&mut self, dirty: bool) { self.dirty = dirty; }
+
+    fn draw<D: DrawTarget<Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        let stride = (self.width as usize + 7) / 8;
+
+        for y in 0..self.height as usize {
+            for x in 0..self.width as usize {
+                let set = self.bitmap
+                    .get(y * stride + x / 8)
+                    .map_or(false, | byte | byte & (0x80 >> (x % 8)) != 0);
+
+                let color = if set { self.color } else { self.background };
+                target.draw_pixel(Pixel(self.position + Point::new(x as i32, y as i32), color))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct ProgressBar {
+    bounds: Rectangle,
+    /// percent
+    value: u8,
+    theme: Theme,
+    dirty: bool
+}
+
+impl ProgressBar {
+    pub fn new(bounds: Rectangle, theme: Theme) -> ProgressBar {
+        ProgressBar { bounds, value: 0, theme, dirty: true }
+    }
+
+    pub fn set_value(&mut self, value: u8) {
+        let value = value.min(100);
+        if self.value != value {
+            self.value = value;
+            self.dirty = true;
+        }
+    }
+}
+
+impl Widget for ProgressBar {
+    fn bounds(&self) -> Rectangle { self.bounds }
+    fn is_dirty(&self) -> bool { self.dirty }
+    fn set_dirty(&mut self, dirty: bool) { self.dirty = dirty; }
+
+    fn draw<D: DrawTarget<Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        self.bounds
+            .into_styled(PrimitiveStyle::with_stroke(self.theme.foreground, 1))
+            .draw(target)?;
+
+        let inner = Rectangle::new(self.bounds.top_left + Point::new(1, 1), self.bounds.bottom_right - Point::new(1, 1));
+        let filled = (width(&inner) * self.value as i32) / 100;
+
+        if filled > 0 {
+            fill(target, Rectangle::new(inner.top_left, Point::new(inner.top_left.x + filled - 1, inner.bottom_right.y)), self.theme.accent)?;
+        }
+        if filled < width(&inner) {
+            fill(target, Rectangle::new(Point::new(inner.top_left.x + filled, inner.top_left.y), inner.bottom_right), self.theme.background)?;
+        }
+        Ok(())
+    }
+}
+
+/// Vertical list of single line entries with one selected entry
+#[allow(dead_code)]
+pub struct List {
+    bounds: Rectangle,
+    items: &'static [&'static str],
+    selected: usize,
+    theme: Theme,
+    dirty: bool
+}
+
+#[allow(dead_code)]
+impl List {
+    pub fn new(bounds: Rectangle, items: &'static [&'static str], theme: Theme) -> List {
+        List { bounds, items, selected: 0, theme, dirty: true }
+    }
+
+    pub fn select(&mut self, selected: usize) {
+        let selected = selected.min(self.items.len().saturating_sub(1));
+        if self.selected != selected {
+            self.selected = selected;
+            self.dirty = true;
+        }
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+}
+
+impl Widget for List {
+    fn bounds(&self) -> Rectangle { self.bounds }
+    fn is_dirty(&self) -> bool { self.dirty }
+    fn set_dirty(&mut self, dirty: bool) { self.dirty = dirty; }
+
+    fn draw<D: DrawTarget<Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        let line_height = CHAR_HEIGHT + 2;
+        let visible = (height(&self.bounds) / line_height).max(1) as usize;
+        // keep the selection in view
+        let first = (self.selected + 1).saturating_sub(visible);
+
+        fill(target, self.bounds, self.theme.background)?;
+
+        for (line, item) in self.items.iter().enumerate().skip(first).take(visible) {
+            let top = self.bounds.top_left + Point::new(0, (line - first) as i32 * line_height);
+            let row = Rectangle::new(top, Point::new(self.bounds.bottom_right.x, top.y + line_height - 1));
+
+            let (color, background) = if line == self.selected {
+                (self.theme.background, self.theme.accent)
+            } else {
+                (self.theme.foreground, self.theme.background)
+            };
+
+            fill(target, row, background)?;
+            text(target, fit(item, width(&row) - 2), top + Point::new(1, 1), color, background)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a `KeyGrid` cell shows instead of its label
+#[derive(Clone, Copy)]
+pub enum KeyIcon {
+    /// `ICON_BYTES` of RGB565, e.g. loaded from the SD card
+    Rgb(&'static [u8]),
+    /// from flash, masks are drawn in the label color
+    Asset(&'static Asset<'static>)
+}
+
+impl PartialEq for KeyIcon {
+    /// Compares where the data is, not the pixels
+    fn eq(&self, other: &KeyIcon) -> bool {
+        match (self, other) {
+            (KeyIcon::Rgb(a), KeyIcon::Rgb(b)) => a.as_ptr() == b.as_ptr(),
+            (KeyIcon::Asset(a), KeyIcon::Asset(b)) => core::ptr::eq(*a, *b),
+            _ => false
+        }
+    }
+}
+
+/// 4x4 grid mirroring the key matrix, indexed `[y][x]` like `matrix::Change`
+pub struct KeyGrid {
+    bounds: Rectangle,
+    labels: [[&'static str; 4]; 4],
+    /// shown instead of the label
+    icons: [[Option<KeyIcon>; 4]; 4],
+    pressed: [[bool; 4]; 4],
+    /// cells that need a redraw
+    dirty_cells: [[bool; 4]; 4],
+    /// unbound cells are not drawn, so a background image stays visible
+    transparent: bool,
+    /// cells move with the rotation, so they stay where their keys are
+    orientation: Orientation,
+    theme: Theme
+}
+
+impl KeyGrid {
+    pub fn new(bounds: Rectangle, theme: Theme) -> KeyGrid {
+        KeyGrid {
+            bounds,
+            labels: [[""; 4]; 4],
+            icons: [[None; 4]; 4],
+            pressed: [[false; 4]; 4],
+            dirty_cells: [[true; 4]; 4],
+            transparent: false,
+            orientation: Orientation::new(Rotation::Deg0, false),
+            theme
+        }
+    }
+
+    pub fn set_label(&mut self, x: usize, y: usize, label: &'static str) {
+        if self.labels[y][x] != label {
+            self.labels[y][x] = label;
+            self.dirty_cells[y][x] = true;
+        }
+    }
+
+    /// RGB icons have to hold `ICON_BYTES`.
+    /// Only redraws if `icon` points somewhere else, set `None` first when reusing the same buffer
+    pub fn set_icon(&mut self, x: usize, y: usize, icon: Option<KeyIcon>) {
+        let icon = icon.filter(| icon | match icon {
+            KeyIcon::Rgb(data) => data.len() >= ICON_BYTES,
+            KeyIcon::Asset(_) => true
+        });
+        if self.icons[y][x] != icon {
+            self.icons[y][x] = icon;
+            self.dirty_cells[y][x] = true;
+        }
+    }
+
+    pub fn set_transparent(&mut self, transparent: bool) {
+        self.transparent = transparent;
+    }
+
+    pub fn set_pressed(&mut self, x: usize, y: usize, pressed: bool) {
+        if self.pressed[y][x] != pressed {
+            self.pressed[y][x] = pressed;
+            self.dirty_cells[y][x] = true;
+        }
+    }
+
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        if self.orientation != orientation {
+            self.orientation = orientation;
+            self.set_dirty(true);
+        }
+    }
+
+    /// Where key `x`, `y` is drawn
+    pub fn cell(&self, x: usize, y: usize) -> Rectangle {
+        let (column, row) = self.orientation.key_position(x, y, 4);
+        grid_cell(&self.bounds, 4, 4, 2, column as i32, row as i32)
+    }
+
+    fn draw_cell<D: DrawTarget<Rgb565>>(&self, target: &mut D, x: usize, y: usize) -> Result<(), D::Error> {
+        let cell = self.cell(x, y);
+        let icon = self.icons[y][x];
+
+        if self.transparent && icon.is_none() && self.labels[y][x].is_empty() {
+            return Ok(());
+        }
+
+        let (color, background) = if self.pressed[y][x] {
+            (self.theme.background, self.theme.accent)
+        } else {
+            (self.theme.foreground, self.theme.background)
+        };
+
+        fill(target, cell, background)?;
+        cell.into_styled(PrimitiveStyle::with_stroke(self.theme.accent, 1)).draw(target)?;
+
+        match icon {
+            Some(KeyIcon::Rgb(icon)) => {
+                let size = ICON_SIZE as i32;
+                let top_left = cell.top_left + Point::new((width(&cell) - size) / 2, (height(&cell) - size) / 2);
+                target.draw_iter((0..ICON_SIZE * ICON_SIZE).map(| i | {
+                    Pixel(top_left + Point::new((i % ICON_SIZE) as i32, (i / ICON_SIZE) as i32), color_at(icon, i))
+                }))
+            },
+            Some(KeyIcon::Asset(asset)) => {
+                let offset = Point::new((width(&cell) - asset.width as i32) / 2, (height(&cell) - asset.height as i32) / 2);
+                target.draw_iter(image::asset_pixels(asset, cell.top_left + offset, color))
+            },
+            None => {
+                let inside = Rectangle::new(cell.top_left + Point::new(1, 1), cell.bottom_right - Point::new(1, 1));
+                centered(target, &inside, self.labels[y][x], color)
+            }
+        }
+    }
+}
+
+impl Widget for KeyGrid {
+    fn bounds(&self) -> Rectangle { self.bounds }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty_cells.iter().any(| row | row.iter().any(| dirty | *dirty))
+    }
+
+    fn set_dirty(&mut self, dirty: bool) {
+        self.dirty_cells = [[dirty; 4]; 4];
+    }
+
+    fn draw<D: DrawTarget<Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        if !self.transparent {
+            fill(target, self.bounds, self.theme.background)?;
+        }
+        for y in 0..4 {
+            for x in 0..4 {
+                self.draw_cell(target, x, y)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Only redraws the cells that changed
+    fn render<D: DrawTarget<Rgb565>>(&mut self, target: &mut D, dirty: &mut DirtyRegions) -> Result<(), D::Error> {
+        for y in 0..4 {
+            for x in 0..4 {
+                if self.dirty_cells[y][x] {
+                    self.draw_cell(target, x, y)?;
+                    dirty.add(self.cell(x, y));
+                    self.dirty_cells[y][x] = false;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One line bar with text on the left and right
+pub struct StatusBar {
+    bounds: Rectangle,
+    left: String<21>,
+    right: String<21>,
+    theme: Theme,
+    dirty: bool
+}
+
+impl StatusBar {
+    pub const HEIGHT: i32 = CHAR_HEIGHT + 4;
+
+    pub fn new(bounds: Rectangle, theme: Theme) -> StatusBar {
+        StatusBar { bounds, left: String::new(), right: String::new(), theme, dirty: true }
+    }
+
+    pub fn set(&mut self, left: &str, right: &str) {
+        if self.left.as_str() != left || self.right.as_str() != right {
+            self.left = text::truncated(left);
+            self.right = text::truncated(right);
+            self.dirty = true;
+        }
+    }
+}
+
+impl Widget for StatusBar {
+    fn bounds(&self) -> Rectangle { self.bounds }
+    fn is_dirty(&self) -> bool { self.dirty }
+    fn set_dirty(&mut self, dirty: bool) { self.dirty = dirty; }
+
+    fn draw<D: DrawTarget<Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        fill(target, self.bounds, self.theme.accent)?;
+
+        let top = self.bounds.top_left + Point::new(2, (height(&self.bounds) - CHAR_HEIGHT) / 2);
+        let right = fit(&self.right, width(&self.bounds) / 2);
+        let right_width = text_width(right);
+        let left = fit(&self.left, width(&self.bounds) - right_width - 8);
+
+        text(target, left, top, self.theme.background, self.theme.accent)?;
+        text(target, right, Point::new(self.bounds.bottom_right.x - 1 - right_width, top.y), self.theme.background, self.theme.accent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_graphics::mock_display::MockDisplay;
+
+    fn rect(x0: i32, y0: i32, x1: i32, y1: i32) -> Rectangle {
+        Rectangle::new(Point::new(x0, y0), Point::new(x1, y1))
+    }
+
+    #[test]
+    fn separate_regions_stay_apart() {
+        let mut dirty = DirtyRegions::new();
+        assert!(dirty.is_empty());
+        dirty.add(rect(0, 0, 9, 9));
+        dirty.add(rect(20, 0, 29, 9));
+        assert_eq!(dirty.regions(), [rect(0, 0, 9, 9), rect(20, 0, 29, 9)]);
+
+        dirty.clear();
+        assert!(dirty.is_empty());
+    }
+
+    #[test]
+    fn overlapping_regions_merge() {
+        let mut dirty = DirtyRegions::new();
+        dirty.add(rect(0, 0, 9, 9));
+        dirty.add(rect(5, 5, 14, 14));
+        assert_eq!(dirty.regions(), [rect(0, 0, 14, 14)]);
+        // touching edges count as overlap
+        dirty.add(rect(14, 0, 20, 2));
+        assert_eq!(dirty.regions(), [rect(0, 0, 20, 14)]);
+    }
+
+    #[test]
+    fn merging_pulls_in_regions_that_overlap_the_union() {
+        let mut dirty = DirtyRegions::new();
+        dirty.add(rect(0, 0, 4, 4));
+        dirty.add(rect(10, 0, 14, 4));
+        dirty.add(rect(30, 30, 34, 34));
+        // bridges the first two only
+        dirty.add(rect(3, 2, 11, 3));
+        assert_eq!(dirty.regions().len(), 2);
+        assert!(dirty.regions().contains(&rect(0, 0, 14, 4)));
+        assert!(dirty.regions().contains(&rect(30, 30, 34, 34)));
+    }
+
+    #[test]
+    fn collapses_into_one_box_when_full() {
+        let mut dirty = DirtyRegions::new();
+        for i in 0..MAX_DIRTY as i32 {
+            dirty.add(rect(i * 10, i * 10, i * 10 + 1, i * 10 + 1));
+        }
+        assert_eq!(dirty.regions().len(), MAX_DIRTY);
+
+        dirty.add(rect(100, 0, 101, 1));
+        assert_eq!(dirty.regions(), [rect(0, 0, 101, 71)]);
+    }
+
+    #[test]
+    fn grid_cells_with_gaps() {
+        let bounds = rect(0, 16, 127, 127);
+        // 30 x 26 pixels, the remainder stays unused on the right and bottom
+        assert_eq!(grid_cell(&bounds, 4, 4, 2, 0, 0), rect(0, 16, 29, 41));
+        assert_eq!(grid_cell(&bounds, 4, 4, 2, 1, 0), rect(32, 16, 61, 41));
+        assert_eq!(grid_cell(&bounds, 4, 4, 2, 3, 3), rect(96, 100, 125, 125));
+        assert_eq!(grid_cell(&rect(10, 10, 19, 19), 1, 1, 2, 0, 0), rect(10, 10, 19, 19));
+    }
+
+    #[test]
+    fn split_top_covers_the_rect() {
+        let (top, rest) = split_top(rect(0, 0, 63, 63), 15);
+        assert_eq!(top, rect(0, 0, 63, 14));
+        assert_eq!(rest, rect(0, 15, 63, 63));
+    }
+
+    #[test]
+    fn widgets_render_only_when_dirty() {
+        let mut display = MockDisplay::new();
+        let mut dirty = DirtyRegions::new();
+        let mut bar = ProgressBar::new(rect(0, 0, 21, 3), THEME);
+        bar.set_value(50);
+
+        bar.render(&mut display, &mut dirty).unwrap();
+        assert_eq!(dirty.regions(), [rect(0, 0, 21, 3)]);
+        assert!(!bar.is_dirty());
+        assert_eq!(display.get_pixel(Point::new(0, 0)), Some(THEME.foreground));
+        assert_eq!(display.get_pixel(Point::new(10, 1)), Some(THEME.accent));
+        assert_eq!(display.get_pixel(Point::new(11, 1)), Some(THEME.background));
+
+        let mut display = MockDisplay::new();
+        dirty.clear();
+        bar.set_value(50);
+        bar.render(&mut display, &mut dirty).unwrap();
+        assert!(dirty.is_empty());
+        assert!((0..22).all(| x | (0..4).all(| y | display.get_pixel(Point::new(x, y)).is_none())));
+
+        bar.set_value(200);
+        bar.render(&mut display, &mut dirty).unwrap();
+        assert_eq!(display.get_pixel(Point::new(20, 2)), Some(THEME.accent));
+    }
+
+    #[test]
+    fn label_redraws_when_the_text_changes() {
+        let mut dirty = DirtyRegions::new();
+        let mut label = Label::new(rect(0, 0, 39, CHAR_HEIGHT - 1), THEME);
+        label.set_text("Hi");
+
+        let mut display = MockDisplay::new();
+        label.render(&mut display, &mut dirty).unwrap();
+        assert_eq!(dirty.regions(), [rect(0, 0, 39, CHAR_HEIGHT - 1)]);
+        let pixels: Vec<Option<Rgb565>> = (0..40).flat_map(| x | (0..CHAR_HEIGHT).map(move | y | Point::new(x, y)))
+            .map(| point | display.get_pixel(point))
+            .collect();
+        assert!(pixels.iter().all(| pixel | pixel.is_some()));
+        assert!(pixels.contains(&Some(THEME.foreground)));
+        assert_eq!(display.get_pixel(Point::new(39, 0)), Some(THEME.background));
+
+        label.set_text("Hi");
+        assert!(!label.is_dirty());
+        label.set_text("Ho");
+        assert!(label.is_dirty());
+    }
+
+    #[test]
+    fn icon_draws_its_bitmap() {
+        static BITS: [u8; 2] = [0b1010_0000, 0b0100_0000];
+        let mut icon = Icon::new(Point::new(5, 5), 3, 2, &BITS, THEME.accent, THEME.background);
+        assert_eq!(icon.bounds(), rect(5, 5, 7, 6));
+
+        let mut display = MockDisplay::new();
+        let mut dirty = DirtyRegions::new();
+        icon.render(&mut display, &mut dirty).unwrap();
+        let (on, off) = (Some(THEME.accent), Some(THEME.background));
+        let rows: Vec<Vec<Option<Rgb565>>> = (5..7).map(| y | (5..8).map(| x | display.get_pixel(Point::new(x, y))).collect()).collect();
+        assert_eq!(rows, [[on, off, on], [off, on, off]]);
+        assert_eq!(display.get_pixel(Point::new(8, 5)), None);
+
+        icon.set_color(THEME.accent);
+        assert!(!icon.is_dirty());
+        icon.set_color(THEME.foreground);
+        assert!(icon.is_dirty());
+    }
+
+    #[test]
+    fn list_keeps_the_selection_in_view() {
+        static ITEMS: [&str; 4] = ["one", "two", "three", "four"];
+        // two lines of CHAR_HEIGHT + 2
+        let mut list = List::new(rect(0, 0, 49, 25), &ITEMS, THEME);
+        let mut dirty = DirtyRegions::new();
+
+        let mut display = MockDisplay::new();
+        list.render(&mut display, &mut dirty).unwrap();
+        assert_eq!(display.get_pixel(Point::new(49, 0)), Some(THEME.accent));
+        assert_eq!(display.get_pixel(Point::new(49, 13)), Some(THEME.background));
+
+        // "three" and "four" are shown, the second line is selected
+        list.select(3);
+        let mut display = MockDisplay::new();
+        list.render(&mut display, &mut dirty).unwrap();
+        assert_eq!(display.get_pixel(Point::new(49, 0)), Some(THEME.background));
+        assert_eq!(display.get_pixel(Point::new(49, 13)), Some(THEME.accent));
+
+        list.select(10);
+        assert_eq!(list.selected(), 3);
+        assert!(!list.is_dirty());
+    }
+
+    #[test]
+    fn key_grid_redraws_changed_cells_only() {
+        let mut display = MockDisplay::new();
+        let mut dirty = DirtyRegions::new();
+        let mut grid = KeyGrid::new(rect(0, 0, 63, 63), THEME);
+        grid.render(&mut display, &mut dirty).unwrap();
+        assert!(!grid.is_dirty() && !dirty.is_empty());
+
+        dirty.clear();
+        grid.set_pressed(1, 2, true);
+        grid.set_label(1, 2, "");
+        grid.render(&mut display, &mut dirty).unwrap();
+        assert_eq!(dirty.regions(), [grid.cell(1, 2)]);
+        assert_eq!(grid.cell(1, 2), rect(16, 32, 29, 45));
+        assert_eq!(display.get_pixel(Point::new(20, 40)), Some(THEME.accent));
+    }
+}
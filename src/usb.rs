@@ -0,0 +1,41 @@
+// USB identity: ids and strings set at build time, see build.rs, the serial number from the chip. And remote wakeup
+use embedded_hal::blocking::delay::DelayMs;
+
+include!(concat!(env!("OUT_DIR"), "/usb.rs"));
+
+/// 96 bit unique ID of the STM32F411
+const UID: u32 = 0x1FFF_7A10;
+/// OTG_FS device control and power/clock gating registers, the HAL driver has no remote wakeup
+const DCTL: *mut u32 = 0x5000_0804 as *mut u32;
+const PCGCCTL: *mut u32 = 0x5000_0E00 as *mut u32;
+const DCTL_RWUSIG: u32 = 1 << 0;
+const PCGCCTL_STOP_CLOCKS: u32 = 0b11;
+
+static mut SERIAL_NUMBER: [u8; 24] = [0; 24];
+
+/// The unique ID as 24 hex digits, different on every pad
+pub fn serial_number() -> &'static str {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+    let serial = unsafe { &mut SERIAL_NUMBER };
+    for word in 0..3 {
+        let id = unsafe { core::ptr::read_volatile((UID + word * 4) as *const u32) };
+        for digit in 0..8 {
+            serial[word as usize * 8 + digit] = DIGITS[(id >> (28 - digit * 4)) as usize & 0xF];
+        }
+    }
+    core::str::from_utf8(serial).unwrap()
+}
+
+/// Signals resume to a suspended host. Only when the host enabled remote wakeup, see `UsbDevice::remote_wakeup_enabled`
+pub fn remote_wakeup<D: DelayMs<u8>>(delay: &mut D) {
+    unsafe {
+        let clocks = core::ptr::read_volatile(PCGCCTL);
+        core::ptr::write_volatile(PCGCCTL, clocks & !PCGCCTL_STOP_CLOCKS);
+
+        // the spec asks for 1 to 15 ms of resume signalling
+        cortex_m::interrupt::free(| _ | core::ptr::write_volatile(DCTL, core::ptr::read_volatile(DCTL) | DCTL_RWUSIG));
+        delay.delay_ms(10);
+        cortex_m::interrupt::free(| _ | core::ptr::write_volatile(DCTL, core::ptr::read_volatile(DCTL) & !DCTL_RWUSIG));
+    }
+}
@@ -1,67 +1,350 @@
 use embedded_hal::PwmPin;
-use rtt_target::rprintln;
 
-pub struct Vibrator<C1> 
-where 
+use crate::haptic::{Effect, Player, Segment};
+
+enum State {
+    Idle,
+    /// Full strength until `deadline`
+    Pulse { deadline: u32 },
+    Effect(Player)
+}
+
+pub struct Vibrator<C1>
+where
     C1: PwmPin<Duty=u16>
 {
     motor: C1,
-    rumbling: bool,
-    cycles: u16,
-    max_duty: u16
+    state: State,
+    intensity: u8,
+    muted: bool
+}
+
+/// `now` reached `deadline`, works across tick overflow
+fn passed(now: u32, deadline: u32) -> bool {
+    (now.wrapping_sub(deadline) as i32) >= 0
 }
 
-impl<C1> Vibrator<C1> 
+impl<C1> Vibrator<C1>
 where
     C1: PwmPin<Duty=u16>
 {
-    
-    pub fn new<C2: PwmPin<Duty=u16>>(motors: (C1, C2)) -> Vibrator<C1> {
-        let (mut motor, mut ground) = motors;
 
-        ground.set_duty(0);
-        motor.set_duty(motor.get_max_duty());
-        
+    pub fn new(mut motor: C1) -> Vibrator<C1> {
+        motor.set_duty(0);
+        motor.disable();
+
         Vibrator {
-            max_duty: motor.get_max_duty(), // cache
             motor,
-            rumbling: false,
-            cycles: 0
+            state: State::Idle,
+            intensity: 100,
+            muted: false
         }
     }
 
+    #[allow(dead_code)]
+    pub fn is_rumbling(&self) -> bool {
+        match self.state {
+            State::Idle => false,
+            _ => true
+        }
+    }
+
+    /// Scales every effect, in percent
+    pub fn set_intensity(&mut self, intensity: u8) {
+        self.intensity = intensity.min(100);
+    }
+
+    /// While muted nothing new starts playing
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+        if muted {
+            self.disable();
+        }
+    }
+
+    /// Stops the motor, whatever it is doing
     pub fn disable(&mut self) {
-        if self.rumbling {
-            self.motor.disable();
-            self.rumbling = false;
+        self.state = State::Idle;
+        self.motor.set_duty(0);
+        self.motor.disable();
+    }
+
+    /// Rumbles at full strength for `duration` ms.
+    /// Retriggering while a pulse is running keeps the later of both deadlines.
+    pub fn enable(&mut self, duration: u16, now: u32) {
+        if duration == 0 || self.muted {
+            return;
         }
+
+        let mut deadline = now.wrapping_add(duration as u32);
+        if let State::Pulse { deadline: running } = self.state {
+            if passed(running, deadline) {
+                deadline = running;
+            }
+        }
+
+        self.state = State::Pulse { deadline };
+        self.drive(100);
     }
 
-    // TODO: sometimes does not stop?
-    pub fn update(&mut self) {
-        if self.rumbling {
-            rprintln!("u");
+    /// Starts playing `effect`, replacing whatever is currently playing.
+    /// A single full strength segment, like a key click, is a pulse, see `enable`.
+    /// Fails for custom effects longer than `MAX_SEGMENTS`, then the running one goes on
+    pub fn play(&mut self, effect: Effect, now: u32) -> Result<(), &'static str> {
+        if self.muted {
+            return Ok(());
+        }
+
+        event!("haptic {}", effect);
+        self.start(effect.segments(), now)
+    }
+
+    /// `play` for segments that are not an `Effect`, e.g. a pattern from the console
+    pub fn play_segments(&mut self, segments: &[Segment], now: u32) -> Result<(), &'static str> {
+        if self.muted {
+            return Ok(());
+        }
 
-            self.cycles -= 1;
+        event!("haptic {}", segments);
+        self.start(segments, now)
+    }
 
-            if self.cycles == 0 {
-                rprintln!("done");
-                self.motor.disable();
-                self.rumbling = false;
-            } else {
-                self.motor.set_duty(self.max_duty - self.max_duty / self.cycles);
+    fn start(&mut self, segments: &[Segment], now: u32) -> Result<(), &'static str> {
+        if let [segment] = segments {
+            if segment.duty >= 100 {
+                self.enable(segment.duration, now);
+                return Ok(());
             }
         }
+        self.state = State::Effect(Player::new(segments, now)?);
+        self.update(now);
+        Ok(())
+    }
+
+    /// Advances playback, runs every millisecond from the TIM4 tick interrupt
+    pub fn update(&mut self, now: u32) {
+        let duty = match &mut self.state {
+            State::Idle => return,
+            State::Pulse { deadline } if passed(now, *deadline) => None,
+            State::Pulse { .. } => Some(100),
+            State::Effect(player) => player.update(now)
+        };
+
+        match duty {
+            Some(duty) => self.drive(duty),
+            None => self.disable()
+        }
+    }
+
+    fn drive(&mut self, duty: u8) {
+        // always (re)enable, the channel might have been disabled by a previous stop
+        // max duty is not cached, the buzzer changes the shared timer frequency
+        let duty = duty as u32 * self.intensity as u32 / 100;
+        self.motor.set_duty((self.motor.get_max_duty() as u32 * duty / 100) as u16);
+        self.motor.enable();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Remembers every duty it was set to
+    struct FakePin {
+        duties: Vec<u16>,
+        enabled: bool
+    }
+
+    impl PwmPin for FakePin {
+        type Duty = u16;
+
+        fn disable(&mut self) {
+            self.enabled = false;
+        }
+
+        fn enable(&mut self) {
+            self.enabled = true;
+        }
+
+        fn get_duty(&self) -> u16 {
+            *self.duties.last().unwrap_or(&0)
+        }
+
+        fn get_max_duty(&self) -> u16 {
+            1000
+        }
+
+        fn set_duty(&mut self, duty: u16) {
+            self.duties.push(duty);
+        }
     }
 
-    pub fn enable(&mut self, cycles: u16) {
-        self.cycles = cycles;
-        rprintln!("{}", cycles);
+    fn vibrator() -> Vibrator<FakePin> {
+        let mut vibrator = Vibrator::new(FakePin { duties: Vec::new(), enabled: true });
+        vibrator.motor.duties.clear();
+        vibrator
+    }
 
-        if !self.rumbling {
-            self.motor.set_duty(self.max_duty);
-            self.motor.enable();
-            self.rumbling = true;
+    /// Runs the tick from `from` to `to` and returns the duties set on the way
+    fn run(vibrator: &mut Vibrator<FakePin>, from: u32, to: u32) -> Vec<u16> {
+        for now in from..=to {
+            vibrator.update(now);
         }
+        vibrator.motor.duties.drain(..).collect()
+    }
+
+    #[test]
+    fn plays_effects_on_the_pin() {
+        let mut vibrator = vibrator();
+        vibrator.play(Effect::DoubleClick, 0).unwrap();
+        assert!(vibrator.motor.enabled);
+
+        let duties = run(&mut vibrator, 1, 100);
+        assert_eq!(duties[..20], [1000; 20]);
+        assert_eq!(duties[20..80], [0; 60]);
+        assert_eq!(duties[80..100], [1000; 20]);
+        // stopped for good at the end
+        assert_eq!(duties[100..], [0]);
+        assert!(!vibrator.motor.enabled && !vibrator.is_rumbling());
+        assert!(run(&mut vibrator, 101, 200).is_empty());
+    }
+
+    #[test]
+    fn intensity_scales_and_mute_blocks() {
+        let mut vibrator = vibrator();
+        vibrator.set_intensity(50);
+        vibrator.play(Effect::Buzz, 0).unwrap();
+        assert_eq!(vibrator.motor.duties, [400]);
+
+        vibrator.set_muted(true);
+        assert!(!vibrator.motor.enabled);
+        vibrator.motor.duties.clear();
+        vibrator.play(Effect::Click, 10).unwrap();
+        assert!(run(&mut vibrator, 10, 50).is_empty());
+    }
+
+    #[test]
+    fn pulse_stops_at_the_deadline() {
+        let mut vibrator = vibrator();
+        vibrator.enable(30, 0);
+        assert_eq!(vibrator.motor.duties.pop(), Some(1000));
+
+        let duties = run(&mut vibrator, 1, 40);
+        assert_eq!(duties[..29], [1000; 29]);
+        assert_eq!(duties[29..], [0]);
+        assert!(!vibrator.motor.enabled && !vibrator.is_rumbling());
+    }
+
+    #[test]
+    fn zero_length_pulse_does_nothing() {
+        let mut vibrator = vibrator();
+        vibrator.enable(0, 5);
+        assert!(!vibrator.is_rumbling());
+        assert!(run(&mut vibrator, 5, 10).is_empty());
+
+        // nor does it cut a running pulse short
+        vibrator.enable(10, 10);
+        vibrator.enable(0, 12);
+        assert!(vibrator.is_rumbling());
+        run(&mut vibrator, 13, 19);
+        assert!(vibrator.is_rumbling());
+        run(&mut vibrator, 20, 20);
+        assert!(!vibrator.is_rumbling());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn retrigger_extends_and_reenables() {
+        let mut vibrator = vibrator();
+        vibrator.enable(20, 0);
+        run(&mut vibrator, 1, 10);
+        // something else switched the channel off in between, the retrigger has to turn it on again
+        vibrator.motor.enabled = false;
+        vibrator.enable(20, 10);
+        assert!(vibrator.motor.enabled);
+
+        run(&mut vibrator, 11, 29);
+        assert!(vibrator.is_rumbling());
+        run(&mut vibrator, 30, 30);
+        assert!(!vibrator.is_rumbling() && !vibrator.motor.enabled);
+    }
+
+    #[test]
+    fn overlapping_shorter_pulse_keeps_the_later_deadline() {
+        let mut vibrator = vibrator();
+        vibrator.enable(100, 0);
+        vibrator.enable(10, 5);
+
+        run(&mut vibrator, 1, 99);
+        assert!(vibrator.is_rumbling());
+        run(&mut vibrator, 100, 100);
+        assert!(!vibrator.is_rumbling());
+    }
+
+    #[test]
+    fn pulse_across_tick_overflow() {
+        let mut vibrator = vibrator();
+        vibrator.enable(20, u32::MAX - 9);
+        run(&mut vibrator, u32::MAX - 8, u32::MAX);
+        run(&mut vibrator, 0, 9);
+        assert!(vibrator.is_rumbling());
+        run(&mut vibrator, 10, 10);
+        assert!(!vibrator.is_rumbling());
+    }
+
+    #[test]
+    fn plays_segments_it_does_not_keep() {
+        let mut vibrator = vibrator();
+        {
+            let segments = [Segment::new(40, 2), Segment::new(80, 1)];
+            vibrator.play_segments(&segments, 0).unwrap();
+        }
+        assert_eq!(run(&mut vibrator, 1, 3), [400, 400, 800, 0]);
+        assert!(!vibrator.is_rumbling());
+    }
+
+    #[test]
+    fn clicks_are_pulses() {
+        // keys pressed in quick succession keep it rumbling, 20 ms after the last press
+        let mut vibrator = vibrator();
+        vibrator.play(Effect::Click, 0).unwrap();
+        run(&mut vibrator, 1, 10);
+        vibrator.play(Effect::Click, 10).unwrap();
+        run(&mut vibrator, 11, 29);
+        assert!(vibrator.is_rumbling());
+        run(&mut vibrator, 30, 30);
+        assert!(!vibrator.is_rumbling() && !vibrator.motor.enabled);
+
+        // a click within a longer pulse does not cut it short
+        vibrator.enable(100, 100);
+        vibrator.play(Effect::Click, 110).unwrap();
+        run(&mut vibrator, 111, 199);
+        assert!(vibrator.is_rumbling());
+        run(&mut vibrator, 200, 200);
+        assert!(!vibrator.is_rumbling());
+
+        // a zero length click does nothing
+        static EMPTY: [Segment; 1] = [Segment::new(100, 0)];
+        vibrator.play(Effect::Custom(&EMPTY), 300).unwrap();
+        assert!(!vibrator.is_rumbling());
+    }
+
+    #[test]
+    fn effect_replaces_pulse() {
+        let mut vibrator = vibrator();
+        vibrator.enable(1000, 0);
+        vibrator.play(Effect::Tick, 1).unwrap();
+        run(&mut vibrator, 2, 9);
+        assert!(!vibrator.is_rumbling() && !vibrator.motor.enabled);
+    }
+
+    #[test]
+    fn click_replaces_effect() {
+        let mut vibrator = vibrator();
+        vibrator.play(Effect::Heartbeat, 0).unwrap();
+        vibrator.play(Effect::Click, 10).unwrap();
+        run(&mut vibrator, 11, 29);
+        assert!(vibrator.is_rumbling());
+        run(&mut vibrator, 30, 30);
+        assert!(!vibrator.is_rumbling());
+    }
+}
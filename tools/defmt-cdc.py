@@ -0,0 +1,43 @@
+#!/usr/bin/env python3
+"""Decodes defmt frames tunnelled over the USB serial, for boards without a debug probe.
+
+Build with `--features defmt`, then
+
+    stty -F /dev/ttyACM0 raw
+    tools/defmt-cdc.py /dev/ttyACM0 target/thumbv7em-none-eabihf/debug/proto
+
+sends `defmt cdc on` and pipes the frames to `defmt-print` (cargo install defmt-print).
+Every other line from the port is printed as it is.
+"""
+
+import subprocess
+import sys
+
+PREFIX = b"#defmt "
+
+
+def main():
+    if len(sys.argv) != 3:
+        sys.exit(f"usage: {sys.argv[0]} <serial port> <firmware elf>")
+    port, elf = sys.argv[1:]
+
+    decoder = subprocess.Popen(["defmt-print", "-e", elf], stdin=subprocess.PIPE)
+
+    with open(port, "r+b", buffering=0) as serial:
+        serial.write(b"defmt cdc on\n")
+
+        line = b""
+        while True:
+            line += serial.read(64)
+            while b"\n" in line:
+                text, line = line.split(b"\n", 1)
+                text = text.rstrip(b"\r")
+                if text.startswith(PREFIX):
+                    decoder.stdin.write(bytes.fromhex(text[len(PREFIX):].decode()))
+                    decoder.stdin.flush()
+                else:
+                    print(text.decode(errors="replace"), flush=True)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,31 @@
+#!/bin/sh
+# Builds the firmware and flashes it over USB, no debugger needed (needs dfu-util and arm-none-eabi-objcopy).
+#
+#     tools/dfu-update.sh
+#
+# dfu-util detaches the running firmware through its DFU runtime interface, then writes the image with the
+# ROM bootloader. Only the sectors of the image are erased, the settings in sector 7 stay, never use :mass-erase.
+# Set PROTO_USB_VID/PROTO_USB_PID like for the build if the ids were changed.
+# Not for proto-boot, the image goes to 0x08000000 over the bootloader: use tools/update.py there,
+# a firmware started by proto-boot refuses to detach.
+set -e
+
+if [ -n "$PROTO_SLOT" ]; then
+    echo "PROTO_SLOT is set, slot images are sent with tools/update.py" >&2
+    exit 1
+fi
+
+VID=${PROTO_USB_VID:-0x16c0}
+PID=${PROTO_USB_PID:-0x27dd}
+ELF=target/thumbv7em-none-eabihf/release/proto
+
+cargo build --release "$@"
+# a slot build left over from tools/slot-images.sh is linked behind the bootloader
+VECTORS=$(arm-none-eabi-objdump -h "$ELF" | awk '$2 == ".vector_table" { print $4 }')
+if [ "$VECTORS" != "08000000" ]; then
+    echo "$ELF is linked for a proto-boot slot, run cargo clean or use tools/update.py" >&2
+    exit 1
+fi
+arm-none-eabi-objcopy -O binary "$ELF" "$ELF.bin"
+# the second id is the ROM bootloader
+dfu-util -d "$(printf '%04x:%04x' "$VID" "$PID"),0483:df11" -a 0 -s 0x08000000:leave -D "$ELF.bin"
@@ -0,0 +1,54 @@
+#!/usr/bin/env python3
+"""Runs console commands over the raw HID interface, no serial driver needed (pip install hidapi).
+
+    tools/rawhid.py "profile list" "vib click"
+
+Every report is 64 bytes, the first one says how many of the others are used. Echo is off,
+a command is done after its `OK` or `ERR <reason>` line.
+"""
+
+import sys
+
+import hid
+
+VID, PID = 0x16C0, 0x27DD
+USAGE_PAGE = 0xFF60
+PAYLOAD = 63
+
+
+def open_device():
+    for info in hid.enumerate(VID, PID):
+        if info["usage_page"] == USAGE_PAGE:
+            device = hid.device()
+            device.open_path(info["path"])
+            return device
+    sys.exit("no raw HID interface found")
+
+
+def send(device, data):
+    for start in range(0, len(data), PAYLOAD):
+        chunk = data[start:start + PAYLOAD]
+        # report id 0, then the length
+        device.write(bytes([0, len(chunk)]) + chunk + bytes(PAYLOAD - len(chunk)))
+
+
+def main():
+    device = open_device()
+    received = b""
+    for command in sys.argv[1:]:
+        send(device, command.encode() + b"\n")
+        while True:
+            report = bytes(device.read(64, 1000))
+            if not report:
+                sys.exit("no answer")
+            received += report[1:1 + report[0]]
+            lines = received.split(b"\r\n")
+            received = lines.pop()
+            for line in lines:
+                print(line.decode(errors="replace"))
+            if any(line == b"OK" or line.startswith(b"ERR") for line in lines):
+                break
+
+
+if __name__ == "__main__":
+    main()
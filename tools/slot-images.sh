@@ -0,0 +1,20 @@
+#!/bin/sh
+# Builds the firmware for both slots of proto-boot and packs the images (needs arm-none-eabi-objcopy).
+#
+#     tools/slot-images.sh 1.2.0 [secret key file]
+#
+# Gives target/proto-a.img and target/proto-b.img, tools/update.py sends the one for the slot the
+# bootloader asks for. With a key file from `proto-image keygen` the images are signed.
+set -e
+
+VERSION=${1:?version like 1.2.0}
+KEY=$2
+ELF=target/thumbv7em-none-eabihf/release/proto
+HOST=$(rustc -vV | sed -n 's/^host: //p')
+
+for SLOT in a b; do
+    PROTO_SLOT=$SLOT cargo build --release
+    arm-none-eabi-objcopy -O binary "$ELF" "target/proto-$SLOT.bin"
+    cargo run -q --manifest-path proto-image/Cargo.toml --target "$HOST" --features host -- \
+        pack "target/proto-$SLOT.bin" "target/proto-$SLOT.img" "$SLOT" "$VERSION" $KEY
+done
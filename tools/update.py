@@ -0,0 +1,90 @@
+#!/usr/bin/env python3
+"""Installs a firmware image with proto-boot over the USB serial, see proto-boot/src/main.rs.
+
+    tools/slot-images.sh 1.2.0
+    stty -F /dev/ttyACM0 raw
+    tools/update.py /dev/ttyACM0 target/proto-a.img target/proto-b.img
+
+Restarts the firmware into the updater with `update`, sends the image for the slot that `info` names
+and boots it. The new firmware runs on trial until it confirms, see src/slots.rs.
+"""
+
+import os
+import sys
+import time
+
+CHUNK = 64
+
+
+class Port:
+    def __init__(self, path):
+        self.file = open(path, "r+b", buffering=0)
+        self.received = b""
+
+    def command(self, line):
+        """The lines before `OK`, exits on `ERR`"""
+        self.file.write(line.encode() + b"\n")
+        lines = []
+        while True:
+            while b"\n" not in self.received:
+                data = self.file.read(64)
+                if not data:
+                    raise RuntimeError(f"{line.split()[0]}: the port was closed")
+                self.received += data
+            text, self.received = self.received.split(b"\n", 1)
+            text = text.rstrip(b"\r").decode(errors="replace")
+            if text == "OK":
+                return lines
+            if text.startswith("ERR"):
+                raise RuntimeError(f"{line.split()[0]}: {text}")
+            lines.append(text)
+
+    def close(self):
+        self.file.close()
+
+
+def wait_for(path):
+    # the port goes away with the reset and comes back as the updater
+    time.sleep(1)
+    for _ in range(50):
+        if os.path.exists(path):
+            return Port(path)
+        time.sleep(0.1)
+    sys.exit(f"{path} did not come back")
+
+
+def main():
+    if len(sys.argv) != 4:
+        sys.exit(f"usage: {sys.argv[0]} <serial port> <image a> <image b>")
+    path, *images = sys.argv[1:]
+
+    port = Port(path)
+    try:
+        port.command("update")
+        port.close()
+        port = wait_for(path)
+    except RuntimeError as error:
+        # the updater does not know `update`, the firmware refuses without proto-boot
+        if "unknown command" not in str(error):
+            sys.exit(str(error))
+
+    try:
+        info = port.command("info")
+        print("\n".join(info))
+        slot = next(line.split()[1] for line in info if line.startswith("update "))
+        with open(images["ab".index(slot)], "rb") as file:
+            image = file.read()
+
+        port.command(f"begin {len(image)}")
+        for start in range(0, len(image), CHUNK):
+            port.command("data " + image[start:start + CHUNK].hex())
+            print(f"\r{min(start + CHUNK, len(image))}/{len(image)}", end="", flush=True)
+        print()
+        print("\n".join(port.command("end")))
+        port.command("boot")
+    except RuntimeError as error:
+        sys.exit(str(error))
+
+
+if __name__ == "__main__":
+    main()